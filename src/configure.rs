@@ -1,7 +1,8 @@
 use std::{
     error::Error,
     fmt, fs, io,
-    io::Write,
+    io::{IsTerminal as _, Write},
+    net::{AddrParseError, Ipv4Addr, SocketAddr},
     num::{NonZeroUsize, ParseIntError},
     path::{Path, PathBuf},
     str::FromStr,
@@ -9,12 +10,17 @@ use std::{
     time::Duration,
 };
 
-use clap::{builder::PathBufValueParser, ArgAction, Parser, ValueEnum};
+use clap::{
+    builder::PathBufValueParser, error::ErrorKind, ArgAction, CommandFactory as _, Parser,
+    Subcommand, ValueEnum,
+};
 use configparser::ini::Ini;
 use reqwest::Client;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr, DurationSeconds};
 use url::Url;
 
-use crate::{api, logger::Logger};
+use crate::{api, logger::Logger, stats::Timestamp, util::parse_nodes};
 
 /// Distributed Stockfish analysis for lichess.org.
 #[derive(Debug, Parser)]
@@ -29,7 +35,8 @@ pub struct Opt {
     pub auto_update: bool,
 
     /// Configuration file. Defaults to fishnet.ini in the current working
-    /// directory.
+    /// directory. Stats options are also read from the same path with
+    /// extension .toml, for example fishnet.toml.
     #[arg(long, value_parser = PathBufValueParser::new(), global = true)]
     pub conf: Option<PathBuf>,
 
@@ -231,14 +238,671 @@ pub struct BacklogOpt {
     pub system: Option<Backlog>,
 }
 
-#[derive(Debug, Clone, Parser)]
+// Stats options from the command line, or from the [stats] table of
+// fishnet.toml, where keys are named like the long flags, for example
+// stats-db = "stats.db".
+#[serde_as]
+#[derive(Debug, Default, Clone, Parser, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 pub struct StatsOpt {
     /// File to record local statistics. Defaults to ~/.fishnet-stats.
+    /// $VAR and ${VAR} are expanded from the environment.
     #[arg(long, global = true)]
     pub stats_file: Option<PathBuf>,
     /// Do not record local statistics to a file.
     #[arg(long, conflicts_with = "stats_file", global = true)]
     pub no_stats_file: bool,
+    /// Write the stats file (and copies) only on clean shutdown, and rely
+    /// on the SQLite database for every batch. Ignored without a database.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub stats_file_on_shutdown: bool,
+    /// Wait this long (for example 10s) for another instance to release
+    /// the stats file, for example during an orchestrated restart, instead
+    /// of not recording to the file at all. Defaults to 0. In seconds in
+    /// fishnet.toml.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        conflicts_with = "no_stats_file",
+        global = true
+    )]
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub stats_file_lock_timeout: Option<Duration>,
+    /// Also write local statistics to this file, for example on a network
+    /// share. Each copy is replaced atomically. Can be given multiple
+    /// times. $VAR and ${VAR} are expanded from the environment.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "no_stats_file",
+        global = true
+    )]
+    pub stats_tee: Vec<PathBuf>,
+    /// Also send a structured message with the totals after each batch to
+    /// the system logger (RFC 5424). No-op on platforms without syslog.
+    #[arg(long, global = true)]
+    pub stats_syslog: bool,
+    /// Also push the totals after each batch to InfluxDB, as line protocol,
+    /// tagged with --stats-tag. For example udp://localhost:8089 or
+    /// http://localhost:8086/write?db=fishnet.
+    #[cfg(feature = "influx")]
+    #[arg(long, value_name = "URL", global = true)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub influx_url: Option<Url>,
+    /// Debugging: Store the compressed engine output (truncated to 64 KiB)
+    /// of this fraction of batches in the SQLite database, for example
+    /// 0.01. Disabled by default.
+    #[arg(long, value_name = "RATE", global = true)]
+    pub stats_capture_output: Option<f64>,
+    /// Record the CPU temperature with each batch, to correlate nps with
+    /// thermal throttling. Only supported on Linux (hwmon).
+    #[arg(long, alias = "record-temperature", global = true)]
+    #[serde(alias = "record-temperature")]
+    pub stats_temperature: bool,
+    /// Do not fall back to a per-user temporary directory if the stats file
+    /// or database is not writable.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub no_stats_fallback: bool,
+    /// Keep local statistics in $XDG_STATE_HOME/fishnet (defaults to
+    /// ~/.local/state/fishnet), migrating an existing ~/.fishnet-stats.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub stats_xdg: bool,
+    /// Create missing parent directories of the stats file and database.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub stats_mkdir: bool,
+    /// SQLite database to record a time series of local statistics.
+    /// Defaults to stats.db in the current working directory. $VAR and
+    /// ${VAR} are expanded from the environment.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub stats_db: Option<PathBuf>,
+    /// Table in the SQLite database to record to. Defaults to stats.
+    #[arg(
+        long,
+        alias = "db-table",
+        conflicts_with = "no_stats_file",
+        global = true
+    )]
+    #[serde(alias = "db-table")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub stats_db_table: Option<DbTable>,
+    /// Trade durability of the SQLite database for speed. Defaults to
+    /// balanced.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub stats_durability: Option<Durability>,
+    /// Checkpoint the write-ahead log of the SQLite database into the
+    /// database file whenever it exceeds this many pages. Defaults to
+    /// 1000. 0 to disable automatic checkpoints.
+    #[arg(
+        long,
+        value_name = "PAGES",
+        conflicts_with = "no_stats_file",
+        global = true
+    )]
+    pub stats_wal_autocheckpoint: Option<u32>,
+    /// Fully checkpoint and truncate the write-ahead log of the SQLite
+    /// database on clean shutdown, so that the database file is up to
+    /// date on its own.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub stats_wal_truncate: bool,
+    /// Unit of node counts stored in the SQLite database, for backends
+    /// with fixed precision. Defaults to nodes. Cannot be changed once
+    /// rows have been recorded.
+    #[arg(
+        long,
+        value_name = "UNIT",
+        conflicts_with = "no_stats_file",
+        global = true
+    )]
+    pub stats_node_unit: Option<NodeUnit>,
+    /// Keep only a single row with the latest totals in the SQLite
+    /// database, instead of a time series. Use a separate table or
+    /// database, because an existing time series is not overwritten.
+    #[arg(long, conflicts_with = "no_stats_file", global = true)]
+    pub db_snapshot: bool,
+    /// Make the stats table in the SQLite database append-only, so that
+    /// recorded rows can no longer be updated or deleted. The table then
+    /// grows without bound, because it cannot be rolled up or backfilled.
+    /// Cannot be undone by fishnet.
+    #[arg(
+        long,
+        conflicts_with_all = ["no_stats_file", "db_snapshot"],
+        global = true
+    )]
+    pub stats_append_only: bool,
+    /// Pause recording to the SQLite database while less than this many
+    /// megabytes are free on its disk. Defaults to 100. 0 to disable.
+    #[arg(
+        long,
+        value_name = "MB",
+        conflicts_with = "no_stats_file",
+        global = true
+    )]
+    pub stats_min_free_mb: Option<u64>,
+    /// Nodes per second analysed by all clients together, to show the share
+    /// contributed by this client since it started in the summary. A rate
+    /// rather than a total, so that the share stays meaningful as the
+    /// session grows. SI prefixes like 1.5G are accepted.
+    #[arg(long, value_name = "NPS", value_parser = parse_node_count, global = true)]
+    pub stats_global_nps: Option<u64>,
+    /// Round node counts to the nearest multiple of this (for example 1M)
+    /// in syslog and InfluxDB. Trades exactness for better compression in
+    /// time-series backends. The stats file, the database and the summary
+    /// keep exact counts. SI prefixes like 1M are accepted.
+    #[arg(long, value_name = "NODES", value_parser = parse_node_count, global = true)]
+    pub stats_precision_nodes: Option<u64>,
+    /// Show the distribution of batch durations in the summary.
+    #[arg(long, global = true)]
+    pub stats_interval_histogram: bool,
+    /// Nps per core that fishnet is throttled to, for example with a nice
+    /// level or cgroup quota, to show the unused headroom in the summary.
+    #[arg(long, value_name = "NPS", global = true)]
+    pub max_nps: Option<u32>,
+    /// Nps per core of a reference machine, to show a performance index
+    /// (estimate / reference) in the summary, for comparing machines.
+    #[arg(long, value_name = "NPS", global = true)]
+    pub nps_reference: Option<u32>,
+    /// Warn if no batch was completed for this long (for example 30m),
+    /// because no work is available or the engine is stuck. Defaults to
+    /// 30m. 0 to disable. In seconds in fishnet.toml.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, global = true)]
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub stats_idle_after: Option<Duration>,
+    /// Do not assume an optimistic 400 knps/core before the first batch
+    /// is completed.
+    #[arg(long, global = true)]
+    pub no_optimistic_nps: bool,
+    /// Learn the nps estimate faster from the first batches after startup,
+    /// while the hash table warms up. Disabled by default.
+    #[arg(long, value_name = "BATCHES", global = true)]
+    pub nps_warmup_batches: Option<u64>,
+    /// Also estimate nps with this smoothing, and show both estimates in
+    /// the summary, to compare them on this hardware. The shadow estimate
+    /// is never used for batch sizing.
+    #[arg(long, value_name = "SMOOTHING", global = true)]
+    pub nps_shadow: Option<Smoothing>,
+    /// Log once in the summary when the nps estimate has stabilized, for
+    /// benchmarks that should only start measuring after warmup: Its
+    /// uncertainty is below this (for example 0.1), and the last 8 samples
+    /// are within 5% of it.
+    #[arg(long, value_name = "UNCERTAINTY", global = true)]
+    pub nps_convergence: Option<f64>,
+    /// Serve a read-only dashboard of local statistics on this address,
+    /// for example :8080 (localhost only) or 0.0.0.0:8080. For alerting,
+    /// GET /stats/rate?window=300 returns nodes and positions per second.
+    /// GET /stats/since?id=0 returns new rows and the id to poll next.
+    #[arg(long, global = true)]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub dashboard: Option<ListenAddr>,
+    /// Serve repeated dashboard queries from memory for this long, unless
+    /// a batch was recorded in the meantime. Defaults to 1s. 0 to disable.
+    /// In seconds in fishnet.toml.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, global = true)]
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub dashboard_cache_ttl: Option<Duration>,
+    /// Label recorded statistics with key=value, for example region=eu.
+    /// Can be given up to 16 times.
+    #[arg(long, value_name = "KEY=VALUE", global = true)]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub stats_tag: Vec<StatsTag>,
+    /// Encrypt the stats file with this key, given as 64 hex digits.
+    /// Defaults to $FISHNET_STATS_KEY. An existing plaintext stats file is
+    /// encrypted when it is next saved.
+    #[cfg(feature = "encryption")]
+    #[arg(
+        long,
+        value_name = "HEX",
+        conflicts_with = "no_stats_file",
+        global = true
+    )]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub stats_key: Option<StatsKey>,
+}
+
+/// Contents of fishnet.toml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    stats: StatsOpt,
+}
+
+impl StatsOpt {
+    /// Fills in options that were not given on the command line from
+    /// `file`. A flag from the file cannot turn off a flag given on the
+    /// command line, and the stats file is chosen by the command line if
+    /// it mentions it at all. `--no-stats-file` on the command line also
+    /// drops the file and database options of `file`.
+    pub fn or_file(self, file: StatsOpt) -> StatsOpt {
+        let file = if self.no_stats_file {
+            file.without_stats_file()
+        } else {
+            file
+        };
+        let stats_file_given = self.stats_file.is_some() || self.no_stats_file;
+        StatsOpt {
+            stats_file: self
+                .stats_file
+                .or(file.stats_file.filter(|_| !stats_file_given)),
+            no_stats_file: self.no_stats_file || (file.no_stats_file && !stats_file_given),
+            stats_tee: if self.stats_tee.is_empty() {
+                file.stats_tee
+            } else {
+                self.stats_tee
+            },
+            stats_file_on_shutdown: self.stats_file_on_shutdown || file.stats_file_on_shutdown,
+            stats_file_lock_timeout: self
+                .stats_file_lock_timeout
+                .or(file.stats_file_lock_timeout),
+            stats_syslog: self.stats_syslog || file.stats_syslog,
+            #[cfg(feature = "influx")]
+            influx_url: self.influx_url.or(file.influx_url),
+            stats_capture_output: self.stats_capture_output.or(file.stats_capture_output),
+            stats_temperature: self.stats_temperature || file.stats_temperature,
+            no_stats_fallback: self.no_stats_fallback || file.no_stats_fallback,
+            stats_xdg: self.stats_xdg || file.stats_xdg,
+            stats_mkdir: self.stats_mkdir || file.stats_mkdir,
+            stats_db: self.stats_db.or(file.stats_db),
+            stats_db_table: self.stats_db_table.or(file.stats_db_table),
+            stats_durability: self.stats_durability.or(file.stats_durability),
+            stats_wal_autocheckpoint: self
+                .stats_wal_autocheckpoint
+                .or(file.stats_wal_autocheckpoint),
+            stats_wal_truncate: self.stats_wal_truncate || file.stats_wal_truncate,
+            stats_node_unit: self.stats_node_unit.or(file.stats_node_unit),
+            db_snapshot: self.db_snapshot || file.db_snapshot,
+            stats_append_only: self.stats_append_only || file.stats_append_only,
+            stats_min_free_mb: self.stats_min_free_mb.or(file.stats_min_free_mb),
+            stats_global_nps: self.stats_global_nps.or(file.stats_global_nps),
+            stats_precision_nodes: self.stats_precision_nodes.or(file.stats_precision_nodes),
+            stats_interval_histogram: self.stats_interval_histogram
+                || file.stats_interval_histogram,
+            max_nps: self.max_nps.or(file.max_nps),
+            nps_reference: self.nps_reference.or(file.nps_reference),
+            stats_idle_after: self.stats_idle_after.or(file.stats_idle_after),
+            no_optimistic_nps: self.no_optimistic_nps || file.no_optimistic_nps,
+            nps_shadow: self.nps_shadow.or(file.nps_shadow),
+            nps_convergence: self.nps_convergence.or(file.nps_convergence),
+            nps_warmup_batches: self.nps_warmup_batches.or(file.nps_warmup_batches),
+            dashboard: self.dashboard.or(file.dashboard),
+            dashboard_cache_ttl: self.dashboard_cache_ttl.or(file.dashboard_cache_ttl),
+            stats_tag: if self.stats_tag.is_empty() {
+                file.stats_tag
+            } else {
+                self.stats_tag
+            },
+            #[cfg(feature = "encryption")]
+            stats_key: self.stats_key.or(file.stats_key),
+        }
+    }
+
+    /// Clears the options that `--no-stats-file` conflicts with.
+    fn without_stats_file(self) -> StatsOpt {
+        StatsOpt {
+            stats_file: None,
+            stats_file_on_shutdown: false,
+            stats_file_lock_timeout: None,
+            stats_tee: Vec::new(),
+            no_stats_fallback: false,
+            stats_xdg: false,
+            stats_mkdir: false,
+            stats_db: None,
+            stats_db_table: None,
+            stats_durability: None,
+            stats_wal_autocheckpoint: None,
+            stats_wal_truncate: false,
+            stats_node_unit: None,
+            db_snapshot: false,
+            stats_append_only: false,
+            stats_min_free_mb: None,
+            stats_syslog: false,
+            #[cfg(feature = "encryption")]
+            stats_key: None,
+            ..self
+        }
+    }
+
+    /// Rejects contradictory or nonsensical combinations, including those
+    /// that come from merging the config file, before anything is opened.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.no_stats_file {
+            let file_options = [
+                ("--stats-file", self.stats_file.is_some()),
+                ("--stats-file-on-shutdown", self.stats_file_on_shutdown),
+                (
+                    "--stats-file-lock-timeout",
+                    self.stats_file_lock_timeout.is_some(),
+                ),
+                ("--stats-tee", !self.stats_tee.is_empty()),
+                ("--no-stats-fallback", self.no_stats_fallback),
+                ("--stats-xdg", self.stats_xdg),
+                ("--stats-mkdir", self.stats_mkdir),
+                ("--stats-db", self.stats_db.is_some()),
+                ("--stats-db-table", self.stats_db_table.is_some()),
+                ("--stats-durability", self.stats_durability.is_some()),
+                (
+                    "--stats-wal-autocheckpoint",
+                    self.stats_wal_autocheckpoint.is_some(),
+                ),
+                ("--stats-wal-truncate", self.stats_wal_truncate),
+                ("--stats-node-unit", self.stats_node_unit.is_some()),
+                ("--db-snapshot", self.db_snapshot),
+                ("--stats-append-only", self.stats_append_only),
+                ("--stats-min-free-mb", self.stats_min_free_mb.is_some()),
+                ("--stats-syslog", self.stats_syslog),
+                #[cfg(feature = "encryption")]
+                ("--stats-key", self.stats_key.is_some()),
+            ];
+            if let Some((name, _)) = file_options.iter().find(|(_, given)| *given) {
+                return Err(format!("--no-stats-file cannot be used with {name}"));
+            }
+        }
+        if self.db_snapshot && self.stats_append_only {
+            return Err("--db-snapshot cannot be used with --stats-append-only".to_owned());
+        }
+        if self
+            .stats_capture_output
+            .is_some_and(|rate| !(0.0..=1.0).contains(&rate))
+        {
+            return Err("--stats-capture-output must be between 0 and 1".to_owned());
+        }
+        if self.max_nps == Some(0) {
+            return Err("--max-nps must be positive".to_owned());
+        }
+        if self.nps_reference == Some(0) {
+            return Err("--nps-reference must be positive".to_owned());
+        }
+        if self
+            .nps_convergence
+            .is_some_and(|uncertainty| !(uncertainty > 0.0 && uncertainty <= 1.0))
+        {
+            return Err("--nps-convergence must be above 0 and at most 1".to_owned());
+        }
+        if self.stats_global_nps == Some(0) {
+            return Err("--stats-global-nps must be positive".to_owned());
+        }
+        if self.stats_precision_nodes == Some(0) {
+            return Err("--stats-precision-nodes must be positive".to_owned());
+        }
+        if self.stats_tag.len() > MAX_STATS_TAGS {
+            return Err(format!(
+                "--stats-tag can be given at most {MAX_STATS_TAGS} times"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Key to encrypt the stats file with, if any.
+    pub fn stats_key(&self) -> Result<Option<StatsKey>, StatsKeyError> {
+        #[cfg(feature = "encryption")]
+        if let Some(ref key) = self.stats_key {
+            return Ok(Some(key.clone()));
+        }
+        #[cfg(feature = "encryption")]
+        if let Ok(key) = std::env::var("FISHNET_STATS_KEY") {
+            return key.parse().map(Some);
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Durability {
+    /// WAL journal without syncing, committing every 16 batches. A crash
+    /// loses up to 16 batches, a power failure may lose more or corrupt the
+    /// database.
+    Fast,
+    /// WAL journal, committing every batch. A power failure may lose the
+    /// most recent batches.
+    #[default]
+    Balanced,
+    /// Rollback journal with full sync, committing every batch. Recorded
+    /// batches are never lost.
+    Safe,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Smoothing {
+    /// Exponentially weighted moving average, weighted by batch size.
+    #[default]
+    Ewma,
+    /// Unweighted mean of the last 8 samples.
+    Sma,
+}
+
+impl fmt::Display for Smoothing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Smoothing::Ewma => "ewma",
+            Smoothing::Sma => "sma",
+        })
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeUnit {
+    #[default]
+    Nodes,
+    Kilonodes,
+    Meganodes,
+}
+
+impl NodeUnit {
+    /// Nodes per stored unit.
+    pub fn nodes(self) -> u64 {
+        match self {
+            NodeUnit::Nodes => 1,
+            NodeUnit::Kilonodes => 1_000,
+            NodeUnit::Meganodes => 1_000_000,
+        }
+    }
+}
+
+impl fmt::Display for NodeUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NodeUnit::Nodes => "nodes",
+            NodeUnit::Kilonodes => "kilonodes",
+            NodeUnit::Meganodes => "meganodes",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DbTable(String);
+
+impl Default for DbTable {
+    fn default() -> DbTable {
+        DbTable("stats".to_owned())
+    }
+}
+
+#[derive(Debug)]
+pub enum DbTableError {
+    Empty,
+    TooLong,
+    InvalidIdentifier,
+    Keyword,
+}
+
+impl fmt::Display for DbTableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DbTableError::Empty => "table name expected to be non-empty",
+            DbTableError::TooLong => "table name expected to be at most 64 characters",
+            DbTableError::InvalidIdentifier => {
+                "table name expected to consist of ascii letters, digits and _, not starting with a digit"
+            }
+            DbTableError::Keyword => "table name expected not to be an SQL keyword",
+        })
+    }
+}
+
+impl Error for DbTableError {}
+
+/// Keywords of SQLite, see <https://www.sqlite.org/lang_keywords.html>.
+const SQLITE_KEYWORDS: &str = "ABORT ACTION ADD AFTER ALL ALTER ALWAYS ANALYZE AND AS ASC ATTACH \
+     AUTOINCREMENT BEFORE BEGIN BETWEEN BY CASCADE CASE CAST CHECK COLLATE COLUMN \
+     COMMIT CONFLICT CONSTRAINT CREATE CROSS CURRENT CURRENT_DATE CURRENT_TIME \
+     CURRENT_TIMESTAMP DATABASE DEFAULT DEFERRABLE DEFERRED DELETE DESC DETACH \
+     DISTINCT DO DROP EACH ELSE END ESCAPE EXCEPT EXCLUDE EXCLUSIVE EXISTS \
+     EXPLAIN FAIL FILTER FIRST FOLLOWING FOR FOREIGN FROM FULL GENERATED GLOB \
+     GROUP GROUPS HAVING IF IGNORE IMMEDIATE IN INDEX INDEXED INITIALLY INNER \
+     INSERT INSTEAD INTERSECT INTO IS ISNULL JOIN KEY LAST LEFT LIKE LIMIT MATCH \
+     MATERIALIZED NATURAL NO NOT NOTHING NOTNULL NULL NULLS OF OFFSET ON OR ORDER \
+     OTHERS OUTER OVER PARTITION PLAN PRAGMA PRECEDING PRIMARY QUERY RAISE RANGE \
+     RECURSIVE REFERENCES REGEXP REINDEX RELEASE RENAME REPLACE RESTRICT \
+     RETURNING RIGHT ROLLBACK ROW ROWS SAVEPOINT SELECT SET TABLE TEMP TEMPORARY \
+     THEN TIES TO TRANSACTION TRIGGER UNBOUNDED UNION UNIQUE UPDATE USING VACUUM \
+     VALUES VIEW VIRTUAL WHEN WHERE WINDOW WITH WITHOUT";
+
+impl FromStr for DbTable {
+    type Err = DbTableError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Table names can not be bound as parameters, so they are restricted
+        // to plain identifiers that are safe to interpolate.
+        if s.is_empty() {
+            Err(DbTableError::Empty)
+        } else if s.len() > 64 {
+            Err(DbTableError::TooLong)
+        } else if s.starts_with(|c: char| c.is_ascii_digit())
+            || s.to_ascii_lowercase().starts_with("sqlite_")
+            || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            Err(DbTableError::InvalidIdentifier)
+        } else if SQLITE_KEYWORDS
+            .split_whitespace()
+            .any(|keyword| keyword.eq_ignore_ascii_case(s))
+        {
+            Err(DbTableError::Keyword)
+        } else {
+            Ok(DbTable(s.to_owned()))
+        }
+    }
+}
+
+impl fmt::Display for DbTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ListenAddr(pub SocketAddr);
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ListenAddr(if s.starts_with(':') {
+            format!("{}{s}", Ipv4Addr::LOCALHOST).parse()?
+        } else {
+            s.parse()?
+        }))
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Maximum number of `--stats-tag` options.
+pub const MAX_STATS_TAGS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsTag {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug)]
+pub enum StatsTagError {
+    MissingValue,
+    InvalidKey,
+    ValueTooLong,
+    InvalidValue,
+}
+
+impl fmt::Display for StatsTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StatsTagError::MissingValue => "tag expected in the form key=value",
+            StatsTagError::InvalidKey => {
+                "tag key expected to be at most 32 ascii letters, digits and _, not starting with a digit"
+            }
+            StatsTagError::ValueTooLong => "tag value expected to be at most 64 bytes",
+            StatsTagError::InvalidValue => "tag value expected to not contain control characters",
+        })
+    }
+}
+
+impl Error for StatsTagError {}
+
+impl FromStr for StatsTag {
+    type Err = StatsTagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Keys are restricted to identifiers that are also valid labels in
+        // common metrics systems.
+        let (key, value) = s.split_once('=').ok_or(StatsTagError::MissingValue)?;
+        if key.is_empty()
+            || key.len() > 32
+            || key.starts_with(|c: char| c.is_ascii_digit())
+            || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            Err(StatsTagError::InvalidKey)
+        } else if value.len() > 64 {
+            Err(StatsTagError::ValueTooLong)
+        } else if value.chars().any(char::is_control) {
+            Err(StatsTagError::InvalidValue)
+        } else {
+            Ok(StatsTag {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            })
+        }
+    }
+}
+
+/// 256 bit key for the stats file.
+#[derive(Clone, PartialEq, Eq)]
+pub struct StatsKey(pub [u8; 32]);
+
+impl fmt::Debug for StatsKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StatsKey(..)")
+    }
+}
+
+#[derive(Debug)]
+pub struct StatsKeyError;
+
+impl fmt::Display for StatsKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("stats key expected to be 64 hex digits")
+    }
+}
+
+impl Error for StatsKeyError {}
+
+impl FromStr for StatsKey {
+    type Err = StatsKeyError;
+
+    fn from_str(s: &str) -> Result<StatsKey, StatsKeyError> {
+        if s.len() != 64 || !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(StatsKeyError);
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| StatsKeyError)?;
+        }
+        Ok(StatsKey(key))
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -317,7 +981,7 @@ impl From<MaxBackoff> for Duration {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Parser)]
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
 pub enum Command {
     /// Donate CPU time by running analysis (default).
     Run,
@@ -329,12 +993,246 @@ pub enum Command {
     SystemdUser,
     /// Show GPLv3 license.
     License,
+    /// Print the JSON schema of the stats file.
+    #[cfg(feature = "schema")]
+    StatsFileJsonSchema,
+    /// Recompute derived columns of the stats database.
+    BackfillStats,
+    /// Analyse the stats database.
+    #[command(subcommand)]
+    Stats(StatsCommand),
+    /// Seed local statistics from the stats file of another client, if
+    /// none have been recorded yet. The upstream lichess fishnet client
+    /// uses the same format.
+    ImportStats {
+        /// Stats file to import. With the http feature, also the http(s)
+        /// URL of a published stats file.
+        source: String,
+    },
+    /// Merge rows from the stats database of another machine into the
+    /// local one, for example to consolidate the databases of several
+    /// machines. Rows that are already present are skipped. The other
+    /// database is not modified.
+    MergeDb {
+        /// Stats database to merge.
+        file: PathBuf,
+        /// Name of the machine that recorded the other database, for
+        /// example its hostname. Its rows are kept apart as a separate
+        /// time series. Use the same name when merging it again.
+        #[arg(long)]
+        source: String,
+    },
+    /// Record synthetic batches into a temporary stats file and database
+    /// with the given stats options, and report write throughput, latency
+    /// and file sizes.
+    #[command(hide = true)]
+    BenchStats {
+        /// Number of batches to record.
+        #[arg(long, default_value_t = 1000)]
+        batches: u64,
+        /// Batches per second. Defaults to as fast as possible.
+        #[arg(long)]
+        rate: Option<f64>,
+    },
 }
 
 impl Command {
-    pub fn is_systemd(self) -> bool {
+    pub fn is_systemd(&self) -> bool {
         matches!(self, Command::Systemd | Command::SystemdUser)
     }
+
+    /// Commands that print machine-readable output, without intro.
+    pub fn is_machine_readable(&self) -> bool {
+        match self {
+            #[cfg(feature = "schema")]
+            Command::StatsFileJsonSchema => true,
+            Command::Stats(
+                StatsCommand::Dump | StatsCommand::Export { .. } | StatsCommand::Output { .. },
+            ) => true,
+            _ => false,
+        }
+    }
+
+    /// Commands that neither need nor offer to create a configuration file.
+    pub fn is_standalone(&self) -> bool {
+        match self {
+            Command::License
+            | Command::BackfillStats
+            | Command::Stats(_)
+            | Command::ImportStats { .. }
+            | Command::MergeDb { .. }
+            | Command::BenchStats { .. } => true,
+            #[cfg(feature = "schema")]
+            Command::StatsFileJsonSchema => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum StatsCommand {
+    /// Compare performance in two time windows, for example before and
+    /// after a configuration change.
+    Compare {
+        /// Window before, as unix timestamps start..end.
+        #[arg(long, value_name = "START..END")]
+        from: TimeRange,
+        /// Window after, as unix timestamps start..end.
+        #[arg(long, value_name = "START..END")]
+        to: TimeRange,
+    },
+    /// Break down recorded work by the fishnet version that did it.
+    Versions,
+    /// Print recorded stats as key=value lines, for scripts.
+    Dump,
+    /// Print rows of the stats database, oldest first.
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Only rows labelled with key=value, for example to extract a
+        /// single machine from a shared database. Can be given multiple
+        /// times to require all tags.
+        #[arg(long, value_name = "KEY=VALUE")]
+        tag: Vec<StatsTag>,
+        /// Only rows recorded at or after this unix timestamp.
+        #[arg(long, value_name = "UNIX_TIME")]
+        since: Option<i64>,
+        /// Only rows recorded at or before this unix timestamp.
+        #[arg(long, value_name = "UNIX_TIME")]
+        until: Option<i64>,
+        /// Field delimiter of CSV, for example ; for spreadsheet apps in
+        /// locales that use , as the decimal separator.
+        #[arg(
+            long,
+            value_name = "CHAR",
+            default_value_t = ',',
+            value_parser = parse_csv_delimiter
+        )]
+        csv_delimiter: char,
+    },
+    /// Compact old rows of the stats database into aggregates per time
+    /// bucket, to keep the database small.
+    Rollup {
+        /// Only rows older than this, for example 30d.
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        older_than: Duration,
+        /// Length of the buckets, for example 1h or 1d.
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1h")]
+        bucket: Duration,
+    },
+    /// Show the hour or day with the most nodes, for example to plan
+    /// maintenance windows. Periods are aligned to UTC.
+    Busiest {
+        #[arg(long, value_enum, default_value_t = Granularity::Day)]
+        per: Granularity,
+    },
+    /// Mark an event like "hardware replaced" in the time series of the
+    /// stats database, to explain changes. Shown on the dashboard.
+    Annotate { label: String },
+    /// Print the engine output captured with --stats-capture-output for
+    /// the row with this id, as shown by export.
+    Output { id: i64 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    Json,
+    /// Comma-separated values with a header line. Tags are a JSON object.
+    Csv,
+}
+
+/// Length of the periods of `fishnet stats busiest`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    /// Length in seconds.
+    pub fn secs(self) -> i64 {
+        match self {
+            Granularity::Hour => 60 * 60,
+            Granularity::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+/// Inclusive range of unix timestamps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+#[derive(Debug)]
+pub enum TimeRangeError {
+    MissingSeparator,
+    InvalidTimestamp(ParseIntError),
+    Reversed,
+}
+
+impl fmt::Display for TimeRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeRangeError::MissingSeparator => f.write_str("time range expected as start..end"),
+            TimeRangeError::InvalidTimestamp(err) => write!(f, "invalid unix timestamp: {err}"),
+            TimeRangeError::Reversed => f.write_str("time range expected to end after start"),
+        }
+    }
+}
+
+impl Error for TimeRangeError {}
+
+impl FromStr for TimeRange {
+    type Err = TimeRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once("..").ok_or(TimeRangeError::MissingSeparator)?;
+        let range = TimeRange {
+            start: start
+                .trim()
+                .parse()
+                .map(Timestamp::from_unix_secs)
+                .map_err(TimeRangeError::InvalidTimestamp)?,
+            end: end
+                .trim()
+                .parse()
+                .map(Timestamp::from_unix_secs)
+                .map_err(TimeRangeError::InvalidTimestamp)?,
+        };
+        if range.end < range.start {
+            Err(TimeRangeError::Reversed)
+        } else {
+            Ok(range)
+        }
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}..{}",
+            self.start.to_unix_secs(),
+            self.end.to_unix_secs()
+        )
+    }
+}
+
+fn parse_node_count(s: &str) -> Result<u64, String> {
+    parse_nodes(s)
+        .ok_or_else(|| format!("invalid node count {s:?}, expected for example 1500000 or 1.5M"))
+}
+
+fn parse_csv_delimiter(s: &str) -> Result<char, String> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some('"' | '\n' | '\r'), None) => Err(format!("{s:?} cannot be used as CSV delimiter")),
+        (Some(c), None) => Ok(c),
+        _ => Err(format!("expected a single character, got {s:?}")),
+    }
 }
 
 fn parse_duration(s: &str) -> Result<Duration, ParseIntError> {
@@ -376,6 +1274,27 @@ impl FromStr for Toggle {
     }
 }
 
+/// Asks a yes or no question on the terminal, defaulting to yes. `None` if
+/// stdin is not a terminal.
+pub fn confirm(question: &str) -> Option<bool> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    loop {
+        let mut answer = String::new();
+        eprint!("{question} (default: yes) ");
+        io::stderr().flush().expect("flush stderr");
+        io::stdin()
+            .read_line(&mut answer)
+            .expect("read confirmation from stdin");
+        match Toggle::from_str(&answer) {
+            Ok(Toggle::Yes | Toggle::Default) => return Some(true),
+            Ok(Toggle::No) => return Some(false),
+            Err(_) => continue,
+        }
+    }
+}
+
 #[rustfmt::skip]
 fn intro() {
     println!(r#"#   _________         .    ."#);
@@ -390,13 +1309,48 @@ fn intro() {
     println!(r#"#               \________/      Distributed Stockfish analysis for lichess.org"#);
 }
 
+/// Reads the stats options from fishnet.toml, if it exists.
+fn read_stats_conf(path: &Path) -> io::Result<Option<StatsOpt>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => toml::from_str::<ConfigFile>(&contents)
+            .map(|conf| Some(conf.stats))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
 pub async fn parse_and_configure(client: &Client) -> Opt {
     let mut opt = Opt::parse();
 
+    // Merge stats options from the config file. Command line arguments
+    // take precedence.
+    if !opt.no_conf {
+        let path = opt.conf().with_extension("toml");
+        match read_stats_conf(&path) {
+            Ok(Some(file)) => opt.stats = opt.stats.or_file(file),
+            Ok(None) => (),
+            Err(err) => panic!("failed to read config file {path:?}: {err}"),
+        }
+    }
+
+    // Also catches conflicts between the command line and the config
+    // file, that clap cannot see.
+    if let Err(err) = opt.stats.validate() {
+        Opt::command()
+            .error(ErrorKind::ArgumentConflict, err)
+            .exit();
+    }
+
     // Show intro and configure logger.
-    let is_systemd = opt.command.is_some_and(Command::is_systemd);
+    let is_systemd = opt.command.as_ref().is_some_and(Command::is_systemd);
     let logger = Logger::new(opt.verbose, is_systemd);
-    if !is_systemd {
+    if !is_systemd
+        && !opt
+            .command
+            .as_ref()
+            .is_some_and(Command::is_machine_readable)
+    {
         intro();
     }
 
@@ -415,7 +1369,7 @@ pub async fn parse_and_configure(client: &Client) -> Opt {
 
     // Handle config file.
     if opt.command == Some(Command::Configure)
-        || (opt.command != Some(Command::License) && !opt.no_conf)
+        || (!opt.command.as_ref().is_some_and(Command::is_standalone) && !opt.no_conf)
     {
         let mut ini = Ini::new();
         ini.set_default_section("Fishnet");
@@ -641,3 +1595,189 @@ pub async fn parse_and_configure(client: &Client) -> Opt {
 
     opt
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> StatsOpt {
+        Opt::try_parse_from(std::iter::once("fishnet").chain(args.iter().copied()))
+            .unwrap()
+            .stats
+    }
+
+    fn file(toml: &str) -> StatsOpt {
+        toml::from_str::<ConfigFile>(toml).unwrap().stats
+    }
+
+    #[test]
+    fn test_stats_conf_file_only() {
+        let opt = cli(&[]).or_file(file(
+            r#"
+            [stats]
+            stats-db = "/var/lib/fishnet/stats.db"
+            stats-durability = "fast"
+            stats-syslog = true
+            dashboard = ":8080"
+            stats-tag = ["region=eu", "node=a"]
+            "#,
+        ));
+        assert_eq!(
+            opt.stats_db,
+            Some(PathBuf::from("/var/lib/fishnet/stats.db"))
+        );
+        assert_eq!(opt.stats_durability, Some(Durability::Fast));
+        assert!(opt.stats_syslog);
+        assert_eq!(opt.dashboard.map(|addr| addr.0.port()), Some(8080));
+        assert_eq!(opt.stats_tag.len(), 2);
+
+        assert!(toml::from_str::<ConfigFile>("[stats]\nstats-colour = true").is_err());
+        assert!(toml::from_str::<ConfigFile>("[stats]\nstats-db-table = \"1x\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_stats_opt() {
+        assert_eq!(StatsOpt::default().validate(), Ok(()));
+        assert_eq!(
+            cli(&["--stats-db", "stats.db", "--max-nps", "1"]).validate(),
+            Ok(())
+        );
+
+        let no_file_with_db = cli(&["--no-stats-file"]).or_file(file(
+            r#"
+            [stats]
+            stats-db = "stats.db"
+            "#,
+        ));
+        assert_eq!(no_file_with_db.validate(), Ok(()));
+        assert_eq!(no_file_with_db.stats_db, None);
+        let no_file_with_tee = file(
+            r#"
+            [stats]
+            no-stats-file = true
+            stats-tee = ["copy"]
+            "#,
+        );
+        assert_eq!(
+            no_file_with_tee.validate(),
+            Err("--no-stats-file cannot be used with --stats-tee".to_owned())
+        );
+
+        assert_eq!(
+            cli(&["--no-stats-file", "--stats-syslog"]).validate(),
+            Err("--no-stats-file cannot be used with --stats-syslog".to_owned())
+        );
+
+        let snapshot_append_only = file(
+            r#"
+            [stats]
+            db-snapshot = true
+            stats-append-only = true
+            "#,
+        );
+        assert_eq!(
+            snapshot_append_only.validate(),
+            Err("--db-snapshot cannot be used with --stats-append-only".to_owned())
+        );
+
+        assert_eq!(
+            cli(&["--stats-capture-output", "1.5"]).validate(),
+            Err("--stats-capture-output must be between 0 and 1".to_owned())
+        );
+        assert_eq!(
+            cli(&["--max-nps", "0"]).validate(),
+            Err("--max-nps must be positive".to_owned())
+        );
+        assert_eq!(
+            cli(&["--nps-convergence", "0"]).validate(),
+            Err("--nps-convergence must be above 0 and at most 1".to_owned())
+        );
+        assert_eq!(cli(&["--nps-convergence", "0.1"]).validate(), Ok(()));
+        assert_eq!(
+            cli(&["--stats-global-nps", "0"]).validate(),
+            Err("--stats-global-nps must be positive".to_owned())
+        );
+        assert_eq!(
+            cli(&["--stats-precision-nodes", "0"]).validate(),
+            Err("--stats-precision-nodes must be positive".to_owned())
+        );
+        assert_eq!(
+            cli(&["--stats-precision-nodes", "1M"]).stats_precision_nodes,
+            Some(1_000_000)
+        );
+
+        let mut too_many_tags = StatsOpt::default();
+        for i in 0..=MAX_STATS_TAGS {
+            too_many_tags
+                .stats_tag
+                .push(format!("tag{i}=x").parse().unwrap());
+        }
+        assert_eq!(
+            too_many_tags.validate(),
+            Err(format!(
+                "--stats-tag can be given at most {MAX_STATS_TAGS} times"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_record_temperature_alias() {
+        assert!(cli(&["--record-temperature"]).stats_temperature);
+        assert!(
+            file(
+                r#"
+                [stats]
+                record-temperature = true
+                "#
+            )
+            .stats_temperature
+        );
+    }
+
+    #[test]
+    fn test_parse_csv_delimiter() {
+        assert_eq!(parse_csv_delimiter(";"), Ok(';'));
+        assert_eq!(parse_csv_delimiter("\t"), Ok('\t'));
+        assert!(parse_csv_delimiter("\"").is_err());
+        assert!(parse_csv_delimiter("").is_err());
+        assert!(parse_csv_delimiter(";;").is_err());
+    }
+
+    #[test]
+    fn test_stats_conf_cli_only() {
+        let opt =
+            cli(&["--stats-db", "cli.db", "--max-nps", "500000"]).or_file(StatsOpt::default());
+        assert_eq!(opt.stats_db, Some(PathBuf::from("cli.db")));
+        assert_eq!(opt.max_nps, Some(500_000));
+        assert_eq!(opt.stats_durability, None);
+    }
+
+    #[test]
+    fn test_stats_conf_mixed() {
+        let conf = file(
+            r#"
+            [stats]
+            stats-file = "/srv/fishnet-stats"
+            stats-db = "file.db"
+            stats-durability = "safe"
+            stats-tag = ["region=eu"]
+            "#,
+        );
+        let opt = cli(&["--stats-db", "cli.db", "--stats-tag", "region=us"]).or_file(conf.clone());
+        assert_eq!(opt.stats_db, Some(PathBuf::from("cli.db")));
+        assert_eq!(opt.stats_durability, Some(Durability::Safe));
+        assert_eq!(opt.stats_file, Some(PathBuf::from("/srv/fishnet-stats")));
+        assert_eq!(
+            opt.stats_tag,
+            vec![StatsTag {
+                key: "region".to_owned(),
+                value: "us".to_owned()
+            }]
+        );
+
+        // Disabling the stats file on the command line wins.
+        let opt = cli(&["--no-stats-file"]).or_file(conf);
+        assert!(opt.no_stats_file);
+        assert_eq!(opt.stats_file, None);
+    }
+}
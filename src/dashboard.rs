@@ -0,0 +1,550 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io,
+    io::{BufRead as _, BufReader, Write as _},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    configure::{ListenAddr, StatsOpt},
+    logger::Logger,
+    stats::{
+        Annotation, RateReport, Stats, StatsDb, StatsEvent, StatsRow, StatsSink, StatsSnapshot,
+        Timestamp, WindowReport,
+    },
+};
+
+const INDEX_HTML: &str = include_str!("dashboard.html");
+
+const DEFAULT_RECENT_ROWS: u32 = 100;
+const MAX_RECENT_ROWS: u32 = 1000;
+
+const DEFAULT_RATE_WINDOW_SECS: u64 = 300;
+const MAX_RATE_WINDOW_SECS: u64 = 7 * 24 * 60 * 60;
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(1);
+const RECENT_EVENTS: usize = 20;
+const CACHE_CAPACITY: usize = 32;
+
+/// Endpoints that query the database.
+const CACHED_PATHS: &[&str] = &[
+    "/api/dashboard",
+    "/stats/recent",
+    "/stats/rate",
+    "/stats/since",
+];
+
+/// Serves the dashboard from a background thread. The database is opened
+/// separately, so that requests never block recording.
+pub fn spawn(
+    addr: ListenAddr,
+    stats_opt: StatsOpt,
+    snapshot: watch::Receiver<StatsSnapshot>,
+    events: broadcast::Receiver<StatsEvent>,
+    logger: Logger,
+) {
+    let listener = match TcpListener::bind(addr.0) {
+        Ok(listener) => listener,
+        Err(err) => {
+            logger.error(&format!("Failed to serve dashboard on {addr}: {err}"));
+            return;
+        }
+    };
+    logger.info(&format!("Dashboard: http://{addr}/"));
+
+    thread::spawn(move || {
+        let dashboard = Dashboard {
+            db: if stats_opt.no_stats_file {
+                None
+            } else {
+                StatsDb::open_opt(&stats_opt)
+            },
+            snapshot,
+            events: RefCell::new(RecentEvents::new(events)),
+            cache: RefCell::new(ResponseCache::new(
+                stats_opt.dashboard_cache_ttl.unwrap_or(DEFAULT_CACHE_TTL),
+            )),
+        };
+        for stream in listener.incoming() {
+            if let Err(err) = stream.and_then(|stream| dashboard.handle(stream)) {
+                logger.debug(&format!("Dashboard request failed: {err}"));
+            }
+        }
+    });
+}
+
+struct Dashboard {
+    db: Option<StatsDb>,
+    snapshot: watch::Receiver<StatsSnapshot>,
+    events: RefCell<RecentEvents>,
+    cache: RefCell<ResponseCache>,
+}
+
+/// Descriptions of the most recent persistence errors and idle warnings,
+/// oldest first.
+struct RecentEvents {
+    rx: broadcast::Receiver<StatsEvent>,
+    messages: VecDeque<String>,
+}
+
+impl RecentEvents {
+    fn new(rx: broadcast::Receiver<StatsEvent>) -> RecentEvents {
+        RecentEvents {
+            rx,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Takes events delivered since the last call. Events are only
+    /// received while the dashboard is polled, so while nobody is watching,
+    /// older events are skipped in favor of the latest.
+    fn update(&mut self) -> Vec<String> {
+        loop {
+            let message = match self.rx.try_recv() {
+                Ok(StatsEvent::PersistenceError { sink, error }) => {
+                    format!("Failed to write to {}: {error}", sink_name(sink))
+                }
+                Ok(StatsEvent::Idle { since }) => {
+                    format!("No batch completed for {} minutes", since.as_secs() / 60)
+                }
+                Ok(StatsEvent::BatchRecorded { .. } | StatsEvent::Flushed) => continue,
+                // Mostly batches, which are not shown anyway.
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(
+                    broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed,
+                ) => break,
+            };
+            if self.messages.len() >= RECENT_EVENTS {
+                self.messages.pop_front();
+            }
+            self.messages.push_back(message);
+        }
+        self.messages.iter().cloned().collect()
+    }
+}
+
+fn sink_name(sink: StatsSink) -> &'static str {
+    match sink {
+        StatsSink::File => "stats file",
+        StatsSink::Database => "database",
+        StatsSink::Syslog => "syslog",
+        #[cfg(feature = "influx")]
+        StatsSink::Influx => "InfluxDB",
+    }
+}
+
+/// Recent responses of the endpoints that query the database, so that
+/// dashboards polling every second do not repeat identical queries.
+/// Responses expire after the ttl or when a batch is recorded.
+struct ResponseCache {
+    ttl: Duration,
+    // Least recently used first.
+    entries: VecDeque<CachedResponse>,
+}
+
+struct CachedResponse {
+    target: String,
+    batches: u64,
+    at: Instant,
+    response: Response,
+}
+
+impl ResponseCache {
+    fn new(ttl: Duration) -> ResponseCache {
+        ResponseCache {
+            ttl,
+            entries: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, target: &str, batches: u64, now: Instant) -> Option<Response> {
+        let i = self
+            .entries
+            .iter()
+            .position(|entry| entry.target == target)?;
+        let entry = self.entries.remove(i)?;
+        if entry.batches != batches || now.duration_since(entry.at) >= self.ttl {
+            return None;
+        }
+        let response = entry.response.clone();
+        self.entries.push_back(entry);
+        Some(response)
+    }
+
+    fn insert(&mut self, target: String, batches: u64, now: Instant, response: Response) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        self.entries.retain(|entry| entry.target != target);
+        if self.entries.len() >= CACHE_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(CachedResponse {
+            target,
+            batches,
+            at: now,
+            response,
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct DashboardData {
+    snapshot: StatsSnapshot,
+    hourly: Vec<WindowReport>,
+    annotations: Vec<Annotation>,
+    events: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RowsSince {
+    rows: Vec<StatsRow>,
+    next_id: i64,
+}
+
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+}
+
+impl Request<'_> {
+    fn parse(line: &str) -> Option<Request<'_>> {
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?;
+        let target = parts.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Some(Request {
+            method,
+            path,
+            query,
+        })
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find_map(|(key, value)| (key == name).then_some(value))
+    }
+}
+
+#[derive(Clone)]
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn json<T: Serialize>(value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::ok("application/json", body),
+            Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: message.as_bytes().to_owned(),
+        }
+    }
+
+    fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+impl Dashboard {
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        loop {
+            // Skip headers.
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let response = match Request::parse(&request_line) {
+            Some(req) if req.method == "GET" => self.cached_route(&req),
+            Some(_) => Response::error("405 Method Not Allowed", "read-only"),
+            None => Response::error("400 Bad Request", "malformed request"),
+        };
+        response.write_to(&mut stream)
+    }
+
+    fn cached_route(&self, req: &Request<'_>) -> Response {
+        if !CACHED_PATHS.contains(&req.path) {
+            return self.route(req);
+        }
+        let target = format!("{}?{}", req.path, req.query);
+        let batches = self.snapshot.borrow().stats.total_batches;
+        let now = Instant::now();
+        if let Some(response) = self.cache.borrow_mut().get(&target, batches, now) {
+            return response;
+        }
+        let response = self.route(req);
+        if response.status == "200 OK" {
+            self.cache
+                .borrow_mut()
+                .insert(target, batches, now, response.clone());
+        }
+        response
+    }
+
+    fn route(&self, req: &Request<'_>) -> Response {
+        match req.path {
+            "/" => Response::ok("text/html; charset=utf-8", INDEX_HTML.into()),
+            "/api/dashboard" => match self.data() {
+                Ok(data) => Response::json(&data),
+                Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+            },
+            "/stats/recent" => {
+                let n = match req.param("n").map(str::parse::<u32>) {
+                    None => DEFAULT_RECENT_ROWS,
+                    Some(Ok(n)) => n.min(MAX_RECENT_ROWS),
+                    Some(Err(_)) => return Response::error("400 Bad Request", "invalid n"),
+                };
+                match self.recent(n) {
+                    Ok(rows) => Response::json(&rows),
+                    Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+                }
+            }
+            "/stats/rate" => {
+                let window = match req.param("window").map(str::parse::<u64>) {
+                    None => DEFAULT_RATE_WINDOW_SECS,
+                    Some(Ok(secs)) if (1..=MAX_RATE_WINDOW_SECS).contains(&secs) => secs,
+                    Some(_) => return Response::error("400 Bad Request", "invalid window"),
+                };
+                match self.rate(Duration::from_secs(window)) {
+                    Ok(rate) => Response::json(&rate),
+                    Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+                }
+            }
+            "/stats/since" => {
+                let last_id = match req.param("id").map(str::parse::<i64>) {
+                    None => 0,
+                    Some(Ok(id)) => id,
+                    Some(Err(_)) => return Response::error("400 Bad Request", "invalid id"),
+                };
+                match self.rows_since(last_id) {
+                    Ok(rows) => Response::json(&rows),
+                    Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+                }
+            }
+            _ => Response::error("404 Not Found", "not found"),
+        }
+    }
+
+    fn data(&self) -> rusqlite::Result<DashboardData> {
+        let since = Timestamp::now() - Duration::from_secs(48 * 60 * 60);
+        let (hourly, annotations) = match self.db {
+            Some(ref db) => (db.hourly_reports(since)?, db.annotations(since)?),
+            None => (Vec::new(), Vec::new()),
+        };
+        Ok(DashboardData {
+            snapshot: self.snapshot.borrow().clone(),
+            hourly,
+            annotations,
+            events: self.events.borrow_mut().update(),
+        })
+    }
+
+    fn recent(&self, n: u32) -> rusqlite::Result<Vec<StatsRow>> {
+        match self.db {
+            Some(ref db) => db.recent(n),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn rows_since(&self, last_id: i64) -> rusqlite::Result<RowsSince> {
+        let (rows, next_id) = match self.db {
+            Some(ref db) => db.rows_since(last_id)?,
+            None => (Vec::new(), last_id),
+        };
+        Ok(RowsSince { rows, next_id })
+    }
+
+    fn rate(&self, window: Duration) -> rusqlite::Result<RateReport> {
+        match self.db {
+            Some(ref db) => db.rate(Timestamp::now(), window),
+            None => Ok(RateReport::new(window, &Stats::default())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::*;
+    use crate::stats::StatsDbConfig;
+
+    #[test]
+    fn test_dashboard_routes() {
+        let (_snapshot_tx, snapshot) = watch::channel(StatsSnapshot::default());
+        let (_events_tx, events) = broadcast::channel(1);
+        let dashboard = Dashboard {
+            db: Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+            snapshot,
+            events: RefCell::new(RecentEvents::new(events)),
+            cache: RefCell::new(ResponseCache::new(DEFAULT_CACHE_TTL)),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for (request, expected) in [
+            ("GET / HTTP/1.1", "HTTP/1.1 200 OK"),
+            ("GET /api/dashboard?x=1 HTTP/1.1", "HTTP/1.1 200 OK"),
+            ("GET /stats/recent?n=5 HTTP/1.1", "HTTP/1.1 200 OK"),
+            ("GET /stats/recent?n=x HTTP/1.1", "HTTP/1.1 400 Bad Request"),
+            ("GET /stats/rate HTTP/1.1", "HTTP/1.1 200 OK"),
+            ("GET /stats/rate?window=60 HTTP/1.1", "HTTP/1.1 200 OK"),
+            (
+                "GET /stats/rate?window=0 HTTP/1.1",
+                "HTTP/1.1 400 Bad Request",
+            ),
+            ("GET /stats/since?id=0 HTTP/1.1", "HTTP/1.1 200 OK"),
+            ("GET /stats/since?id=x HTTP/1.1", "HTTP/1.1 400 Bad Request"),
+            ("GET /missing HTTP/1.1", "HTTP/1.1 404 Not Found"),
+            ("POST / HTTP/1.1", "HTTP/1.1 405 Method Not Allowed"),
+        ] {
+            let client = thread::spawn(move || {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                write!(stream, "{request}\r\nHost: localhost\r\n\r\n").unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            });
+            let (stream, _) = listener.accept().unwrap();
+            dashboard.handle(stream).unwrap();
+            let response = client.join().unwrap();
+            assert!(response.starts_with(expected), "{request}: {response}");
+        }
+    }
+
+    #[test]
+    fn test_dashboard_annotations() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.annotate(Timestamp::now(), "hardware replaced").unwrap();
+        db.annotate(
+            Timestamp::now() - Duration::from_secs(72 * 60 * 60),
+            "too old",
+        )
+        .unwrap();
+        let (_snapshot_tx, snapshot) = watch::channel(StatsSnapshot::default());
+        let (_events_tx, events) = broadcast::channel(1);
+        let dashboard = Dashboard {
+            db: Some(db),
+            snapshot,
+            events: RefCell::new(RecentEvents::new(events)),
+            cache: RefCell::new(ResponseCache::new(DEFAULT_CACHE_TTL)),
+        };
+        let labels: Vec<_> = dashboard
+            .data()
+            .unwrap()
+            .annotations
+            .into_iter()
+            .map(|annotation| annotation.label)
+            .collect();
+        assert_eq!(labels, ["hardware replaced"]);
+    }
+
+    #[test]
+    fn test_recent_events() {
+        let (tx, rx) = broadcast::channel(64);
+        let mut events = RecentEvents::new(rx);
+        assert!(events.update().is_empty());
+
+        tx.send(StatsEvent::BatchRecorded {
+            stats: Stats::default(),
+            nnue_nps: None,
+        })
+        .unwrap();
+        tx.send(StatsEvent::PersistenceError {
+            sink: StatsSink::Database,
+            error: "disk I/O error".to_owned(),
+        })
+        .unwrap();
+        assert_eq!(
+            events.update(),
+            ["Failed to write to database: disk I/O error"]
+        );
+
+        for _ in 0..RECENT_EVENTS {
+            tx.send(StatsEvent::Idle {
+                since: Duration::from_secs(30 * 60),
+            })
+            .unwrap();
+        }
+        let messages = events.update();
+        assert_eq!(messages.len(), RECENT_EVENTS);
+        assert!(messages
+            .iter()
+            .all(|message| message == "No batch completed for 30 minutes"));
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let mut cache = ResponseCache::new(Duration::from_secs(1));
+        let now = Instant::now();
+        let response = |body: &str| Response::ok("text/plain", body.into());
+
+        cache.insert("/stats/rate?".to_owned(), 1, now, response("a"));
+        let hit = cache.get("/stats/rate?", 1, now + Duration::from_millis(500));
+        assert_eq!(hit.map(|r| r.body), Some(b"a".to_vec()));
+        assert!(cache.get("/stats/rate?window=60", 1, now).is_none());
+
+        // Expired.
+        assert!(cache
+            .get("/stats/rate?", 1, now + Duration::from_secs(1))
+            .is_none());
+
+        // Invalidated by a new batch.
+        cache.insert("/stats/rate?".to_owned(), 1, now, response("a"));
+        assert!(cache.get("/stats/rate?", 2, now).is_none());
+
+        // Least recently used entries are evicted.
+        for i in 0..CACHE_CAPACITY {
+            cache.insert(format!("/stats/recent?n={i}"), 1, now, response("b"));
+        }
+        assert!(cache.get("/stats/recent?n=0", 1, now).is_some());
+        cache.insert("/stats/rate?".to_owned(), 1, now, response("a"));
+        assert!(cache.get("/stats/recent?n=0", 1, now).is_some());
+        assert!(cache.get("/stats/recent?n=1", 1, now).is_none());
+        assert_eq!(cache.entries.len(), CACHE_CAPACITY);
+
+        // Disabled.
+        let mut disabled = ResponseCache::new(Duration::ZERO);
+        disabled.insert("/stats/rate?".to_owned(), 1, now, response("a"));
+        assert!(disabled.get("/stats/rate?", 1, now).is_none());
+    }
+}
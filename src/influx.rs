@@ -0,0 +1,198 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use url::Url;
+
+use crate::stats::Stats;
+
+const MEASUREMENT: &str = "fishnet";
+
+/// Pushes stats to InfluxDB, as line protocol.
+pub enum Influx {
+    /// udp://host:port, for the UDP listener of InfluxDB 1.x or Telegraf.
+    Udp(UdpSocket),
+    /// http(s)://.../write?db=... or .../api/v2/write?org=...&bucket=...,
+    /// sent in the background on the current tokio runtime.
+    Http {
+        client: reqwest::Client,
+        url: Url,
+        runtime: tokio::runtime::Handle,
+        // Failure of a background request, reported by the next send.
+        failed: Arc<Mutex<Option<String>>>,
+    },
+}
+
+impl Influx {
+    /// Connects to `url`. HTTP requests are sent with `client`.
+    pub fn connect(url: &Url, client: &reqwest::Client) -> io::Result<Influx> {
+        match url.scheme() {
+            "udp" => {
+                let addr = url.socket_addrs(|| None)?;
+                let socket = UdpSocket::bind(if addr.first().is_some_and(|a| a.is_ipv6()) {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })?;
+                socket.connect(&*addr)?;
+                Ok(Influx::Udp(socket))
+            }
+            "http" | "https" => Ok(Influx::Http {
+                client: client.clone(),
+                url: url.clone(),
+                runtime: tokio::runtime::Handle::try_current()
+                    .map_err(|err| io::Error::new(io::ErrorKind::Unsupported, err))?,
+                failed: Arc::default(),
+            }),
+            scheme => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported scheme {scheme:?}, expected udp, http or https"),
+            )),
+        }
+    }
+
+    /// Sends a line. Over HTTP the request completes in the background, so
+    /// its failure is only returned by the following send.
+    pub fn send(&self, line: &str) -> io::Result<()> {
+        match self {
+            Influx::Udp(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            Influx::Http {
+                client,
+                url,
+                runtime,
+                failed,
+            } => {
+                let previous = failed.lock().expect("influx failure").take();
+                let request = client.post(url.clone()).body(line.to_owned()).send();
+                let failed = Arc::clone(failed);
+                runtime.spawn(async move {
+                    let error = match request.await {
+                        Ok(response) if response.status().is_success() => return,
+                        Ok(response) => format!("HTTP {}", response.status()),
+                        Err(err) => err.to_string(),
+                    };
+                    *failed.lock().expect("influx failure") = Some(error);
+                });
+                match previous {
+                    Some(error) => Err(io::Error::other(error)),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Formats the totals after a batch, tagged with `tags`.
+pub fn batch_line(
+    tags: &BTreeMap<String, String>,
+    stats: &Stats,
+    nnue_nps: Option<u32>,
+    time: SystemTime,
+) -> String {
+    let mut line = escape(MEASUREMENT, &[',', ' ']);
+    for (key, value) in tags {
+        let _ = write!(
+            line,
+            ",{}={}",
+            escape(key, &[',', '=', ' ']),
+            escape(value, &[',', '=', ' '])
+        );
+    }
+    let _ = write!(
+        line,
+        " batches={}i,positions={}i,nodes={}i",
+        stats.total_batches, stats.total_positions, stats.total_nodes
+    );
+    if let Some(nnue_nps) = nnue_nps {
+        let _ = write!(line, ",nnue_nps={nnue_nps}i");
+    }
+    // Times before the epoch are not representable, leave them to the
+    // server.
+    if let Ok(since_epoch) = time.duration_since(UNIX_EPOCH) {
+        let _ = write!(line, " {}", since_epoch.as_nanos());
+    }
+    line
+}
+
+/// Backslash escapes `special` characters and backslashes. Newlines cannot
+/// be escaped, so they become escaped spaces.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' | '\r' => escaped.push_str("\\ "),
+            c if c == '\\' || special.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn stats() -> Stats {
+        Stats {
+            total_batches: 2,
+            total_positions: 120,
+            total_nodes: 3_000_000,
+            ..Stats::default()
+        }
+    }
+
+    #[test]
+    fn test_batch_line() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            batch_line(&BTreeMap::new(), &stats(), Some(400_000), time),
+            "fishnet batches=2i,positions=120i,nodes=3000000i,nnue_nps=400000i 1700000000000000000"
+        );
+        let tags = BTreeMap::from([
+            ("host".to_owned(), "box 1".to_owned()),
+            ("region".to_owned(), "eu,west=2".to_owned()),
+        ]);
+        assert_eq!(
+            batch_line(&tags, &stats(), None, time),
+            "fishnet,host=box\\ 1,region=eu\\,west\\=2 batches=2i,positions=120i,nodes=3000000i 1700000000000000000"
+        );
+        assert!(
+            batch_line(&tags, &stats(), None, UNIX_EPOCH - Duration::from_secs(1))
+                .ends_with("nodes=3000000i")
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a b,c=d", &[',', '=', ' ']), "a\\ b\\,c\\=d");
+        assert_eq!(escape("back\\slash", &[]), "back\\\\slash");
+        assert_eq!(escape("multi\nline", &[' ']), "multi\\ line");
+    }
+
+    #[test]
+    fn test_send_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let url = Url::parse(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        let influx = Influx::connect(&url, &reqwest::Client::new()).expect("connect");
+        influx.send("fishnet batches=1i").expect("send");
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..n], b"fishnet batches=1i");
+    }
+
+    #[test]
+    fn test_unsupported_scheme() {
+        let url = Url::parse("tcp://localhost:8089").unwrap();
+        assert!(Influx::connect(&url, &reqwest::Client::new()).is_err());
+    }
+}
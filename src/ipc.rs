@@ -17,10 +17,15 @@ pub struct Chunk {
     pub variant: Variant,
     pub flavor: EngineFlavor,
     pub positions: Vec<Position>,
+    /// Keep the engine output, for debugging, see --stats-capture-output.
+    pub capture_output: bool,
 }
 
 impl Chunk {
     pub const MAX_POSITIONS: usize = 6;
+
+    /// Bytes of engine output kept per position, if captured.
+    pub const MAX_CAPTURED_OUTPUT: usize = 16 * 1024;
 }
 
 #[derive(Debug, Clone)]
@@ -47,6 +52,8 @@ pub struct PositionResponse {
     pub nodes: u64,
     pub time: Duration,
     pub nps: Option<u32>,
+    /// Truncated engine output, if captured.
+    pub output: Option<String>,
 }
 
 impl PositionResponse {
@@ -3,12 +3,17 @@
 mod api;
 mod assets;
 mod configure;
+mod dashboard;
+#[cfg(feature = "influx")]
+mod influx;
 mod ipc;
 mod logger;
 mod queue;
 mod stats;
 mod stockfish;
+mod syslog;
 mod systemd;
+mod temperature;
 mod update;
 mod util;
 
@@ -17,6 +22,7 @@ use std::{
     io::IsTerminal as _,
     path::PathBuf,
     process,
+    process::ExitCode,
     sync::Arc,
     thread,
     time::{Duration, Instant},
@@ -34,23 +40,27 @@ use tokio::{
 
 use crate::{
     assets::{Assets, ByEngineFlavor, Cpu, EngineFlavor},
-    configure::{Command, Cores, CpuPriority, Opt},
+    configure::{Command, Cores, CpuPriority, Opt, StatsCommand},
     ipc::{Chunk, ChunkFailed, Pull},
     logger::{Logger, ProgressAt},
+    stats::{RowFilter, Timestamp},
     update::{auto_update, UpdateSuccess},
-    util::{dot_thousands, RandomizedBackoff},
+    util::{dot_thousands, format_nodes, RandomizedBackoff},
 };
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let client = configure_client();
     let opt = configure::parse_and_configure(&client).await;
-    let logger = Logger::new(opt.verbose, opt.command.is_some_and(Command::is_systemd));
+    let logger = Logger::new(
+        opt.verbose,
+        opt.command.as_ref().is_some_and(Command::is_systemd),
+    );
 
     if opt.auto_update {
         let current_exe = env::current_exe().expect("current exe");
         match auto_update(
-            !opt.command.is_some_and(Command::is_systemd),
+            !opt.command.as_ref().is_some_and(Command::is_systemd),
             &client,
             &logger,
         )
@@ -67,13 +77,64 @@ async fn main() {
         }
     }
 
+    // Stats commands exit with their status, so that scripts notice failures.
     match opt.command {
         Some(Command::Run) | None => run(opt, &client, &logger).await,
         Some(Command::Systemd) => systemd::systemd_system(opt),
         Some(Command::SystemdUser) => systemd::systemd_user(opt),
         Some(Command::Configure) => (),
         Some(Command::License) => license(&logger),
+        Some(Command::BackfillStats) => return stats::backfill_stats(opt.stats),
+        Some(Command::Stats(StatsCommand::Compare { from, to })) => {
+            return stats::compare_stats(opt.stats, from, to)
+        }
+        Some(Command::Stats(StatsCommand::Versions)) => return stats::version_stats(opt.stats),
+        Some(Command::Stats(StatsCommand::Export {
+            format,
+            tag,
+            since,
+            until,
+            csv_delimiter,
+        })) => {
+            return stats::export_stats(
+                opt.stats,
+                format,
+                csv_delimiter,
+                &RowFilter {
+                    since: since.map(Timestamp::from_unix_secs),
+                    until: until.map(Timestamp::from_unix_secs),
+                    tags: tag.into_iter().map(|tag| (tag.key, tag.value)).collect(),
+                },
+            )
+        }
+        Some(Command::Stats(StatsCommand::Rollup { older_than, bucket })) => {
+            return stats::rollup_stats(opt.stats, older_than, bucket)
+        }
+        Some(Command::Stats(StatsCommand::Busiest { per })) => {
+            return stats::busiest_stats(opt.stats, per)
+        }
+        Some(Command::Stats(StatsCommand::Annotate { label })) => {
+            return stats::annotate_stats(opt.stats, &label)
+        }
+        Some(Command::Stats(StatsCommand::Output { id })) => {
+            return stats::output_stats(opt.stats, id)
+        }
+        Some(Command::Stats(StatsCommand::Dump)) => {
+            return stats::dump_stats(opt.stats, opt.cores.unwrap_or(Cores::Auto).number())
+        }
+        Some(Command::ImportStats { source }) => {
+            return stats::import_stats(opt.stats, &client, &source).await
+        }
+        Some(Command::MergeDb { file, source }) => {
+            return stats::merge_db(opt.stats, &file, &source)
+        }
+        Some(Command::BenchStats { batches, rate }) => {
+            return stats::bench_stats(opt.stats, batches, rate)
+        }
+        #[cfg(feature = "schema")]
+        Some(Command::StatsFileJsonSchema) => println!("{}", stats::stats_json_schema()),
     }
+    ExitCode::SUCCESS
 }
 
 async fn run(opt: Opt, client: &Client, logger: &Logger) {
@@ -136,16 +197,41 @@ async fn run(opt: Opt, client: &Client, logger: &Logger) {
     logger.headline(&format!("Running ({to_stop} to stop) ..."));
 
     // Spawn queue actor.
+    let stats_global_nps = opt.stats.stats_global_nps;
+    let stats_interval_histogram = opt.stats.stats_interval_histogram;
+    let max_nps = opt.stats.max_nps;
+    let nps_reference = opt.stats.nps_reference;
+    // Only logged if requested.
+    let mut nps_converged = opt.stats.nps_convergence.is_none();
+    let stats_idle_after = opt
+        .stats
+        .stats_idle_after
+        .unwrap_or(stats::DEFAULT_IDLE_AFTER);
+    let dashboard_opt = opt.stats.dashboard.map(|addr| (addr, opt.stats.clone()));
+    // Only here, so that other commands never migrate as a side effect.
+    stats::adopt_moved_default_stats_file(&opt.stats, opt.conf());
     let (mut queue, queue_actor) = queue::channel(
         opt.stats,
         opt.backlog,
         cores,
         api,
         opt.max_backoff.unwrap_or_default(),
+        client,
         logger.clone(),
     );
     join_set.spawn(queue_actor.run());
 
+    // Serve dashboard.
+    if let Some((addr, stats_opt)) = dashboard_opt {
+        dashboard::spawn(
+            addr,
+            stats_opt,
+            queue.watch_snapshot().await,
+            queue.subscribe().await,
+            logger.clone(),
+        );
+    }
+
     // Spawn workers. Workers handle engine processes and send their results
     // to tx, thereby requesting more work.
     let mut rx = {
@@ -173,6 +259,7 @@ async fn run(opt: Opt, client: &Client, logger: &Logger) {
     let mut restart = None;
     let mut up_to_date = Instant::now();
     let mut summarized = Instant::now();
+    let started = Instant::now();
     let mut shutdown_soon = false;
 
     loop {
@@ -203,14 +290,101 @@ async fn run(opt: Opt, client: &Client, logger: &Logger) {
         if now.duration_since(summarized) >= Duration::from_secs(120) {
             summarized = now;
             let (stats, nnue_nps) = queue.stats().await;
-            logger.fishnet_info(&format!(
-                "v{}: {} (nnue), {} batches, {} positions, {} total nodes",
-                env!("CARGO_PKG_VERSION"),
-                nnue_nps,
-                dot_thousands(stats.total_batches),
-                dot_thousands(stats.total_positions),
-                dot_thousands(stats.total_nodes),
-            ));
+            let session = queue.session().await;
+            if !stats.is_empty() {
+                logger.fishnet_info(&format!(
+                    "v{}: {} (nnue{}{}), {} batches, {} positions{}{}, {} total nodes",
+                    env!("CARGO_PKG_VERSION"),
+                    nnue_nps,
+                    nnue_nps
+                        .default_engine()
+                        .peak_nps()
+                        .map_or(String::new(), |peak| format!(
+                            ", peak {}",
+                            stats::format_nps_per_core(peak)
+                        )),
+                    nnue_nps.default_engine().percentile(0.5).map_or(
+                        String::new(),
+                        |median| format!(", median {}", stats::format_nps_per_core(median))
+                    ),
+                    dot_thousands(stats.total_batches),
+                    dot_thousands(stats.total_positions),
+                    stats
+                        .cache_hit_ratio()
+                        .filter(|_| stats.total_positions_cached > 0)
+                        .map_or(String::new(), |ratio| format!(
+                            " ({:.1}% cached)",
+                            100.0 * ratio
+                        )),
+                    session
+                        .duplicate_ratio()
+                        .map_or(String::new(), |ratio| format!(
+                            " ({:.1}% duplicate this session)",
+                            100.0 * ratio
+                        )),
+                    dot_thousands(stats.total_nodes),
+                ));
+                if let Some(global_nps) = stats_global_nps {
+                    // Estimated from the configured rate, because no total
+                    // is published.
+                    let global_nodes = (global_nps as f64 * started.elapsed().as_secs_f64()) as u64;
+                    logger.fishnet_info(&format!(
+                        "Contributed {:.2}% of an estimated {} nodes since start",
+                        100.0 * queue.contribution_share(global_nodes).await,
+                        format_nodes(global_nodes),
+                    ));
+                }
+                if let Some(max_nps) = max_nps {
+                    if nnue_nps.default_engine().estimate().is_some() {
+                        logger.fishnet_info(&format!(
+                            "Headroom {:.1}% below {}",
+                            100.0 * nnue_nps.default_engine().headroom(max_nps),
+                            stats::format_nps_per_core(max_nps)
+                        ));
+                    }
+                }
+                if let Some(reference) = nps_reference {
+                    if let Some(index) = nnue_nps.default_engine().perf_index(reference) {
+                        logger.fishnet_info(&format!(
+                            "Performance index {:.2} (relative to {})",
+                            index,
+                            stats::format_nps_per_core(reference)
+                        ));
+                    }
+                }
+                if !nps_converged && nnue_nps.default_engine().has_converged() {
+                    nps_converged = true;
+                    logger.fishnet_info(&format!(
+                        "Nps estimate converged at {}",
+                        nnue_nps.default_engine()
+                    ));
+                }
+                if let Some(shadow) = queue.shadow_nps().await {
+                    logger.fishnet_info(&format!(
+                        "Smoothing: {} {}, {} {} (shadow)",
+                        nnue_nps.default_engine().smoothing(),
+                        nnue_nps.default_engine(),
+                        shadow.smoothing(),
+                        shadow
+                    ));
+                }
+                if stats_interval_histogram {
+                    logger.fishnet_info(&format!(
+                        "Batch durations: {}",
+                        queue.batch_durations().await
+                    ));
+                }
+            }
+        }
+
+        // Detect idling, distinct from slow progress.
+        if !stats_idle_after.is_zero() {
+            if let Some(idle) = queue.check_idle(stats_idle_after).await {
+                logger.warn(&format!(
+                    "No batch completed in the last {} minutes. No work available, or engine stuck?",
+                    idle.as_secs() / 60
+                ));
+            }
         }
 
         // Main loop. Handles signals, forwards worker results from rx to the
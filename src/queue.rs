@@ -1,6 +1,6 @@
 use std::{
     cmp::{max, min},
-    collections::{hash_map::Entry, HashMap, VecDeque},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
     error::Error,
     fmt,
     iter::{once, zip},
@@ -9,14 +9,16 @@ use std::{
     time::Duration,
 };
 
+use reqwest::Client;
 use shakmaty::{
     fen::Fen,
     uci::{IllegalUciMoveError, UciMove},
     variant::{Variant, VariantPosition},
+    zobrist::{Zobrist64, ZobristHash as _},
     CastlingMode, EnPassantMode, Position as _, PositionError,
 };
 use tokio::{
-    sync::{mpsc, oneshot, Mutex, Notify},
+    sync::{broadcast, mpsc, oneshot, watch, Mutex, Notify},
     time::{sleep, Instant},
 };
 use url::Url;
@@ -30,7 +32,10 @@ use crate::{
     configure::{BacklogOpt, Endpoint, MaxBackoff, StatsOpt},
     ipc::{Chunk, ChunkFailed, Position, PositionResponse, Pull},
     logger::{short_variant_name, Logger, ProgressAt, QueueStatusBar},
-    stats::{NpsRecorder, Stats, StatsRecorder},
+    stats::{
+        format_nps_per_core, BatchDurations, NpsRecorder, NpsRecorders, Stats, StatsEvent,
+        StatsRecorder, StatsSnapshot, HCE_ENGINE,
+    },
     util::{grow_with_and_get_mut, NevermindExt as _, RandomizedBackoff},
 };
 
@@ -40,6 +45,7 @@ pub fn channel(
     cores: NonZeroUsize,
     api: ApiStub,
     max_backoff: MaxBackoff,
+    client: &Client,
     logger: Logger,
 ) -> (QueueStub, QueueActor) {
     let (tx, rx) = mpsc::unbounded_channel();
@@ -47,6 +53,7 @@ pub fn channel(
     let state = Arc::new(Mutex::new(QueueState::new(
         stats_opt,
         cores,
+        client,
         logger.clone(),
     )));
     let stub = QueueStub {
@@ -113,13 +120,48 @@ impl QueueStub {
         }
     }
 
-    pub async fn stats(&self) -> (Stats, NpsRecorder) {
+    pub async fn stats(&self) -> (Stats, NpsRecorders) {
         let state = self.state.lock().await;
         (
-            state.stats_recorder.stats.clone(),
+            state.stats_recorder.totals().clone(),
             state.stats_recorder.nnue_nps.clone(),
         )
     }
+
+    pub async fn session(&self) -> Stats {
+        let state = self.state.lock().await;
+        state.stats_recorder.session().clone()
+    }
+
+    pub async fn shadow_nps(&self) -> Option<NpsRecorder> {
+        let state = self.state.lock().await;
+        state.stats_recorder.shadow_nps().cloned()
+    }
+
+    pub async fn watch_snapshot(&self) -> watch::Receiver<StatsSnapshot> {
+        let state = self.state.lock().await;
+        state.stats_recorder.watch_snapshot()
+    }
+
+    pub async fn subscribe(&self) -> broadcast::Receiver<StatsEvent> {
+        let state = self.state.lock().await;
+        state.stats_recorder.subscribe()
+    }
+
+    pub async fn batch_durations(&self) -> BatchDurations {
+        let state = self.state.lock().await;
+        state.stats_recorder.batch_durations().clone()
+    }
+
+    pub async fn check_idle(&self, threshold: Duration) -> Option<Duration> {
+        let mut state = self.state.lock().await;
+        state.stats_recorder.check_idle(threshold)
+    }
+
+    pub async fn contribution_share(&self, global_total: u64) -> f64 {
+        let state = self.state.lock().await;
+        state.stats_recorder.contribution_share(global_total)
+    }
 }
 
 struct QueueState {
@@ -133,14 +175,28 @@ struct QueueState {
 }
 
 impl QueueState {
-    fn new(stats_opt: StatsOpt, cores: NonZeroUsize, logger: Logger) -> QueueState {
+    #[cfg_attr(not(feature = "influx"), allow(unused_variables))]
+    fn new(
+        stats_opt: StatsOpt,
+        cores: NonZeroUsize,
+        client: &Client,
+        logger: Logger,
+    ) -> QueueState {
+        #[cfg(feature = "influx")]
+        let influx_url = stats_opt.influx_url.clone();
+        #[cfg_attr(not(feature = "influx"), allow(unused_mut))]
+        let mut stats_recorder = StatsRecorder::new(stats_opt, Some(cores));
+        #[cfg(feature = "influx")]
+        if let Some(url) = influx_url {
+            stats_recorder.connect_influx(&url, client);
+        }
         QueueState {
             shutdown_soon: false,
             cores,
             incoming: VecDeque::new(),
             pending: HashMap::new(),
             move_submissions: VecDeque::new(),
-            stats_recorder: StatsRecorder::new(stats_opt, cores),
+            stats_recorder,
             logger,
         }
     }
@@ -160,9 +216,11 @@ impl QueueState {
             )),
             Entry::Vacant(entry) => {
                 let progress_at = ProgressAt::from(&batch);
+                let capture_output = self.stats_recorder.sample_output();
 
                 let mut positions = Vec::with_capacity(batch.chunks.len() * Chunk::MAX_POSITIONS);
-                for chunk in batch.chunks {
+                for mut chunk in batch.chunks {
+                    chunk.capture_output = capture_output;
                     for pos in &chunk.positions {
                         if let Some(position_index) = pos.position_index {
                             *grow_with_and_get_mut(&mut positions, position_index.0, || {
@@ -179,8 +237,10 @@ impl QueueState {
                     variant: batch.variant,
                     url: batch.url,
                     positions,
+                    unique_positions: batch.unique_positions,
                     total_nodes: 0,
                     total_cpu_time: Duration::ZERO,
+                    started_at: Instant::now(),
                 });
 
                 self.logger.progress(self.status_bar(), progress_at);
@@ -219,6 +279,13 @@ impl QueueState {
                 if let Some(progress_at) = progress_at {
                     self.logger.progress(self.status_bar(), progress_at);
                 }
+                if let Some(pending) = batch_ids.last().and_then(|id| self.pending.get(id)) {
+                    if pending.work.is_analysis() {
+                        let done = pending.positions.iter().filter(|pos| pos.is_some()).count();
+                        self.stats_recorder
+                            .note_progress(done as u64, pending.positions.len() as u64);
+                    }
+                }
                 for batch_id in batch_ids {
                     self.maybe_finished(queue.clone(), batch_id);
                 }
@@ -260,12 +327,32 @@ impl QueueState {
                             } else {
                                 None
                             };
+                            // Chunks of the first batch are spread over all
+                            // workers, so it includes the warmup of each
+                            // engine.
+                            let cold_start = self.stats_recorder.session().total_batches == 0;
+                            self.stats_recorder
+                                .record_cached(completed.skipped_positions());
+                            self.stats_recorder
+                                .record_unique(completed.unique_positions);
+                            if let Some(output) = completed.captured_output() {
+                                self.stats_recorder.record_output(&output);
+                            }
                             self.stats_recorder.record_batch(
                                 completed.total_positions(),
                                 completed.total_nodes,
                                 nnue_nps,
+                                completed.elapsed,
+                                cold_start,
                             );
-                            format!("{} knps/core", nps / 1000)
+                            if nnue_nps.is_none() && !cold_start {
+                                self.stats_recorder.record_engine_nps(
+                                    HCE_ENGINE,
+                                    nps,
+                                    completed.total_nodes,
+                                );
+                            }
+                            format_nps_per_core(nps)
                         }
                         None => "? nps".to_owned(),
                     });
@@ -541,6 +628,7 @@ pub struct IncomingBatch {
     variant: Variant,
     chunks: Vec<Chunk>,
     url: Option<Url>,
+    unique_positions: u64,
 }
 
 impl IncomingBatch {
@@ -569,15 +657,31 @@ impl IncomingBatch {
 
         let root_fen = Fen(root_pos.clone().into_setup(EnPassantMode::Legal));
 
-        let body_moves = {
+        let (body_moves, hashes) = {
             let mut moves = Vec::with_capacity(body.moves.len());
+            let mut hashes = Vec::with_capacity(body.moves.len() + 1);
             let mut pos = root_pos;
+            hashes.push(pos.zobrist_hash::<Zobrist64>(EnPassantMode::Legal));
             for uci in body.moves {
                 let m = uci.to_move(&pos)?;
                 moves.push(m.to_uci(CastlingMode::Chess960));
                 pos.play_unchecked(&m);
+                hashes.push(pos.zobrist_hash::<Zobrist64>(EnPassantMode::Legal));
             }
-            moves
+            (moves, hashes)
+        };
+
+        // Positions that repeat within the game are analysed again.
+        let unique_positions = if body.work.is_analysis() {
+            hashes
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !body.skip_positions.contains(&PositionIndex(*i)))
+                .map(|(_, hash)| hash)
+                .collect::<HashSet<_>>()
+                .len() as u64
+        } else {
+            1
         };
 
         Ok(IncomingBatch {
@@ -585,6 +689,7 @@ impl IncomingBatch {
             url: url.clone(),
             flavor,
             variant: body.variant,
+            unique_positions,
             chunks: match body.work {
                 Work::Move { .. } => {
                     vec![Chunk {
@@ -592,6 +697,7 @@ impl IncomingBatch {
                         deadline: Instant::now() + body.work.timeout_per_ply(),
                         flavor,
                         variant: body.variant,
+                        capture_output: false,
                         positions: vec![Position {
                             work: body.work,
                             url,
@@ -675,6 +781,7 @@ impl IncomingBatch {
                                 flavor,
                                 variant: body.variant,
                                 positions: chunk_positions,
+                                capture_output: false,
                             });
                         }
                     }
@@ -688,6 +795,7 @@ impl IncomingBatch {
                             flavor,
                             variant: body.variant,
                             positions: vec![Skip::Skip; num_positions],
+                            unique_positions: 0,
                             total_nodes: 0,
                             total_cpu_time: Duration::ZERO,
                         }));
@@ -749,8 +857,10 @@ struct PendingBatch {
     flavor: EngineFlavor,
     variant: Variant,
     positions: Vec<Option<Skip<PositionResponse>>>,
+    unique_positions: u64,
     total_nodes: u64,
     total_cpu_time: Duration,
+    started_at: Instant,
 }
 
 impl PendingBatch {
@@ -763,8 +873,10 @@ impl PendingBatch {
                 flavor: self.flavor,
                 variant: self.variant,
                 positions,
+                unique_positions: self.unique_positions,
                 total_nodes: self.total_nodes,
                 total_cpu_time: self.total_cpu_time,
+                elapsed: self.started_at.elapsed(),
             }),
             None => Err(self),
         }
@@ -795,8 +907,10 @@ pub struct CompletedBatch {
     flavor: EngineFlavor,
     variant: Variant,
     positions: Vec<Skip<PositionResponse>>,
+    unique_positions: u64,
     total_nodes: u64,
     total_cpu_time: Duration,
+    elapsed: Duration,
 }
 
 impl CompletedBatch {
@@ -830,6 +944,27 @@ impl CompletedBatch {
             .sum()
     }
 
+    /// Engine output of all positions, if captured.
+    fn captured_output(&self) -> Option<String> {
+        let mut outputs = self
+            .positions
+            .iter()
+            .filter_map(|p| match p {
+                Skip::Present(pos) => pos.output.as_deref(),
+                Skip::Skip => None,
+            })
+            .peekable();
+        outputs.peek()?;
+        Some(outputs.collect())
+    }
+
+    fn skipped_positions(&self) -> u64 {
+        self.positions
+            .iter()
+            .filter(|p| matches!(p, Skip::Skip))
+            .count() as u64
+    }
+
     fn nps(&self) -> Option<u32> {
         (u128::from(self.total_nodes) * 1000)
             .checked_div(self.total_cpu_time.as_millis())
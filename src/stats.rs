@@ -1,43 +1,595 @@
 use std::{
     cmp::{max, min},
-    fmt,
+    collections::{BTreeMap, VecDeque},
+    env, fmt, fs,
     fs::{File, OpenOptions},
     io,
-    io::{Read as _, Seek as _, Write as _},
+    io::{Read as _, Seek as _, Write},
+    iter, mem,
     num::NonZeroUsize,
-    path::PathBuf,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    ops,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use serde::{Deserialize, Serialize};
-use rusqlite::{params, Connection, Result}; // SQLite-Bibliothek
+use fastrand::Rng;
+use fs2::FileExt as _;
+use rusqlite::{
+    params, params_from_iter,
+    types::{FromSql, FromSqlResult, ToSqlOutput, Type, ValueRef},
+    Connection, Error, ErrorCode, OpenFlags, OptionalExtension as _, Result, Row, ToSql,
+};
+use serde::{Deserialize, Serialize, Serializer};
+use tempfile::NamedTempFile;
+use tokio::sync::{broadcast, watch};
 
-use crate::configure::StatsOpt;
+#[cfg(feature = "influx")]
+use crate::influx::{self, Influx};
+use crate::{
+    configure::{
+        confirm, DbTable, Durability, ExportFormat, Granularity, NodeUnit, Smoothing, StatsKey,
+        StatsOpt, StatsTag, TimeRange,
+    },
+    syslog::{self, Syslog},
+    temperature::CpuTemperature,
+    util::{expand_vars, format_nodes},
+};
 
-fn default_stats_file() -> Option<PathBuf> {
+/// Stats file used without --stats-file or --stats-xdg, i.e.
+/// `~/.fishnet-stats`. `None` if the home directory is unknown.
+pub fn default_stats_file() -> Option<PathBuf> {
     home::home_dir().map(|dir| dir.join(".fishnet-stats"))
 }
 
+fn xdg_state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home::home_dir().map(|dir| dir.join(".local").join("state")))
+        .map(|dir| dir.join("fishnet"))
+}
+
+fn xdg_stats_file() -> Option<PathBuf> {
+    let dir = xdg_state_dir()?;
+    Some(match default_stats_file() {
+        Some(legacy) => migrate_stats_file(&legacy, &dir),
+        None => dir.join("stats"),
+    })
+}
+
+/// Moves the stats file from the legacy location into `dir`, unless already
+/// done. Returns the path to use, which is the legacy location if the
+/// migration fails.
+fn migrate_stats_file(legacy: &Path, dir: &Path) -> PathBuf {
+    let path = dir.join("stats");
+    if let Err(err) = fs::create_dir_all(dir) {
+        eprintln!("E: Failed to create {dir:?}: {err}. Using {legacy:?} ...");
+        return legacy.to_owned();
+    }
+    if legacy.exists() && !path.exists() {
+        match fs::rename(legacy, &path) {
+            Ok(()) => println!("Migrated {legacy:?} to {path:?}"),
+            Err(err) => {
+                eprintln!(
+                    "E: Failed to migrate {legacy:?} to {path:?}: {err}. Using {legacy:?} ..."
+                );
+                return legacy.to_owned();
+            }
+        }
+    }
+    path
+}
+
+/// Marker file next to the configuration file, listing the default stats
+/// file locations of previous runs, newest last.
+const KNOWN_LOCATIONS_FILE: &str = ".fishnet-stats-locations";
+
+const MAX_KNOWN_LOCATIONS: usize = 8;
+
+fn read_known_locations(marker: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(marker)
+        .map(|contents| {
+            contents
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn remember_location(marker: &Path, path: &Path) -> io::Result<()> {
+    let mut known = read_known_locations(marker);
+    if known.last().is_some_and(|last| last == path) {
+        return Ok(());
+    }
+    known.retain(|known| known != path);
+    known.push(path.to_owned());
+    let mut contents = String::new();
+    for known in &known[known.len().saturating_sub(MAX_KNOWN_LOCATIONS)..] {
+        // Not representable as a line.
+        if let Some(known) = known.to_str().filter(|known| !known.contains('\n')) {
+            contents.push_str(known);
+            contents.push('\n');
+        }
+    }
+    fs::write(marker, contents)
+}
+
+/// Newest non-empty stats file at a `known` location, if there are no stats
+/// at `path`, yet. For example after the home directory was renamed or
+/// remounted.
+fn moved_stats_file(known: &[PathBuf], path: &Path) -> Option<PathBuf> {
+    if fs::metadata(path).is_ok_and(|meta| meta.len() > 0) {
+        return None;
+    }
+    known
+        .iter()
+        .filter(|known| known.as_path() != path)
+        .filter_map(|known| {
+            let meta = fs::metadata(known).ok()?;
+            (meta.is_file() && meta.len() > 0)
+                .then(|| (meta.modified().unwrap_or(UNIX_EPOCH), known))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, known)| known.clone())
+}
+
+/// Offers to migrate the stats of a previous run, if the default stats
+/// location moved since. Previous locations are remembered next to the
+/// configuration file `conf`.
+pub fn adopt_moved_default_stats_file(opt: &StatsOpt, conf: &Path) {
+    if opt.no_stats_file || opt.stats_file.is_some() {
+        return;
+    }
+    let path = if opt.stats_xdg {
+        xdg_stats_file()
+    } else {
+        default_stats_file()
+    };
+    if let Some(path) = path {
+        let marker = conf
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(KNOWN_LOCATIONS_FILE);
+        adopt_moved_stats_file(&marker, &path, |previous| {
+            confirm(&format!(
+                "Found stats of a previous run at {previous:?}. Migrate them to {path:?}?"
+            ))
+        });
+    }
+}
+
+/// Copies the stats file of a previous run to `path`, if the default
+/// location moved and `confirm` agrees. `confirm` returns `None` if nobody
+/// can be asked, which does not migrate. Then remembers `path` for future
+/// runs. The previous file is left in place.
+fn adopt_moved_stats_file(marker: &Path, path: &Path, confirm: impl FnOnce(&Path) -> Option<bool>) {
+    if let Some(previous) = moved_stats_file(&read_known_locations(marker), path) {
+        match confirm(&previous) {
+            Some(true) => match fs::copy(&previous, path) {
+                Ok(_) => println!(
+                    "Migrated {previous:?} to {path:?}, because the default stats location moved"
+                ),
+                Err(err) => eprintln!("E: Failed to migrate {previous:?} to {path:?}: {err}"),
+            },
+            Some(false) => {
+                println!("Not migrating {previous:?}. Starting {path:?} from scratch ...")
+            }
+            None => eprintln!(
+                "W: Not migrating stats of a previous run at {previous:?} without confirmation. To keep them, stop fishnet and copy them to {path:?}"
+            ),
+        }
+    }
+    if let Err(err) = remember_location(marker, path) {
+        eprintln!("W: Failed to remember stats location in {marker:?}: {err}");
+    }
+}
+
+/// Records batches into the stats file, the database and other sinks.
+///
+/// Owned by a single task (the queue) and mutated only through `&mut self`.
+/// It is `Send`, so it can move between threads with its owner, but not
+/// `Sync`, because [`StatsDb`] is not. Other threads do not share it:
+/// they observe it through [`StatsRecorder::watch_snapshot()`] and
+/// [`StatsRecorder::subscribe()`], whose receivers can be used
+/// concurrently, or open their own [`StatsDb`].
 pub struct StatsRecorder {
+    /// Lifetime totals. Read them with [`StatsRecorder::totals()`] instead,
+    /// this field may become private.
     pub stats: Stats,
-    pub nnue_nps: NpsRecorder,
+    session: Stats,
+    pub nnue_nps: NpsRecorders,
+    measured_nps: NpsRecorder,
+    nps_reference: Option<u32>,
+    // Node counts of syslog and InfluxDB are rounded to multiples of this.
+    node_precision: u64,
+    shadow_nps: Option<NpsRecorder>,
+    last_batch_instant: Option<Instant>,
+    idle_since: Option<Timestamp>,
     store: Option<(PathBuf, File)>,
+    tees: Vec<(PathBuf, ErrorThrottle)>,
+    // Write the stats file and tees only on flush.
+    defer_file: bool,
+    file_stale: bool,
+    syslog: Option<(Syslog, ErrorThrottle)>,
+    #[cfg(feature = "influx")]
+    influx: Option<(Influx, ErrorThrottle)>,
+    temperature: Option<CpuTemperature>,
+    // Fraction of batches whose engine output is stored, and the output
+    // for the next row.
+    capture_output: f64,
+    captured_output: Option<Vec<u8>>,
+    rng: Rng,
+    stats_key: Option<StatsKey>,
     cores: NonZeroUsize,
-    db_conn: Option<Connection>, // SQLite-Verbindung
+    db: Option<StatsDb>,
+    db_reopen: Option<DbReopen>,
+    // Loaded with open_read_only(), the sinks must not be written.
+    read_only: bool,
+    store_failing: bool,
+    db_failing: bool,
+    store_errors: ErrorThrottle,
+    db_errors: ErrorThrottle,
+    disk_space: Option<DiskSpaceGuard>,
+    batch_durations: BatchDurations,
+    batch_progress: Option<f64>,
+    tags: BTreeMap<String, String>,
+    started: Instant,
+    clock: Arc<dyn Clock>,
+    snapshot_tx: watch::Sender<StatsSnapshot>,
+    events_tx: broadcast::Sender<StatsEvent>,
+}
+
+/// Bytes of captured engine output stored per row, before compression.
+const MAX_SAMPLE_OUTPUT: usize = 64 * 1024;
+
+/// Default of --stats-idle-after.
+pub const DEFAULT_IDLE_AFTER: Duration = Duration::from_secs(30 * 60);
+
+/// Events buffered for each [`StatsRecorder::subscribe()`] receiver.
+const EVENT_CAPACITY: usize = 64;
+
+/// Event delivered to [`StatsRecorder::subscribe()`] receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatsEvent {
+    /// A batch was recorded. Contains the new cumulative totals.
+    BatchRecorded { stats: Stats, nnue_nps: Option<u32> },
+    /// The stats file and database were flushed, because the recorder was
+    /// dropped. No further events follow.
+    Flushed,
+    /// Writing to a sink failed.
+    PersistenceError { sink: StatsSink, error: String },
+    /// No batch was recorded for `since` (since the previous batch or
+    /// startup), see [`StatsRecorder::check_idle()`].
+    Idle { since: Duration },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatsSink {
+    File,
+    Database,
+    Syslog,
+    #[cfg(feature = "influx")]
+    Influx,
+}
+
+/// Health of the stats file and database, see
+/// [`StatsRecorder::persistence_status()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PersistenceStatus {
+    /// All sinks are writable.
+    Healthy,
+    /// A sink failed to open or its most recent write failed. Stats are
+    /// still counted in memory.
+    Degraded,
+    /// Persistence is disabled with --no-stats-file.
+    Disabled,
 }
 
-#[derive(Default, Clone, Serialize, Deserialize)]
+impl Default for StatsSnapshot {
+    fn default() -> StatsSnapshot {
+        StatsSnapshot {
+            timestamp: 0,
+            stats: Stats::default(),
+            session: Stats::default(),
+            cores: 1,
+            nnue_nps: None,
+            nnue_nps_uncertainty: 1.0,
+            nnue_nps_phase: NpsPhase::default(),
+            peak_nnue_nps: None,
+            last_nnue_nps: None,
+            measured_nps: None,
+            engine_nps: BTreeMap::new(),
+            batch_progress: None,
+            idle_since: None,
+            temp_c: None,
+            perf_index: None,
+            persistence: PersistenceStatus::Disabled,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+/// Point-in-time view of a [`StatsRecorder`].
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub timestamp: u64,
+    pub stats: Stats,
+    pub session: Stats,
+    pub cores: usize,
+    pub nnue_nps: Option<u32>,
+    pub nnue_nps_uncertainty: f64,
+    pub nnue_nps_phase: NpsPhase,
+    pub peak_nnue_nps: Option<u32>,
+    pub last_nnue_nps: Option<u32>,
+    /// Nps per core derived from the nodes of each batch and the wall time
+    /// since the previous batch, as a cross-check for `nnue_nps`. Includes
+    /// scheduling overhead and idle time, and also covers work that does
+    /// not report nps.
+    pub measured_nps: Option<u32>,
+    /// Estimate of each engine, including the default engine.
+    pub engine_nps: BTreeMap<String, Option<u32>>,
+    /// Fraction of the batch in flight, see [`StatsRecorder::note_progress`].
+    pub batch_progress: Option<f64>,
+    /// Unix timestamp since which no batch was recorded, if that exceeded
+    /// the idle threshold, see [`StatsRecorder::check_idle`].
+    pub idle_since: Option<u64>,
+    /// Most recent CPU temperature in degrees Celsius, with
+    /// --stats-temperature.
+    pub temp_c: Option<i32>,
+    /// Nps estimate relative to --nps-reference, see
+    /// [`StatsRecorder::perf_index`].
+    pub perf_index: Option<f64>,
+    pub persistence: PersistenceStatus,
+    pub tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Stats {
+    // Aliases accept field names that stats files of other forks were
+    // reported to use. No particular fork is known to write them. Only
+    // the original counters have aliases, because forks cannot have named
+    // fields that were added later.
+    #[serde(alias = "totalBatches", alias = "batches")]
     pub total_batches: u64,
+    #[serde(alias = "totalPositions", alias = "positions")]
     pub total_positions: u64,
+    #[serde(alias = "totalNodes", alias = "nodes")]
     pub total_nodes: u64,
+    /// Positions that the backend asked to skip, for example because it
+    /// already had an evaluation. Not included in `total_positions`.
+    #[serde(default)]
+    pub total_positions_cached: u64,
+    /// Distinct positions among `total_positions`, counted per batch.
+    /// Repetitions within a game are analysed more than once.
+    #[serde(default)]
+    pub total_unique_positions: u64,
+}
+
+// Contents of the stats file.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "schema",
+    derive(schemars::JsonSchema),
+    schemars(rename = "Stats")
+)]
+struct StatsFile {
+    #[serde(flatten)]
+    stats: Stats,
+    // Cores when the stats were last saved. Missing in files of older
+    // versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cores: Option<u64>,
+    // Version of fishnet that last saved the stats. Missing in files of
+    // older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(flatten)]
+    batch_durations: BatchDurations,
+}
+
+// Number of batches by wall time from receiving to completing the batch.
+// Fields are missing in files of older versions.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BatchDurations {
+    #[serde(default)]
+    pub batches_under_1s: u64,
+    #[serde(default)]
+    pub batches_under_5s: u64,
+    #[serde(default)]
+    pub batches_under_30s: u64,
+    #[serde(default)]
+    pub batches_from_30s: u64,
+}
+
+impl BatchDurations {
+    fn bucket_mut(&mut self, duration: Duration) -> &mut u64 {
+        match duration.as_secs() {
+            0 => &mut self.batches_under_1s,
+            1..=4 => &mut self.batches_under_5s,
+            5..=29 => &mut self.batches_under_30s,
+            _ => &mut self.batches_from_30s,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        *self.bucket_mut(duration) += 1;
+    }
+
+    fn total(&self) -> u64 {
+        self.batches_under_1s
+            + self.batches_under_5s
+            + self.batches_under_30s
+            + self.batches_from_30s
+    }
+}
+
+impl fmt::Display for BatchDurations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total();
+        if total == 0 {
+            return f.write_str("no batches");
+        }
+        let percent = |n: u64| 100.0 * n as f64 / total as f64;
+        write!(
+            f,
+            "<1s {:.0}%, 1-5s {:.0}%, 5-30s {:.0}%, 30s+ {:.0}%",
+            percent(self.batches_under_1s),
+            percent(self.batches_under_5s),
+            percent(self.batches_under_30s),
+            percent(self.batches_from_30s),
+        )
+    }
+}
+
+/// JSON schema of the stats file.
+#[cfg(feature = "schema")]
+pub fn stats_json_schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(StatsFile)).expect("serialize schema")
+}
+
+impl Stats {
+    /// No work recorded, yet.
+    pub fn is_empty(&self) -> bool {
+        self.total_batches == 0
+            && self.total_positions == 0
+            && self.total_nodes == 0
+            && self.total_positions_cached == 0
+            && self.total_unique_positions == 0
+    }
+
+    /// Fraction of submitted positions that were skipped instead of
+    /// analysed. `None` if no positions were submitted.
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let submitted = self.total_positions + self.total_positions_cached;
+        (submitted > 0).then(|| self.total_positions_cached as f64 / submitted as f64)
+    }
+
+    /// Fraction of analysed positions that repeated an earlier position of
+    /// the same batch. `None` if no unique positions were recorded, for
+    /// example with totals from older versions. Positions from before
+    /// unique positions were counted skew the ratio upwards, so prefer the
+    /// session over lifetime totals.
+    pub fn duplicate_ratio(&self) -> Option<f64> {
+        (self.total_unique_positions > 0 && self.total_positions > 0).then(|| {
+            self.total_positions
+                .saturating_sub(self.total_unique_positions) as f64
+                / self.total_positions as f64
+        })
+    }
+
+    /// Work done since the cumulative totals were `earlier`.
+    pub fn diff(&self, earlier: &Stats) -> Stats {
+        Stats {
+            total_batches: self.total_batches.saturating_sub(earlier.total_batches),
+            total_positions: self.total_positions.saturating_sub(earlier.total_positions),
+            total_nodes: self.total_nodes.saturating_sub(earlier.total_nodes),
+            total_positions_cached: self
+                .total_positions_cached
+                .saturating_sub(earlier.total_positions_cached),
+            total_unique_positions: self
+                .total_unique_positions
+                .saturating_sub(earlier.total_unique_positions),
+        }
+    }
+}
+
+/// Adds up work of independent time series, like that of merged sources.
+impl ops::AddAssign<&Stats> for Stats {
+    fn add_assign(&mut self, other: &Stats) {
+        self.total_batches = self.total_batches.saturating_add(other.total_batches);
+        self.total_positions = self.total_positions.saturating_add(other.total_positions);
+        self.total_nodes = self.total_nodes.saturating_add(other.total_nodes);
+        self.total_positions_cached = self
+            .total_positions_cached
+            .saturating_add(other.total_positions_cached);
+        self.total_unique_positions = self
+            .total_unique_positions
+            .saturating_add(other.total_unique_positions);
+    }
+}
+
+/// Reads of a stats file that is empty or not valid JSON, before giving up.
+const LOAD_ATTEMPTS: u32 = 5;
+
+const LOAD_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+impl Stats {
+    /// Reads the totals from the stats file at `path`, which may belong to
+    /// a running client. Encrypted files need the `key` they were saved
+    /// with.
+    ///
+    /// The running client rewrites the file in place, so that it can be
+    /// empty or partially written for a moment. It is never left like that:
+    /// Readers that retry shortly afterwards see complete contents. So an
+    /// empty or invalid file is read again a few times, before it is
+    /// considered corrupt. An empty file is then treated as no stats.
+    pub fn load_path(path: &Path, key: Option<&StatsKey>) -> io::Result<Stats> {
+        Stats::load_path_with(path, key, || thread::sleep(LOAD_RETRY_INTERVAL))
+    }
+
+    /// Like [`Stats::load_path()`], but calls `wait` before each retry.
+    fn load_path_with(
+        path: &Path,
+        key: Option<&StatsKey>,
+        mut wait: impl FnMut(),
+    ) -> io::Result<Stats> {
+        let mut attempt = 1;
+        loop {
+            let result = File::open(path).and_then(|mut file| StatsFile::load_from(&mut file, key));
+            match result {
+                Ok(Some(file)) => return Ok(file.stats),
+                Ok(None) if attempt >= LOAD_ATTEMPTS => return Ok(Stats::default()),
+                Err(err)
+                    if err.kind() != io::ErrorKind::InvalidData || attempt >= LOAD_ATTEMPTS =>
+                {
+                    return Err(err)
+                }
+                Ok(None) | Err(_) => (),
+            }
+            attempt += 1;
+            wait();
+        }
+    }
 }
 
+#[cfg(feature = "http")]
 impl Stats {
-    fn load_from(file: &mut File) -> io::Result<Option<Stats>> {
+    /// Fetches a published stats file, for reporting on other clients.
+    pub async fn load_from_url(client: &reqwest::Client, url: &str) -> Result<Stats, StatsError> {
+        let response = client.get(url).send().await?;
+        if response.status() != reqwest::StatusCode::OK {
+            return Err(StatsError::HttpStatus(response.status()));
+        }
+        let file: StatsFile = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(file.stats)
+    }
+}
+
+impl StatsFile {
+    /// Loads plaintext or, given the key, encrypted stats files.
+    fn load_from(file: &mut File, key: Option<&StatsKey>) -> io::Result<Option<StatsFile>> {
         file.rewind()?;
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
+        if let Some(sealed) = buf.strip_prefix(ENCRYPTED_HEADER) {
+            buf = match key {
+                Some(key) => open_sealed(key, sealed)?,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stats file is encrypted, but no key was given",
+                    ))
+                }
+            };
+        }
         Ok(if buf.is_empty() {
             None
         } else {
@@ -48,184 +600,7483 @@ impl Stats {
         })
     }
 
-    fn save_to(&self, file: &mut File) -> io::Result<()> {
-        file.set_len(0)?;
-        file.rewind()?;
-        file.write_all(
-            serde_json::to_string_pretty(&self)
-                .expect("serialize stats")
-                .as_bytes(),
-        )?;
-        Ok(())
+    /// Whether `file` starts with [`ENCRYPTED_HEADER`], even if it cannot
+    /// be decrypted.
+    fn is_encrypted(file: &mut File) -> bool {
+        let mut header = vec![0; ENCRYPTED_HEADER.len()];
+        file.rewind()
+            .and_then(|()| file.read_exact(&mut header))
+            .is_ok()
+            && header == ENCRYPTED_HEADER
+    }
+
+    fn to_bytes(&self, key: Option<&StatsKey>) -> io::Result<Vec<u8>> {
+        let json = to_json(self)?;
+        match key {
+            Some(key) => seal(key, &json),
+            None => Ok(json),
+        }
+    }
+
+    fn save_to(&self, file: &mut File, key: Option<&StatsKey>) -> io::Result<()> {
+        match key {
+            Some(key) => replace_contents(file, &self.to_bytes(Some(key))?),
+            None => replace_json(file, self),
+        }
+    }
+}
+
+/// Replaces the contents of `file` with `value` as pretty JSON.
+fn replace_json<T: Serialize>(file: &mut File, value: &T) -> io::Result<()> {
+    replace_contents(file, &to_json(value)?)
+}
+
+fn to_json<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    serde_json::to_vec_pretty(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// Serialize before truncating, so that the previous contents survive a
+// serialization failure.
+fn replace_contents(file: &mut File, buf: &[u8]) -> io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(buf)?;
+    Ok(())
+}
+
+/// Replaces the file at `path` with `buf` by renaming a temporary file in
+/// the same directory, so that readers never see a partial write.
+fn write_atomic(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut temp = NamedTempFile::new_in(dir)?;
+    temp.write_all(buf)?;
+    temp.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// Distinguishes encrypted stats files from plaintext JSON. Followed by
+/// the nonce and the ciphertext.
+const ENCRYPTED_HEADER: &[u8] = b"fishnet-stats-chacha20poly1305\n";
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+#[cfg(feature = "encryption")]
+fn seal(key: &StatsKey, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    use chacha20poly1305::{
+        aead::{Aead as _, AeadCore as _, KeyInit as _, OsRng},
+        ChaCha20Poly1305, Key,
+    };
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.0));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| io::Error::other("failed to encrypt stats"))?;
+    let mut sealed = ENCRYPTED_HEADER.to_vec();
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+#[cfg(feature = "encryption")]
+fn open_sealed(key: &StatsKey, sealed: &[u8]) -> io::Result<Vec<u8>> {
+    use chacha20poly1305::{
+        aead::{Aead as _, KeyInit as _},
+        ChaCha20Poly1305, Key, Nonce,
+    };
+
+    if sealed.len() < NONCE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "encrypted stats file is truncated",
+        ));
     }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(Key::from_slice(&key.0))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "failed to decrypt stats file (wrong key?)",
+            )
+        })
+}
+
+#[cfg(not(feature = "encryption"))]
+fn seal(_key: &StatsKey, _plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "stats file encryption requires the encryption feature",
+    ))
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open_sealed(_key: &StatsKey, _sealed: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "stats file is encrypted, which requires the encryption feature",
+    ))
+}
+
+/// Warns if `cores` exceeds the `detected` logical cores. That is likely a
+/// misconfiguration, and makes per-core figures too low.
+fn check_cores(cores: NonZeroUsize, detected: Option<NonZeroUsize>) -> Option<String> {
+    let detected = detected?;
+    (cores > detected).then(|| {
+        format!("Recording stats for {cores} cores, but only {detected} logical cores were detected. Per-core figures will be too low.")
+    })
 }
 
 impl StatsRecorder {
-    pub fn new(opt: StatsOpt, cores: NonZeroUsize) -> StatsRecorder {
-        let nnue_nps = NpsRecorder::new();
+    /// Per-core figures are computed for `cores`, or all logical cores
+    /// if `None`.
+    pub fn new(opt: StatsOpt, cores: Option<NonZeroUsize>) -> StatsRecorder {
+        let detected = thread::available_parallelism().ok();
+        let cores = match cores {
+            Some(cores) => {
+                if let Some(warning) = check_cores(cores, detected) {
+                    eprintln!("W: {warning}");
+                }
+                cores
+            }
+            None => detected.unwrap_or(NonZeroUsize::MIN),
+        };
+
+        let mut nnue_nps = if opt.no_optimistic_nps {
+            NpsRecorder::new_unknown()
+        } else {
+            NpsRecorder::new()
+        };
+        if let Some(batches) = opt.nps_warmup_batches {
+            nnue_nps.set_warmup_batches(batches);
+        }
+        if let Some(max_uncertainty) = opt.nps_convergence {
+            nnue_nps.set_convergence(Convergence {
+                max_uncertainty,
+                ..Convergence::default()
+            });
+        }
+
+        let tags = tag_map(&opt.stats_tag);
 
         if opt.no_stats_file {
-            return StatsRecorder {
-                stats: Stats::default(),
-                store: None,
+            return StatsRecorder::with_sinks(
+                Stats::default(),
                 nnue_nps,
                 cores,
-                db_conn: None,
-            };
+                tags,
+                false,
+                None,
+                None,
+            );
         }
 
-        let path = opt.stats_file.or_else(default_stats_file);
+        let stats_key = match opt.stats_key() {
+            Ok(stats_key) => stats_key,
+            Err(err) => {
+                // Do not fall back to a plaintext stats file.
+                eprintln!("E: Invalid $FISHNET_STATS_KEY: {err}. Not recording to stats file.");
+                return StatsRecorder::with_sinks(
+                    Stats::default(),
+                    nnue_nps,
+                    cores,
+                    tags,
+                    true,
+                    None,
+                    StatsDb::open_opt(&opt),
+                );
+            }
+        };
 
-        let (stats, store) = match &path {
-            Some(path) => match OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open(&path)
-            {
-                Ok(mut file) => (
-                    match Stats::load_from(&mut file) {
-                        Ok(Some(stats)) => {
-                            println!("Resuming from {path:?} ...");
-                            stats
-                        }
-                        Ok(None) => {
-                            println!("Recording to new stats file {path:?} ...");
-                            Stats::default()
-                        }
-                        Err(err) => {
-                            eprintln!("E: Failed to resume from {path:?}: {err}. Resetting ...");
-                            Stats::default()
+        let path = match &opt.stats_file {
+            Some(path) => resolve_path(path, opt.stats_mkdir)
+                .map_err(|err| format!("Failed to resolve stats file {path:?}: {err}")),
+            None if opt.stats_xdg => xdg_stats_file()
+                .ok_or_else(|| "Could not resolve $XDG_STATE_HOME/fishnet".to_owned()),
+            None => {
+                default_stats_file().ok_or_else(|| "Could not resolve ~/.fishnet-stats".to_owned())
+            }
+        };
+        // Whether the stats file started from scratch, because it was empty
+        // or could not be read.
+        let mut fresh = true;
+        let (mut loaded, store) = match path {
+            Ok(path) => match open_stats_file(
+                &path,
+                !opt.no_stats_fallback,
+                opt.stats_file_lock_timeout.unwrap_or_default(),
+            ) {
+                Ok((path, mut file)) => match StatsFile::load_from(&mut file, stats_key.as_ref()) {
+                    Ok(Some(loaded)) => {
+                        eprintln!("Resuming from {path:?} ...");
+                        fresh = false;
+                        (loaded, Some((path, file)))
+                    }
+                    Ok(None) => {
+                        eprintln!("Recording to new stats file {path:?} ...");
+                        (StatsFile::default(), Some((path, file)))
+                    }
+                    Err(err) if StatsFile::is_encrypted(&mut file) => {
+                        // Without the right key, resetting would destroy
+                        // the encrypted totals.
+                        eprintln!(
+                            "E: Failed to resume from {path:?}: {err}. Not recording to stats file ..."
+                        );
+                        (StatsFile::default(), None)
+                    }
+                    Err(err) => {
+                        eprintln!("E: Failed to resume from {path:?}: {err}. Resetting ...");
+                        (StatsFile::default(), Some((path, file)))
+                    }
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // Still show the totals, for example with dump-stats
+                    // while another instance is running.
+                    eprintln!("E: {path:?} is {err}. Not recording to stats file ...");
+                    let loaded = File::open(&path)
+                        .and_then(|mut file| StatsFile::load_from(&mut file, stats_key.as_ref()));
+                    match loaded {
+                        Ok(Some(loaded)) => {
+                            fresh = false;
+                            (loaded, None)
                         }
-                    },
-                    Some((path.clone(), file)),
-                ),
+                        _ => (StatsFile::default(), None),
+                    }
+                }
                 Err(err) => {
-                    eprintln!("E: Failed to open {path:?}: {err}");
-                    (Stats::default(), None)
+                    eprintln!(
+                        "E: Failed to open {path:?}: {err}{}",
+                        missing_dir_hint(&path)
+                    );
+                    (StatsFile::default(), None)
                 }
             },
-            None => {
-                eprintln!("E: Could not resolve ~/.fishnet-stats");
-                (Stats::default(), None)
-            }
-        };
-
-        // SQLite-Datenbank initialisieren
-        let db_conn = match initialize_database("stats.db") {
-            Ok(conn) => Some(conn),
             Err(err) => {
-                eprintln!("E: Failed to initialize SQLite database: {err}");
-                None
+                eprintln!("E: {err}");
+                (StatsFile::default(), None)
             }
         };
 
-        StatsRecorder {
-            stats,
-            store,
-            nnue_nps,
-            cores,
-            db_conn,
+        let db = StatsDb::open_opt(&opt);
+        // Opening may have failed transiently, for example if the file was
+        // briefly locked. Not if the path cannot even be resolved.
+        let db_reopen = db
+            .is_none()
+            .then(|| StatsDb::path_and_config(&opt))
+            .and_then(|(path, config)| Some((resolve_path(&path, opt.stats_mkdir).ok()?, config)));
+
+        let latest = db.as_ref().and_then(|db| db.latest().ok().flatten());
+
+        // An empty stats file may as well be a truncation accident. Do not
+        // lose the lifetime totals that are still in the database.
+        if let Some((db, row)) = db
+            .as_ref()
+            .zip(latest.as_ref())
+            .filter(|(_, row)| prefer_db(&loaded.stats, fresh, &row.stats))
+        {
+            eprintln!(
+                "Resuming from database ({} batches, {} positions), because the stats file is new or was reset ...",
+                row.stats.total_batches, row.stats.total_positions
+            );
+            loaded.stats = db.unscaled(&row.stats);
         }
-    }
 
-    pub fn record_batch(&mut self, positions: u64, nodes: u64, nnue_nps: Option<u32>) {
-        self.stats.total_batches += 1;
-        self.stats.total_positions += positions;
-        self.stats.total_nodes += nodes;
+        if let Some(mismatch) = db
+            .as_ref()
+            .zip(latest.as_ref())
+            .and_then(|(db, row)| stats_mismatch(&db.scaled(&loaded.stats), &row.stats))
+        {
+            eprintln!("W: {mismatch}");
+        }
 
-        if let Some(nnue_nps) = nnue_nps {
-            self.nnue_nps.record(nnue_nps);
+        let recorded_cores = loaded
+            .cores
+            .or_else(|| latest.as_ref().and_then(|row| row.cores));
+        if let Some(recorded) = changed_cores(recorded_cores, cores) {
+            eprintln!(
+                "W: Stats were recorded with {recorded} cores, now using {cores}. Per-core figures before and after are not comparable."
+            );
         }
+        let stats = loaded.stats;
+        let batch_durations = loaded.batch_durations;
+
+        let min_free_mb = opt.stats_min_free_mb.unwrap_or(DEFAULT_MIN_FREE_MB);
+        let disk_space = db
+            .as_ref()
+            .and_then(StatsDb::dir)
+            .filter(|_| min_free_mb > 0)
+            .map(|dir| DiskSpaceGuard::new(dir, min_free_mb * MB));
 
-        // Speichern in .stats-file
-        if let Some((ref path, ref mut stats_file)) = &self.store {
-            if let Err(err) = self.stats.save_to(stats_file) {
-                eprintln!("E: Failed to write stats to {path:?}: {err}");
+        let mut recorder = StatsRecorder::with_sinks(stats, nnue_nps, cores, tags, true, store, db);
+        recorder.disk_space = disk_space;
+        if let Some((path, config)) = db_reopen {
+            recorder.retry_db(path, config);
+        }
+        recorder.batch_durations = batch_durations;
+        recorder.stats_key = stats_key;
+        recorder.nps_reference = opt.nps_reference;
+        recorder.node_precision = opt.stats_precision_nodes.unwrap_or(1);
+        if let Some(smoothing) = opt.nps_shadow {
+            recorder.set_shadow_nps(smoothing);
+        }
+        recorder.tees = opt
+            .stats_tee
+            .iter()
+            .filter_map(|path| match resolve_path(path, opt.stats_mkdir) {
+                Ok(path) => Some((path, ErrorThrottle::default())),
+                Err(err) => {
+                    eprintln!("E: Failed to resolve stats file {path:?}: {err}");
+                    None
+                }
+            })
+            .collect();
+        if opt.stats_file_on_shutdown {
+            if recorder.db.is_some() {
+                recorder.defer_file = true;
+            } else {
+                eprintln!("W: No stats database, writing the stats file after every batch");
+            }
+        }
+        recorder.capture_output = opt.stats_capture_output.unwrap_or(0.0);
+        if opt.stats_temperature {
+            recorder.temperature = CpuTemperature::spawn();
+            if recorder.temperature.is_none() {
+                eprintln!("W: No supported CPU temperature sensor found");
+            }
+        }
+        if opt.stats_syslog {
+            match Syslog::connect() {
+                Ok(syslog) => recorder.syslog = Some((syslog, ErrorThrottle::default())),
+                Err(err) => eprintln!("E: Failed to connect to syslog: {err}"),
             }
         }
 
-        // Speichern in SQLite-Datenbank
-        if let Some(conn) = &self.db_conn {
-            if let Err(err) = self.save_to_database(conn, nnue_nps) {
-                eprintln!("E: Failed to save stats to SQLite database: {err}");
+        if let Some((available, total)) = recorder.db_disk_space() {
+            if available < total / 10 {
+                eprintln!(
+                    "W: Only {} of {} MB free for the stats database",
+                    available / MB,
+                    total / MB
+                );
             }
         }
+
+        recorder
     }
 
-    // Neue Methode: Stats in SQLite speichern
-    pub fn save_to_database(&self, conn: &Connection, nnue_nps: Option<u32>) -> Result<()> {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
+    /// Loads the totals of the stats file and database configured in `opt`
+    /// for reporting, without creating, locking, migrating or writing
+    /// anything, and without the sinks and sensors of a recording instance.
+    /// Missing files are treated as empty.
+    pub fn open_read_only(opt: &StatsOpt, cores: NonZeroUsize) -> StatsRecorder {
+        let stats_key = opt.stats_key().unwrap_or_else(|err| {
+            eprintln!("E: Invalid $FISHNET_STATS_KEY: {err}");
+            None
+        });
 
-        conn.execute(
-            "INSERT INTO stats (timestamp, total_batches, total_positions, total_nodes, nnue_nps)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![
-                now as i64,
-                self.stats.total_batches as i64,
-                self.stats.total_positions as i64,
-                self.stats.total_nodes as i64,
-                nnue_nps.unwrap_or_default() as i64, // nnue_nps, falls vorhanden
-            ],
-        )?;
-        Ok(())
-    }
-}
+        let path = match &opt.stats_file {
+            _ if opt.no_stats_file => None,
+            Some(path) => expand_path(path)
+                .map_err(|err| eprintln!("E: Failed to resolve stats file {path:?}: {err}"))
+                .ok(),
+            // Not migrating from the legacy location, unlike a recording
+            // instance.
+            None if opt.stats_xdg => xdg_state_dir()
+                .map(|dir| dir.join("stats"))
+                .filter(|path| path.exists())
+                .or_else(default_stats_file),
+            None => default_stats_file(),
+        };
+        let (mut stats, fresh, store) = match path.map(|path| (File::open(&path), path)) {
+            Some((Ok(mut file), path)) => match StatsFile::load_from(&mut file, stats_key.as_ref())
+            {
+                Ok(Some(loaded)) => (loaded.stats, false, Some((path, file))),
+                Ok(None) => (Stats::default(), true, Some((path, file))),
+                Err(err) => {
+                    eprintln!("E: Failed to read {path:?}: {err}");
+                    (Stats::default(), true, None)
+                }
+            },
+            Some((Err(err), path)) if err.kind() != io::ErrorKind::NotFound => {
+                eprintln!("E: Failed to open {path:?}: {err}");
+                (Stats::default(), true, None)
+            }
+            _ => (Stats::default(), true, None),
+        };
 
-// Funktion, um die SQLite-Datenbank zu initialisieren
-fn initialize_database(path: &str) -> Result<Connection> {
-    let conn = Connection::open(path)?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS stats (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            timestamp INTEGER NOT NULL,
-            total_batches INTEGER NOT NULL,
-            total_positions INTEGER NOT NULL,
-            total_nodes INTEGER NOT NULL,
-            nnue_nps INTEGER NOT NULL
-        )",
-        [],
-    )?;
-    Ok(conn)
-}
+        let (db_path, config) = StatsDb::path_and_config(opt);
+        let db = expand_path(&db_path)
+            .map_err(|err| eprintln!("E: Failed to resolve stats database {db_path:?}: {err}"))
+            .ok()
+            .filter(|path| path.exists())
+            .and_then(|path| match StatsDb::open_read_only(&path, config) {
+                Ok(db) => Some(db),
+                Err(err) => {
+                    eprintln!("E: Failed to open SQLite database {path:?}: {err}");
+                    None
+                }
+            });
+        if let Some(db) = &db {
+            match db.latest() {
+                Ok(Some(row)) if prefer_db(&stats, fresh, &row.stats) => {
+                    stats = db.unscaled(&row.stats);
+                }
+                Ok(_) => (),
+                Err(err) => eprintln!("E: Failed to query stats database: {err}"),
+            }
+        }
 
-#[derive(Clone)]
-pub struct NpsRecorder {
-    pub nps: u32,
-    pub uncertainty: f64,
-}
+        let mut recorder = StatsRecorder::with_sinks(
+            stats,
+            NpsRecorder::new_unknown(),
+            cores,
+            tag_map(&opt.stats_tag),
+            false,
+            store,
+            db,
+        );
+        recorder.read_only = true;
+        recorder
+    }
 
-impl NpsRecorder {
-    fn new() -> NpsRecorder {
-        NpsRecorder {
-            nps: 400_000, // start with an optimistic estimate
-            uncertainty: 1.0,
+    /// Pushes batch totals to InfluxDB at `url`, over HTTP with `client`.
+    #[cfg(feature = "influx")]
+    pub fn connect_influx(&mut self, url: &url::Url, client: &reqwest::Client) {
+        match Influx::connect(url, client) {
+            Ok(influx) => self.influx = Some((influx, ErrorThrottle::default())),
+            Err(err) => eprintln!("E: Failed to connect to InfluxDB at {url}: {err}"),
         }
     }
 
-    fn record(&mut self, nps: u32) {
-        let alpha = 0.9;
-        self.uncertainty *= alpha;
-        self.nps = (f64::from(self.nps) * alpha + f64::from(nps) * (1.0 - alpha)) as u32;
+    /// Sinks that are `None` despite `persist` failed to open.
+    fn with_sinks(
+        stats: Stats,
+        nnue_nps: NpsRecorder,
+        cores: NonZeroUsize,
+        tags: BTreeMap<String, String>,
+        persist: bool,
+        store: Option<(PathBuf, File)>,
+        db: Option<StatsDb>,
+    ) -> StatsRecorder {
+        let mut recorder = StatsRecorder {
+            stats,
+            session: Stats::default(),
+            nnue_nps: NpsRecorders::new(nnue_nps),
+            measured_nps: NpsRecorder::new_unknown(),
+            nps_reference: None,
+            node_precision: 1,
+            shadow_nps: None,
+            last_batch_instant: None,
+            idle_since: None,
+            store_failing: persist && store.is_none(),
+            db_failing: persist && db.is_none(),
+            store_errors: ErrorThrottle::default(),
+            db_errors: ErrorThrottle::default(),
+            disk_space: None,
+            batch_durations: BatchDurations::default(),
+            batch_progress: None,
+            store,
+            tees: Vec::new(),
+            defer_file: false,
+            file_stale: false,
+            syslog: None,
+            #[cfg(feature = "influx")]
+            influx: None,
+            temperature: None,
+            capture_output: 0.0,
+            captured_output: None,
+            rng: Rng::new(),
+            stats_key: None,
+            cores,
+            db: db.map(|mut db| {
+                db.set_cores(cores);
+                db
+            }),
+            db_reopen: None,
+            read_only: false,
+            tags,
+            started: Instant::now(),
+            clock: Arc::new(SystemClock),
+            snapshot_tx: watch::Sender::new(StatsSnapshot::default()),
+            events_tx: broadcast::Sender::new(EVENT_CAPACITY),
+        };
+        recorder.snapshot_tx.send_replace(recorder.snapshot());
+        recorder
     }
-}
 
-impl fmt::Display for NpsRecorder {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} knps/core", self.nps / 1000)?;
-        if self.uncertainty > 0.1 {
-            write!(f, " ?")?;
+    /// Decides whether to capture the engine output of the next batch, see
+    /// --stats-capture-output.
+    pub fn sample_output(&mut self) -> bool {
+        self.capture_output > 0.0 && self.db.is_some() && self.rng.f64() < self.capture_output
+    }
+
+    /// Stores the captured engine output of a batch, truncated and
+    /// compressed, with the next row. Only for debugging.
+    pub fn record_output(&mut self, output: &str) {
+        let mut end = output.len().min(MAX_SAMPLE_OUTPUT);
+        while !output.is_char_boundary(end) {
+            end -= 1;
         }
-        if self.uncertainty > 0.4 {
-            write!(f, "?")?;
+        match zstd::encode_all(output[..end].as_bytes(), 0) {
+            Ok(compressed) => self.captured_output = Some(compressed),
+            Err(err) => eprintln!("E: Failed to compress engine output: {err}"),
         }
-        if self.uncertainty > 0.7 {
-            write!(f, "?")?;
+    }
+
+    /// Counts positions of a batch that were skipped instead of analysed.
+    /// Persisted with the next batch.
+    pub fn record_cached(&mut self, positions: u64) {
+        self.stats.total_positions_cached += positions;
+        self.session.total_positions_cached += positions;
+    }
+
+    /// Counts distinct positions of a batch. Persisted with the next batch.
+    pub fn record_unique(&mut self, positions: u64) {
+        self.stats.total_unique_positions += positions;
+        self.session.total_unique_positions += positions;
+    }
+
+    pub fn record_batch(
+        &mut self,
+        positions: u64,
+        nodes: u64,
+        nnue_nps: Option<u32>,
+        duration: Duration,
+        cold_start: bool,
+    ) {
+        // Batches right after startup include engine warmup, so they would
+        // skew the steady state estimates.
+        if !cold_start {
+            self.batch_durations.record(duration);
         }
-        Ok(())
+
+        self.stats.total_batches += 1;
+        self.stats.total_positions += positions;
+        self.stats.total_nodes += nodes;
+
+        self.session.total_batches += 1;
+        self.session.total_positions += positions;
+        self.session.total_nodes += nodes;
+
+        self.batch_progress = None;
+        self.reopen_db();
+
+        if let Some(nnue_nps) = nnue_nps.filter(|_| !cold_start) {
+            self.nnue_nps.record(DEFAULT_ENGINE, nnue_nps, nodes);
+            if let Some(shadow) = &mut self.shadow_nps {
+                shadow.record(nnue_nps, nodes);
+            }
+        }
+
+        let now = self.clock.now();
+        if let Some(last) = self.last_batch_instant.filter(|_| !cold_start) {
+            let elapsed = now.duration_since(last).as_secs_f64();
+            if elapsed > 0.0 {
+                let measured = per_core(nodes as f64 / elapsed, self.cores);
+                self.measured_nps
+                    .record(measured.min(f64::from(u32::MAX)) as u32, nodes);
+            }
+        }
+        self.last_batch_instant = Some(now);
+        self.idle_since = None;
+
+        self.persist(nnue_nps, Some(cold_start));
+    }
+
+    /// Time since the most recent batch, or since startup.
+    pub fn idle_for(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.last_batch_instant.unwrap_or(self.started))
+    }
+
+    pub fn is_idle(&self, threshold: Duration) -> bool {
+        self.idle_for() >= threshold
+    }
+
+    /// Returns how long the recorder has been idle, once per idle period,
+    /// when it first exceeds `threshold`. Also notes it in snapshots and
+    /// emits [`StatsEvent::Idle`], so that supervisors can tell a node
+    /// without work from a slow one.
+    pub fn check_idle(&mut self, threshold: Duration) -> Option<Duration> {
+        if self.idle_since.is_some() || !self.is_idle(threshold) {
+            return None;
+        }
+        let idle = self.idle_for();
+        self.idle_since = Some(Timestamp::from(self.clock.system_now()) - idle);
+        self.snapshot_tx.send_replace(self.snapshot());
+        self.emit(StatsEvent::Idle { since: idle });
+        Some(idle)
+    }
+
+    /// Writes the stats file and its copies, if any.
+    fn write_files(&mut self) {
+        self.file_stale = false;
+        let file = StatsFile {
+            stats: self.stats.clone(),
+            cores: Some(self.cores_u64()),
+            version: Some(VERSION.to_owned()),
+            batch_durations: self.batch_durations.clone(),
+        };
+        if let Some((path, stats_file)) = &mut self.store {
+            let result = file.save_to(stats_file, self.stats_key.as_ref());
+            match &result {
+                Err(err) => log_errors(self.store_errors.error(
+                    self.clock.now(),
+                    format!("Failed to write stats to {path:?}: {err}"),
+                )),
+                Ok(()) => log_errors(self.store_errors.recovered()),
+            }
+            if let Err(err) = &result {
+                self.emit(StatsEvent::PersistenceError {
+                    sink: StatsSink::File,
+                    error: err.to_string(),
+                });
+            }
+            self.store_failing = result.is_err();
+        }
+
+        // A failing copy does not keep the others from being written.
+        if !self.tees.is_empty() {
+            let buf = file.to_bytes(self.stats_key.as_ref());
+            let now = self.clock.now();
+            let mut errors = Vec::new();
+            for (path, throttle) in &mut self.tees {
+                let result = match buf {
+                    Ok(ref buf) => write_atomic(path, buf),
+                    Err(ref err) => Err(io::Error::new(err.kind(), err.to_string())),
+                };
+                match result {
+                    Ok(()) => log_errors(throttle.recovered()),
+                    Err(err) => {
+                        log_errors(
+                            throttle
+                                .error(now, format!("Failed to write stats to {path:?}: {err}")),
+                        );
+                        errors.push(err.to_string());
+                    }
+                }
+            }
+            for error in errors {
+                self.emit(StatsEvent::PersistenceError {
+                    sink: StatsSink::File,
+                    error,
+                });
+            }
+        }
+    }
+
+    /// Writes the stats file if it was deferred with
+    /// --stats-file-on-shutdown. Also done on drop.
+    pub fn flush(&mut self) {
+        if self.file_stale {
+            self.write_files();
+        }
+    }
+
+    /// Writes the current totals to all sinks. `cold_start` is `None` if
+    /// not recording a batch.
+    fn persist(&mut self, nnue_nps: Option<u32>, cold_start: Option<bool>) {
+        // While disk space is low, keep only the tiny stats file.
+        let paused = self
+            .disk_space
+            .as_mut()
+            .is_some_and(|guard| guard.is_low(self.clock.now(), available_space));
+
+        // Batches are safe in the database. If it was closed or paused,
+        // fall back to writing the file.
+        if self.defer_file && cold_start.is_some() && self.db.is_some() && !paused {
+            self.file_stale = true;
+        } else {
+            self.write_files();
+        }
+
+        // Exact in the stats file and the database.
+        let rounded = round_nodes(&self.stats, self.node_precision);
+
+        // Only after batches, not for every flush.
+        if let Some((logger, throttle)) = self.syslog.as_mut().filter(|_| cold_start.is_some()) {
+            let result = logger.send(&syslog::batch_message(&rounded, nnue_nps));
+            match &result {
+                Err(err) => log_errors(throttle.error(
+                    self.clock.now(),
+                    format!("Failed to send stats to syslog: {err}"),
+                )),
+                Ok(()) => log_errors(throttle.recovered()),
+            }
+            if let Err(err) = result {
+                self.emit(StatsEvent::PersistenceError {
+                    sink: StatsSink::Syslog,
+                    error: err.to_string(),
+                });
+            }
+        }
+
+        #[cfg(feature = "influx")]
+        if let Some((sink, throttle)) = self.influx.as_mut().filter(|_| cold_start.is_some()) {
+            let result = sink.send(&influx::batch_line(
+                &self.tags,
+                &rounded,
+                nnue_nps,
+                self.clock.system_now(),
+            ));
+            match &result {
+                Err(err) => log_errors(throttle.error(
+                    self.clock.now(),
+                    format!("Failed to send stats to InfluxDB: {err}"),
+                )),
+                Ok(()) => log_errors(throttle.recovered()),
+            }
+            if let Err(err) = result {
+                self.emit(StatsEvent::PersistenceError {
+                    sink: StatsSink::Influx,
+                    error: err.to_string(),
+                });
+            }
+        }
+
+        if let Some(db) = self.db.as_mut().filter(|_| !paused) {
+            // Sampled in the background, so this does not block.
+            let temp_c = self
+                .temperature
+                .as_ref()
+                .filter(|_| cold_start.is_some())
+                .and_then(CpuTemperature::celsius);
+            let sample_output = self.captured_output.take();
+            let result = db.insert_row(
+                Timestamp::from(self.clock.system_now()),
+                &self.stats,
+                nnue_nps,
+                cold_start,
+                temp_c,
+                sample_output.as_deref(),
+            );
+            match &result {
+                Err(err) if is_unrecoverable(err) => {
+                    eprintln!(
+                        "E: SQLite database became unusable, no longer saving stats to it: {err}"
+                    );
+                    self.db = None;
+                }
+                Err(err) => log_errors(self.db_errors.error(
+                    self.clock.now(),
+                    format!("Failed to save stats to SQLite database: {err}"),
+                )),
+                Ok(()) => log_errors(self.db_errors.recovered()),
+            }
+            if let Err(err) = &result {
+                self.emit(StatsEvent::PersistenceError {
+                    sink: StatsSink::Database,
+                    error: err.to_string(),
+                });
+            }
+            // Stays set if the database was closed.
+            self.db_failing = result.is_err();
+        }
+
+        self.snapshot_tx.send_replace(self.snapshot());
+        self.emit(StatsEvent::BatchRecorded {
+            stats: self.stats.clone(),
+            nnue_nps,
+        });
+    }
+
+    fn emit(&self, event: StatsEvent) {
+        // Fails only if there are no subscribers.
+        let _ = self.events_tx.send(event);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            timestamp: u64::try_from(Timestamp::from(self.clock.system_now()).to_unix_secs())
+                .unwrap_or(0),
+            stats: self.stats.clone(),
+            session: self.session.clone(),
+            cores: self.cores.get(),
+            nnue_nps: self.nnue_nps.default_engine().estimate(),
+            nnue_nps_uncertainty: self.nnue_nps.default_engine().uncertainty,
+            nnue_nps_phase: self.nnue_nps.default_engine().phase(),
+            peak_nnue_nps: self.nnue_nps.default_engine().peak_nps(),
+            last_nnue_nps: self.nnue_nps.default_engine().last(),
+            measured_nps: self.measured_nps.estimate(),
+            engine_nps: self
+                .nnue_nps
+                .iter()
+                .map(|(engine, nps)| (engine.to_owned(), nps.estimate()))
+                .collect(),
+            batch_progress: self.batch_progress,
+            idle_since: self
+                .idle_since
+                .and_then(|since| u64::try_from(since.to_unix_secs()).ok()),
+            temp_c: self.temperature.as_ref().and_then(CpuTemperature::celsius),
+            perf_index: self.perf_index(),
+            persistence: self.persistence_status(),
+            tags: self.tags.clone(),
+        }
+    }
+
+    /// Starts a shadow recorder from the current state of the default
+    /// engine, fed the same samples, but using `smoothing`. Only for
+    /// comparison, batch sizing never uses it.
+    pub fn set_shadow_nps(&mut self, smoothing: Smoothing) {
+        let mut shadow = self.nnue_nps.default_engine().clone_state();
+        shadow.set_smoothing(smoothing);
+        self.shadow_nps = Some(shadow);
+    }
+
+    pub fn shadow_nps(&self) -> Option<&NpsRecorder> {
+        self.shadow_nps.as_ref()
+    }
+
+    /// Estimate of the default engine relative to --nps-reference, if
+    /// both are known.
+    pub fn perf_index(&self) -> Option<f64> {
+        self.nnue_nps
+            .default_engine()
+            .perf_index(self.nps_reference?)
+    }
+
+    /// Notes that `done` of `total` positions of the batch in flight are
+    /// analysed, for live views. Not persisted, and cleared when the batch
+    /// is recorded. Ignored if `total` is 0.
+    pub fn note_progress(&mut self, done: u64, total: u64) {
+        if total == 0 {
+            return;
+        }
+        self.batch_progress = Some((done as f64 / total as f64).min(1.0));
+        self.snapshot_tx.send_replace(self.snapshot());
+    }
+
+    /// Keeps trying to open the database at `path` with exponential
+    /// backoff, when batches are recorded, until it succeeds.
+    fn retry_db(&mut self, path: PathBuf, config: StatsDbConfig) {
+        self.db_reopen = Some(DbReopen {
+            path,
+            config,
+            backoff: DB_REOPEN_BACKOFF_MIN,
+            next_attempt: self.clock.now() + DB_REOPEN_BACKOFF_MIN,
+        });
+    }
+
+    fn reopen_db(&mut self) {
+        let now = self.clock.now();
+        let Some(reopen) = self.db_reopen.as_mut().filter(|r| now >= r.next_attempt) else {
+            return;
+        };
+        match StatsDb::open(&reopen.path, reopen.config.clone()) {
+            Ok(mut db) => {
+                println!("Reopened SQLite database {:?}", reopen.path);
+                db.set_cores(self.cores);
+                self.db = Some(db);
+                self.db_reopen = None;
+            }
+            Err(err) => {
+                reopen.backoff = min(reopen.backoff * 2, DB_REOPEN_BACKOFF_MAX);
+                reopen.next_attempt = now + reopen.backoff;
+                log_errors(self.db_errors.error(
+                    now,
+                    format!(
+                        "Failed to reopen SQLite database {:?}: {err}. Retrying in {}s",
+                        reopen.path,
+                        reopen.backoff.as_secs()
+                    ),
+                ));
+            }
+        }
+    }
+
+    fn cores_u64(&self) -> u64 {
+        cores_u64(self.cores)
+    }
+
+    /// Uses `clock` instead of the system clock from now on.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.started = clock.now();
+        self.last_batch_instant = None;
+        self.nnue_nps.set_clock(&clock);
+        self.measured_nps.clock = Arc::clone(&clock);
+        if let Some(shadow) = &mut self.shadow_nps {
+            shadow.clock = Arc::clone(&clock);
+        }
+        self.clock = clock;
+    }
+
+    /// Writes the current state as `key=value` lines, for scripts. Keys are
+    /// stable across releases, new keys are only ever appended:
+    ///
+    /// * `total_batches`, `total_positions`, `total_nodes`,
+    ///   `total_positions_cached`, `total_unique_positions`: Lifetime totals.
+    /// * `nnue_nps`: Estimated nps per core, empty if unknown.
+    /// * `nnue_nps_uncertainty`: From 1 (no samples) towards 0.
+    /// * `cores`
+    /// * `uptime_secs`: Seconds since the recorder was created.
+    /// * `stats_file`, `database`: Whether the sink is open, or for
+    ///   [`StatsRecorder::open_read_only()`], was found and read.
+    pub fn dump(&self, w: &mut impl Write) -> io::Result<()> {
+        let nps = self.nnue_nps.default_engine();
+        writeln!(w, "total_batches={}", self.stats.total_batches)?;
+        writeln!(w, "total_positions={}", self.stats.total_positions)?;
+        writeln!(w, "total_nodes={}", self.stats.total_nodes)?;
+        writeln!(
+            w,
+            "total_positions_cached={}",
+            self.stats.total_positions_cached
+        )?;
+        writeln!(
+            w,
+            "total_unique_positions={}",
+            self.stats.total_unique_positions
+        )?;
+        writeln!(
+            w,
+            "nnue_nps={}",
+            nps.estimate().map_or(String::new(), |nps| nps.to_string())
+        )?;
+        writeln!(w, "nnue_nps_uncertainty={:.3}", nps.uncertainty)?;
+        writeln!(w, "cores={}", self.cores)?;
+        writeln!(
+            w,
+            "uptime_secs={}",
+            self.clock.now().duration_since(self.started).as_secs()
+        )?;
+        writeln!(w, "stats_file={}", self.store.is_some())?;
+        writeln!(w, "database={}", self.db.is_some())
+    }
+
+    /// Receives a new snapshot after every recorded batch.
+    pub fn watch_snapshot(&self) -> watch::Receiver<StatsSnapshot> {
+        self.snapshot_tx.subscribe()
+    }
+
+    /// Receives all [`StatsEvent`]s from now on, in order. Any number of
+    /// receivers can subscribe. Recording never waits for receivers:
+    /// each buffers up to 64 events, and a receiver that falls further
+    /// behind skips the oldest events, getting
+    /// [`broadcast::error::RecvError::Lagged`] with the number of skipped
+    /// events.
+    pub fn subscribe(&self) -> broadcast::Receiver<StatsEvent> {
+        self.events_tx.subscribe()
+    }
+
+    /// Records a sample of an engine other than [`DEFAULT_ENGINE`], like
+    /// [`HCE_ENGINE`] for variant batches, without recording a batch.
+    pub fn record_engine_nps(&mut self, engine: &str, nps: u32, nodes: u64) {
+        self.nnue_nps.record(engine, nps, nodes);
+        self.snapshot_tx.send_replace(self.snapshot());
+    }
+
+    pub fn db(&self) -> Option<&StatsDb> {
+        self.db.as_ref()
+    }
+
+    /// Available and total bytes on the filesystem of the database.
+    pub fn db_disk_space(&self) -> Option<(u64, u64)> {
+        let dir = self.db.as_ref()?.dir()?;
+        Some((
+            fs2::available_space(&dir).ok()?,
+            fs2::total_space(&dir).ok()?,
+        ))
+    }
+
+    /// Whether recorded stats are currently persisted, as of the most
+    /// recent write.
+    pub fn persistence_status(&self) -> PersistenceStatus {
+        if self.store_failing
+            || self.db_failing
+            || self.disk_space.as_ref().is_some_and(|guard| guard.low)
+        {
+            PersistenceStatus::Degraded
+        } else if self.store.is_none() && self.db.is_none() {
+            PersistenceStatus::Disabled
+        } else {
+            PersistenceStatus::Healthy
+        }
+    }
+
+    /// Lifetime distribution of batch durations.
+    pub fn batch_durations(&self) -> &BatchDurations {
+        &self.batch_durations
+    }
+
+    /// Lifetime totals, including work resumed from the stats file.
+    pub fn totals(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Work recorded since this recorder was created, as opposed to the
+    /// lifetime [`StatsRecorder::totals()`].
+    pub fn session(&self) -> &Stats {
+        &self.session
+    }
+
+    /// Fraction of `global_total` nodes contributed in this session. The
+    /// global total should cover the same period, so lifetime totals from
+    /// the stats file are deliberately not used.
+    pub fn contribution_share(&self, global_total: u64) -> f64 {
+        if global_total == 0 {
+            0.0
+        } else {
+            (self.session.total_nodes as f64 / global_total as f64).min(1.0)
+        }
+    }
+}
+
+/// Writes the stats file if deferred, flushes it to disk and commits rows
+/// that are pending with [`Durability::Fast`]. Errors are logged. Recorded
+/// stats may be lost if the recorder is leaked, for example with
+/// `mem::forget()`.
+impl Drop for StatsRecorder {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        self.flush();
+        if let Some((path, stats_file)) = &mut self.store {
+            if let Err(err) = stats_file.sync_all() {
+                eprintln!("E: Failed to flush stats to {path:?}: {err}");
+            }
+        }
+
+        if let Some(db) = &mut self.db {
+            if let Err(err) = db.commit() {
+                eprintln!("E: Failed to commit pending stats to SQLite database: {err}");
+            }
+            if db.truncate_wal {
+                match db.checkpoint() {
+                    Ok(true) => (),
+                    Ok(false) => eprintln!(
+                        "W: SQLite database is busy, write-ahead log not fully checkpointed"
+                    ),
+                    Err(err) => eprintln!("E: Failed to checkpoint SQLite database: {err}"),
+                }
+            }
+        }
+
+        self.emit(StatsEvent::Flushed);
+    }
+}
+
+const MB: u64 = 1024 * 1024;
+
+const DEFAULT_MIN_FREE_MB: u64 = 100;
+
+/// Interval for checking free disk space while recording.
+const DISK_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+fn available_space(dir: &Path) -> io::Result<u64> {
+    fs2::available_space(dir)
+}
+
+const DB_REOPEN_BACKOFF_MIN: Duration = Duration::from_secs(30);
+const DB_REOPEN_BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// Database that failed to open, to be retried.
+struct DbReopen {
+    path: PathBuf,
+    config: StatsDbConfig,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+/// Tracks whether free disk space in `dir` is below `min_free` bytes,
+/// checking at most once per [`DISK_SPACE_CHECK_INTERVAL`].
+struct DiskSpaceGuard {
+    dir: PathBuf,
+    min_free: u64,
+    checked: Option<Instant>,
+    low: bool,
+}
+
+impl DiskSpaceGuard {
+    fn new(dir: PathBuf, min_free: u64) -> DiskSpaceGuard {
+        DiskSpaceGuard {
+            dir,
+            min_free,
+            checked: None,
+            low: false,
+        }
+    }
+
+    fn is_low<F>(&mut self, now: Instant, available_space: F) -> bool
+    where
+        F: FnOnce(&Path) -> io::Result<u64>,
+    {
+        if self
+            .checked
+            .is_some_and(|checked| now.duration_since(checked) < DISK_SPACE_CHECK_INTERVAL)
+        {
+            return self.low;
+        }
+        self.checked = Some(now);
+        match available_space(&self.dir) {
+            Ok(available) => {
+                let low = available < self.min_free;
+                if low && !self.low {
+                    eprintln!(
+                        "W: Only {} MB free on {:?}. Pausing stats database until space recovers ...",
+                        available / MB,
+                        self.dir
+                    );
+                } else if !low && self.low {
+                    println!("Disk space recovered. Resuming stats database ...");
+                }
+                self.low = low;
+            }
+            Err(err) => eprintln!(
+                "W: Failed to check free disk space on {:?}: {err}",
+                self.dir
+            ),
+        }
+        self.low
+    }
+}
+
+/// Interval in which repeated identical errors are collapsed into a
+/// single summary.
+const ERROR_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Collapses repeated identical errors of a sink, so that sustained
+/// failures do not flood the log. Methods return the lines to log.
+#[derive(Default)]
+struct ErrorThrottle {
+    last: Option<(String, Instant)>,
+    suppressed: u64,
+}
+
+impl ErrorThrottle {
+    fn error(&mut self, now: Instant, message: String) -> Vec<String> {
+        if let Some((last, since)) = &mut self.last {
+            if *last == message {
+                if now.duration_since(*since) < ERROR_SUMMARY_INTERVAL {
+                    self.suppressed += 1;
+                    return Vec::new();
+                }
+                let occurrences = mem::take(&mut self.suppressed) + 1;
+                *since = now;
+                return vec![format!(
+                    "{message} ({occurrences} occurrences in the last minute)"
+                )];
+            }
+        }
+        let mut lines: Vec<String> = self.recovered().into_iter().collect();
+        lines.push(message.clone());
+        self.last = Some((message, now));
+        lines
+    }
+
+    /// Summarizes errors that were suppressed since they were last logged.
+    fn recovered(&mut self) -> Option<String> {
+        let (message, _) = self.last.take()?;
+        let suppressed = mem::take(&mut self.suppressed);
+        (suppressed > 0).then(|| format!("{message} ({suppressed} more occurrences)"))
+    }
+}
+
+fn log_errors<I: IntoIterator<Item = String>>(lines: I) {
+    for line in lines {
+        eprintln!("E: {line}");
+    }
+}
+
+/// Collects `--stats-tag` options. Later tags override earlier tags with
+/// the same key.
+fn tag_map(tags: &[StatsTag]) -> BTreeMap<String, String> {
+    tags.iter()
+        .map(|tag| (tag.key.clone(), tag.value.clone()))
+        .collect()
+}
+
+/// Describes how the totals of the stats file differ from the latest row
+/// of the database. They agree unless one of them missed a write, for
+/// example due to a crash between writing the two.
+fn stats_mismatch(file: &Stats, db: &Stats) -> Option<String> {
+    (file != db).then(|| {
+        format!(
+            "Stats file ({} batches, {} nodes) and database ({} batches, {} nodes) disagree. One of them missed writes, for example due to a crash.",
+            file.total_batches, file.total_nodes, db.total_batches, db.total_nodes
+        )
+    })
+}
+
+/// `stats` with nodes rounded to the nearest multiple of `precision`, see
+/// --stats-precision-nodes.
+fn round_nodes(stats: &Stats, precision: u64) -> Stats {
+    let precision = precision.max(1);
+    Stats {
+        total_nodes: stats.total_nodes.saturating_add(precision / 2) / precision * precision,
+        ..stats.clone()
+    }
+}
+
+/// Seed the totals from the latest row of the database instead of a
+/// stats file that started from scratch, if the database is ahead.
+fn prefer_db(file: &Stats, fresh: bool, db: &Stats) -> bool {
+    fresh && db.total_batches > file.total_batches
+}
+
+/// Cores that stats were recorded with, if different from `cores`.
+fn changed_cores(recorded: Option<u64>, cores: NonZeroUsize) -> Option<u64> {
+    recorded.filter(|&recorded| recorded != cores_u64(cores))
+}
+
+/// Number of cores for per-core math. Never 0, and saturates on the
+/// (theoretical) platforms where `usize` is wider than `u64`.
+pub fn cores_u64(cores: NonZeroUsize) -> u64 {
+    u64::try_from(cores.get()).unwrap_or(u64::MAX)
+}
+
+/// Share of `total` per core.
+fn per_core(total: f64, cores: NonZeroUsize) -> f64 {
+    total / cores_u64(cores) as f64
+}
+
+fn open_stats_file(
+    path: &Path,
+    fallback: bool,
+    lock_timeout: Duration,
+) -> io::Result<(PathBuf, File)> {
+    reject_directory(path)?;
+    let open = |path: &Path| -> io::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        lock_stats_file(&file, lock_timeout)?;
+        Ok(file)
+    };
+    open_with_fallback(path, "fishnet-stats", fallback, is_unwritable, open)
+}
+
+/// Opens `path` with `open`, or the file `name` in [`fallback_dir()`] if
+/// `path` is not writable and `fallback` is enabled.
+fn open_with_fallback<T, E: fmt::Display>(
+    path: &Path,
+    name: &str,
+    fallback: bool,
+    is_unwritable: fn(&E) -> bool,
+    open: impl Fn(&Path) -> Result<T, E>,
+) -> Result<(PathBuf, T), E> {
+    match open(path) {
+        Ok(opened) => Ok((path.to_owned(), opened)),
+        Err(err) if fallback && is_unwritable(&err) => match fallback_dir() {
+            Ok(dir) => {
+                let fallback_path = dir.join(name);
+                eprintln!(
+                    "W: {path:?} is not writable ({err}). Recording to {fallback_path:?} instead ..."
+                );
+                let opened = open(&fallback_path)?;
+                Ok((fallback_path, opened))
+            }
+            Err(dir_err) => {
+                eprintln!("W: Failed to create fallback directory: {dir_err}");
+                Err(err)
+            }
+        },
+        Err(err) => Err(err),
+    }
+}
+
+/// Directory for fallback files of the current user, because the temporary
+/// directory may be shared with other users.
+fn fallback_dir() -> io::Result<PathBuf> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _};
+
+        // Without libc, the owner of a new file is the simplest way to find
+        // the effective user.
+        let uid = tempfile::tempfile()?.metadata()?.uid();
+        let dir = env::temp_dir().join(format!("fishnet-{uid}"));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => (),
+        }
+        let metadata = fs::symlink_metadata(&dir)?;
+        if !metadata.is_dir() || metadata.uid() != uid {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{dir:?} is not a directory owned by the current user"),
+            ));
+        }
+        Ok(dir)
+    }
+    #[cfg(not(unix))]
+    {
+        // Already per user on Windows.
+        let dir = env::temp_dir().join("fishnet");
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+}
+
+/// Interval of attempts to lock the stats file.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Takes an advisory lock on the stats file, so that two instances do not
+/// overwrite each other's totals. Retries until `timeout` has elapsed.
+fn lock_stats_file(file: &File, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(()),
+            Err(err) if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "locked by another instance",
+                    ));
+                }
+                thread::sleep(remaining.min(LOCK_RETRY_INTERVAL));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Errors after which further writes to the database cannot succeed, for
+/// example because the file was deleted, corrupted or made read-only.
+fn is_unrecoverable(err: &Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(
+            ErrorCode::SystemIoFailure
+                | ErrorCode::DatabaseCorrupt
+                | ErrorCode::NotADatabase
+                | ErrorCode::CannotOpen
+                | ErrorCode::ReadOnly
+        )
+    )
+}
+
+fn is_unwritable(err: &io::Error) -> bool {
+    // Not using io::ErrorKind::ReadOnlyFilesystem, which requires Rust 1.83.
+    const EROFS: i32 = 30;
+    err.kind() == io::ErrorKind::PermissionDenied
+        || (cfg!(unix) && err.raw_os_error() == Some(EROFS))
+}
+
+fn is_db_unwritable(err: &StatsError) -> bool {
+    matches!(
+        err,
+        StatsError::Sqlite(err) if matches!(
+            err.sqlite_error_code(),
+            Some(ErrorCode::CannotOpen | ErrorCode::ReadOnly)
+        )
+    )
+}
+
+/// Recomputes derived columns in the configured database.
+pub fn backfill_stats(opt: StatsOpt) -> ExitCode {
+    let Some(mut db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    match db.backfill(|done, total| {
+        eprint!("\rBackfilling ... {done}/{total} rows");
+    }) {
+        Ok(total) => {
+            eprintln!("\rBackfilled {total} rows");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("\nE: Failed to backfill, nothing changed: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Seeds the stats file and database from the stats file of another client,
+/// which may still be running, or from a URL where it is published. Does
+/// nothing if stats have already been recorded, so that importing twice
+/// does not count work twice.
+pub async fn import_stats(opt: StatsOpt, client: &reqwest::Client, source: &str) -> ExitCode {
+    let stats_key = match opt.stats_key() {
+        Ok(stats_key) => stats_key,
+        Err(err) => {
+            eprintln!("E: Invalid $FISHNET_STATS_KEY: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let imported = match load_stats_source(client, source, stats_key.as_ref()).await {
+        Ok(imported) => imported,
+        Err(err) => {
+            eprintln!("E: Failed to import {source:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut recorder = StatsRecorder::new(opt, None);
+    if recorder.store.is_none() {
+        // The reason was logged when opening.
+        eprintln!("E: No stats file to import {source:?} into");
+        return ExitCode::FAILURE;
+    }
+    if !recorder.totals().is_empty() {
+        eprintln!("E: Stats have already been recorded. Not importing {source:?}");
+        return ExitCode::FAILURE;
+    }
+    recorder.stats = imported;
+    recorder.persist(None, None);
+    let totals = recorder.totals();
+    println!(
+        "Imported {} batches, {} positions and {} nodes",
+        totals.total_batches, totals.total_positions, totals.total_nodes
+    );
+    ExitCode::SUCCESS
+}
+
+/// Loads stats from a file, encrypted with `key` if given, or, with the http
+/// feature, a plaintext file published at an http(s) URL.
+#[cfg_attr(not(feature = "http"), allow(unused_variables))]
+async fn load_stats_source(
+    client: &reqwest::Client,
+    source: &str,
+    key: Option<&StatsKey>,
+) -> Result<Stats, String> {
+    #[cfg(feature = "http")]
+    if let Ok(url) = url::Url::parse(source) {
+        if matches!(url.scheme(), "http" | "https") {
+            return Stats::load_from_url(client, url.as_str())
+                .await
+                .map_err(|err| err.to_string());
+        }
+    }
+    Stats::load_path(Path::new(source), key).map_err(|err| err.to_string())
+}
+
+/// Merges the rows of the stats database at `file` into the configured
+/// database, as the time series of `source`. A copy of the other database
+/// is migrated to the current schema first, the original is not modified.
+pub fn merge_db(opt: StatsOpt, file: &Path, source: &str) -> ExitCode {
+    if opt.db_snapshot {
+        eprintln!("E: Cannot merge a time series into a snapshot (--db-snapshot)");
+        return ExitCode::FAILURE;
+    }
+    let dir = match tempfile::tempdir() {
+        Ok(dir) => dir,
+        Err(err) => {
+            eprintln!("E: Failed to create temporary directory: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let other = StatsDb::open_copy(
+        file,
+        dir.path(),
+        StatsDbConfig {
+            table: opt.stats_db_table.clone().unwrap_or_default(),
+            node_unit: opt.stats_node_unit.unwrap_or_default(),
+            ..StatsDbConfig::default()
+        },
+    );
+    let other = match other {
+        Ok(other) => other,
+        Err(err) => {
+            eprintln!("E: Failed to open stats database {file:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(mut db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    match db.merge(&other, source) {
+        Ok(MergeCounts { merged, skipped }) => {
+            println!("Merged {merged} rows, skipped {skipped} rows that were already present");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("E: Failed to merge {file:?}, nothing changed: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Records `batches` synthetic batches through
+/// [`StatsRecorder::record_batch()`] into a temporary stats file and
+/// database with the configured settings, at most `rate` per second, and
+/// reports write throughput, latency and file sizes.
+pub fn bench_stats(opt: StatsOpt, batches: u64, rate: Option<f64>) -> ExitCode {
+    let dir = match tempfile::tempdir() {
+        Ok(dir) => dir,
+        Err(err) => {
+            eprintln!("E: Failed to create temporary directory: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let file_path = dir.path().join("fishnet-stats");
+    let db_path = dir.path().join("stats.db");
+    let (_, config) = StatsDb::path_and_config(&opt);
+    let tags = config.tags.clone();
+    let (store, db) = match (
+        File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&file_path),
+        StatsDb::open(&db_path, config),
+    ) {
+        (Ok(file), Ok(db)) => (file, db),
+        (Err(err), _) => {
+            eprintln!("E: Failed to create {file_path:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+        (_, Err(err)) => {
+            eprintln!("E: Failed to initialize SQLite database {db_path:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut recorder = StatsRecorder::with_sinks(
+        Stats::default(),
+        NpsRecorder::with_seed(0),
+        NonZeroUsize::MIN,
+        tags,
+        true,
+        Some((file_path.clone(), store)),
+        Some(db),
+    );
+    // Rows are spaced as if batches arrived at `rate`, regardless of how
+    // fast they are actually written.
+    let interval = rate
+        .filter(|&rate| rate > 0.0)
+        .map_or(Duration::from_secs(1), |rate| {
+            Duration::from_secs_f64(1.0 / rate)
+        });
+    let clock = Arc::new(MockClock::new(SystemTime::now()));
+    recorder.set_clock(clock.clone());
+    let mut rng = Rng::with_seed(0);
+
+    let started = Instant::now();
+    let mut latencies = Vec::new();
+    for i in 0..batches {
+        if rate.is_some() {
+            if let Some(wait) = (started
+                + interval.saturating_mul(u32::try_from(i).unwrap_or(u32::MAX)))
+            .checked_duration_since(Instant::now())
+            {
+                thread::sleep(wait);
+            }
+        }
+        clock.advance(interval);
+        let positions = rng.u64(1..=60);
+        let nodes = positions * rng.u64(1_000_000..=2_000_000);
+        let write = Instant::now();
+        recorder.record_batch(
+            positions,
+            nodes,
+            Some(rng.u32(300_000..=600_000)),
+            interval,
+            i == 0,
+        );
+        latencies.push(write.elapsed());
+    }
+    drop(recorder);
+    let elapsed = started.elapsed();
+
+    latencies.sort_unstable();
+    let quantile = |q: f64| {
+        latencies
+            .len()
+            .checked_sub(1)
+            .and_then(|last| latencies.get((q * last as f64).round() as usize))
+            .map_or(0.0, |latency| latency.as_secs_f64() * 1000.0)
+    };
+    let size = |path: &Path| fs::metadata(path).map_or(0, |meta| meta.len());
+    println!(
+        "Recorded {batches} batches in {:.2}s ({:.0} batches/s)",
+        elapsed.as_secs_f64(),
+        batches as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    println!(
+        "Write latency: p50 {:.3} ms, p99 {:.3} ms, max {:.3} ms",
+        quantile(0.5),
+        quantile(0.99),
+        quantile(1.0)
+    );
+    println!(
+        "Database: {} KB (+ {} KB write-ahead log), stats file: {} bytes",
+        size(&db_path) / 1024,
+        size(&db_path.with_extension("db-wal")) / 1024,
+        size(&file_path)
+    );
+    ExitCode::SUCCESS
+}
+
+/// Prints the rows matching `filter`. `csv_delimiter` separates fields of
+/// [`ExportFormat::Csv`].
+pub fn export_stats(
+    opt: StatsOpt,
+    format: ExportFormat,
+    csv_delimiter: char,
+    filter: &RowFilter,
+) -> ExitCode {
+    let Some(db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    let rows = match db.filtered_rows(filter) {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("E: Failed to query stats database: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = write_rows(&mut io::stdout().lock(), format, csv_delimiter, &rows) {
+        eprintln!("E: Failed to export stats: {err}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+fn write_rows(
+    w: &mut impl Write,
+    format: ExportFormat,
+    csv_delimiter: char,
+    rows: &[StatsRow],
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Json => {
+            for row in rows {
+                serde_json::to_writer(&mut *w, row)?;
+                writeln!(w)?;
+            }
+        }
+        ExportFormat::Csv => {
+            // All numbers are integers, so there are no decimal separators
+            // that could clash with the delimiter.
+            let delimiter = csv_delimiter.to_string();
+            writeln!(
+                w,
+                "{}",
+                [
+                    "id",
+                    "timestamp",
+                    "timestamp_ms",
+                    "total_batches",
+                    "total_positions",
+                    "total_nodes",
+                    "total_positions_cached",
+                    "total_unique_positions",
+                    "nnue_nps",
+                    "batch_nodes",
+                    "cores",
+                    "version",
+                    "cold_start",
+                    "temp_c",
+                    "tags",
+                    "source",
+                ]
+                .join(&delimiter)
+            )?;
+            for row in rows {
+                let optional = |value: Option<String>| value.unwrap_or_default();
+                let fields = [
+                    row.id.to_string(),
+                    row.timestamp.to_unix_secs().to_string(),
+                    optional(row.timestamp_ms.map(|millis| millis.to_string())),
+                    row.stats.total_batches.to_string(),
+                    row.stats.total_positions.to_string(),
+                    row.stats.total_nodes.to_string(),
+                    row.stats.total_positions_cached.to_string(),
+                    row.stats.total_unique_positions.to_string(),
+                    optional(row.nnue_nps.map(|nps| nps.to_string())),
+                    optional(row.batch_nodes.map(|nodes| nodes.to_string())),
+                    optional(row.cores.map(|cores| cores.to_string())),
+                    csv_field(row.version.as_deref().unwrap_or_default(), csv_delimiter),
+                    optional(row.cold_start.map(|cold| u8::from(cold).to_string())),
+                    optional(row.temp_c.map(|temp| temp.to_string())),
+                    if row.tags.is_empty() {
+                        String::new()
+                    } else {
+                        csv_field(&serde_json::to_string(&row.tags)?, csv_delimiter)
+                    },
+                    csv_field(row.source.as_deref().unwrap_or_default(), csv_delimiter),
+                ];
+                writeln!(w, "{}", fields.join(&delimiter))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Quotes `value` for CSV, if needed (RFC 4180).
+fn csv_field(value: &str, delimiter: char) -> String {
+    if value.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Prints the recorded stats as `key=value` lines, see
+/// [`StatsRecorder::dump()`].
+pub fn dump_stats(opt: StatsOpt, cores: NonZeroUsize) -> ExitCode {
+    let recorder = StatsRecorder::open_read_only(&opt, cores);
+    if let Err(err) = recorder.dump(&mut io::stdout().lock()) {
+        eprintln!("E: Failed to dump stats: {err}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Prints the change in performance from the window `from` to the window
+/// `to` of the configured database.
+pub fn compare_stats(opt: StatsOpt, from: TimeRange, to: TimeRange) -> ExitCode {
+    let Some(db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    let reports = db.window_report(from.start, from.end).and_then(|before| {
+        let after = db.window_report(to.start, to.end)?;
+        Ok((before, after))
+    });
+    match reports {
+        Ok((Some(before), Some(after))) => {
+            for line in comparison(&before, &after) {
+                println!("{line}");
+            }
+        }
+        Ok((before, after)) => {
+            for (range, report) in [(from, before), (to, after)] {
+                if report.is_none() {
+                    eprintln!("W: No stats recorded in {range}, nothing to compare");
+                }
+            }
+        }
+        Err(err) => {
+            eprintln!("E: Failed to query stats database: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Prints the work recorded in the configured database per fishnet
+/// version.
+pub fn version_stats(opt: StatsOpt) -> ExitCode {
+    let Some(db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    match db.version_reports() {
+        Ok(reports) if reports.is_empty() => eprintln!("W: No stats recorded"),
+        Ok(reports) => {
+            for report in reports {
+                println!(
+                    "{:<12} {:>10} batches {:>8} positions {:>8} nodes {:>16}",
+                    report.version.as_deref().unwrap_or("unknown"),
+                    report.batches,
+                    format_nodes(report.positions),
+                    format_nodes(report.nodes),
+                    report
+                        .avg_nps
+                        .map_or_else(|| "-".to_owned(), format_nps_per_core)
+                );
+            }
+        }
+        Err(err) => {
+            eprintln!("E: Failed to query stats database: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Marks an event in the time series of the configured database, while
+/// another instance may be recording to it.
+pub fn annotate_stats(opt: StatsOpt, label: &str) -> ExitCode {
+    let Some(mut db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    match db.annotate(Timestamp::now(), label) {
+        Ok(()) => {
+            println!("Annotated {label:?}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("E: Failed to save annotation to SQLite database: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints the hour or day of the configured database with the most nodes.
+pub fn busiest_stats(opt: StatsOpt, granularity: Granularity) -> ExitCode {
+    let Some(db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    match db.busiest_period(granularity) {
+        Ok(Some((start, work))) => {
+            let range = TimeRange {
+                start,
+                end: Timestamp::from_unix_secs(start.to_unix_secs() + granularity.secs() - 1),
+            };
+            println!(
+                "{range}: {} batches, {} positions, {} nodes",
+                work.total_batches,
+                format_nodes(work.total_positions),
+                format_nodes(work.total_nodes)
+            );
+        }
+        Ok(None) => eprintln!("W: No stats recorded"),
+        Err(err) => {
+            eprintln!("E: Failed to query stats database: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Prints the engine output captured with the row `id` of the configured
+/// database.
+pub fn output_stats(opt: StatsOpt, id: i64) -> ExitCode {
+    let Some(db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    match db.sample_output(id) {
+        Ok(Some(output)) => print!("{output}"),
+        Ok(None) => eprintln!("W: No output captured for row {id}"),
+        Err(err) => {
+            eprintln!("E: Failed to query stats database: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Compacts rows of the configured database older than `older_than` into
+/// aggregates per `bucket`, see [`StatsDb::rollup`].
+pub fn rollup_stats(opt: StatsOpt, older_than: Duration, bucket: Duration) -> ExitCode {
+    let Some(mut db) = StatsDb::open_opt(&opt) else {
+        return ExitCode::FAILURE;
+    };
+    match db
+        .rollup(older_than, bucket)
+        .and_then(|deleted| Ok((deleted, db.rollups()?)))
+    {
+        Ok((deleted, rollups)) => {
+            println!(
+                "Rolled up {deleted} rows, {} buckets in total",
+                rollups.len()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("E: Failed to roll up stats database: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Change from `before` to `after` in percent. `None` if there is nothing
+/// to compare to.
+fn percent_change(before: f64, after: f64) -> Option<f64> {
+    (before > 0.0).then(|| (after - before) / before * 100.0)
+}
+
+/// Report lines comparing hourly rates and nps of two windows, which may
+/// have different lengths.
+fn comparison(before: &WindowReport, after: &WindowReport) -> Vec<String> {
+    [
+        (
+            "batches/h",
+            Some(before.per_hour(before.batches)),
+            Some(after.per_hour(after.batches)),
+        ),
+        (
+            "positions/h",
+            Some(before.per_hour(before.positions)),
+            Some(after.per_hour(after.positions)),
+        ),
+        (
+            "nodes/h",
+            Some(before.per_hour(before.nodes)),
+            Some(after.per_hour(after.nodes)),
+        ),
+        (
+            "avg nps",
+            before.avg_nps.map(f64::from),
+            after.avg_nps.map(f64::from),
+        ),
+    ]
+    .into_iter()
+    // Only with --stats-temperature.
+    .chain(
+        (before.max_temp_c.is_some() || after.max_temp_c.is_some()).then(|| {
+            (
+                "max temp C",
+                before.max_temp_c.map(f64::from),
+                after.max_temp_c.map(f64::from),
+            )
+        }),
+    )
+    .map(|(label, before, after)| {
+        let change = before
+            .zip(after)
+            .and_then(|(before, after)| percent_change(before, after));
+        format!(
+            "{label:<12} {:>16} -> {:>16} {:>9}",
+            before.map_or_else(|| "-".to_owned(), |v| format!("{v:.0}")),
+            after.map_or_else(|| "-".to_owned(), |v| format!("{v:.0}")),
+            change.map_or_else(|| "n/a".to_owned(), |c| format!("{c:+.1}%")),
+        )
+    })
+    .collect()
+}
+
+/// Expands `$VAR` and `${VAR}` in `path`. Missing parent directories are
+/// created with `mkdir` (--stats-mkdir), and always for templated paths,
+/// so that per-host paths work out of the box.
+fn resolve_path(path: &Path, mkdir: bool) -> io::Result<PathBuf> {
+    resolve_path_with(path, mkdir, |name| env::var(name).ok())
+}
+
+/// Expands `$VAR` and `${VAR}` in `path` like [`resolve_path`], but never
+/// creates directories.
+fn expand_path(path: &Path) -> io::Result<PathBuf> {
+    match path.to_str() {
+        Some(template) => expand_vars(template, |name| env::var(name).ok())
+            .map(PathBuf::from)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+        None => Ok(path.to_owned()),
+    }
+}
+
+/// [`resolve_path`] with variables from `lookup` instead of the environment.
+fn resolve_path_with<F>(path: &Path, mkdir: bool, lookup: F) -> io::Result<PathBuf>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let (resolved, templated) = match path.to_str() {
+        Some(template) => {
+            let expanded = expand_vars(template, lookup)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let templated = expanded != template;
+            (PathBuf::from(expanded), templated)
+        }
+        None => (path.to_owned(), false),
+    };
+    if mkdir || templated {
+        if let Some(parent) = resolved.parent() {
+            fs::create_dir_all(parent).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to create directory {parent:?}: {err}"),
+                )
+            })?;
+        }
+    }
+    reject_directory(&resolved)?;
+    Ok(resolved)
+}
+
+/// Opening a directory as a file fails with a confusing error, if at all.
+fn reject_directory(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "is a directory, expected the path of a file (which may be in that directory)",
+        ));
+    }
+    Ok(())
+}
+
+/// Suggests --stats-mkdir if opening `path` may have failed because its
+/// directory does not exist.
+fn missing_dir_hint(path: &Path) -> &'static str {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+            " (directory does not exist, create it or use --stats-mkdir)"
+        }
+        _ => "",
+    }
+}
+
+/// Source of the current time, so that tests can control time with
+/// [`MockClock`].
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn system_now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that stands still until advanced. For tests and simulations.
+pub struct MockClock {
+    elapsed: std::sync::Mutex<Duration>,
+    instant: Instant,
+    system_time: SystemTime,
+}
+
+impl MockClock {
+    pub fn new(system_time: SystemTime) -> MockClock {
+        MockClock {
+            elapsed: std::sync::Mutex::new(Duration::ZERO),
+            instant: Instant::now(),
+            system_time,
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.instant + *self.elapsed.lock().unwrap()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_time + *self.elapsed.lock().unwrap()
+    }
+}
+
+/// Point in time, stored in the database as whole seconds since the unix
+/// epoch. Times before the epoch (e.g. from a misconfigured clock) are
+/// negative.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(SystemTime);
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp(SystemTime::now())
+    }
+
+    pub fn from_unix_secs(secs: i64) -> Timestamp {
+        let offset = Duration::from_secs(secs.unsigned_abs());
+        Timestamp(if secs < 0 {
+            UNIX_EPOCH - offset
+        } else {
+            UNIX_EPOCH + offset
+        })
+    }
+
+    /// Whole milliseconds since the unix epoch, rounded down.
+    pub fn to_unix_millis(self) -> i64 {
+        match self.0.duration_since(UNIX_EPOCH) {
+            Ok(after) => i64::try_from(after.as_millis()).unwrap_or(i64::MAX),
+            Err(err) => {
+                let before = err.duration();
+                i64::try_from(before.as_millis()).map_or(i64::MIN, |millis| {
+                    -millis - i64::from(before.subsec_nanos() % 1_000_000 > 0)
+                })
+            }
+        }
+    }
+
+    /// Whole seconds since the unix epoch, rounded down.
+    pub fn to_unix_secs(self) -> i64 {
+        match self.0.duration_since(UNIX_EPOCH) {
+            Ok(after) => i64::try_from(after.as_secs()).unwrap_or(i64::MAX),
+            Err(err) => {
+                let before = err.duration();
+                i64::try_from(before.as_secs()).map_or(i64::MIN, |secs| {
+                    -secs - i64::from(before.subsec_nanos() > 0)
+                })
+            }
+        }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        Timestamp(time)
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> SystemTime {
+        timestamp.0
+    }
+}
+
+impl ops::Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, duration: Duration) -> Timestamp {
+        Timestamp(self.0 - duration)
+    }
+}
+
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_unix_secs()))
+    }
+}
+
+impl FromSql for Timestamp {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Timestamp> {
+        i64::column_result(value).map(Timestamp::from_unix_secs)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.to_unix_secs())
+    }
+}
+
+/// Time series of cumulative [`Stats`], one row per recorded batch.
+///
+/// Wraps a single connection, so it is `Send` but not `Sync`. Threads that
+/// read concurrently, like the dashboard, open their own.
+pub struct StatsDb {
+    conn: Connection,
+    table: DbTable,
+    durability: Durability,
+    node_unit: NodeUnit,
+    tags: Option<String>,
+    snapshot: bool,
+    truncate_wal: bool,
+    cores: Option<NonZeroUsize>,
+    uncommitted: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StatsDbConfig {
+    pub table: DbTable,
+    pub durability: Durability,
+    /// Labels recorded with every row.
+    pub tags: BTreeMap<String, String>,
+    /// Keep only a single row with the latest totals, instead of a time
+    /// series.
+    pub snapshot: bool,
+    /// Unit of stored node counts.
+    pub node_unit: NodeUnit,
+    /// Pages of write-ahead log before automatically checkpointing.
+    /// `None` for the SQLite default.
+    pub wal_autocheckpoint: Option<u32>,
+    /// Checkpoint and truncate the write-ahead log when dropped.
+    pub truncate_wal: bool,
+    /// Reject updates and deletes of recorded rows. Persists in the
+    /// database.
+    pub append_only: bool,
+}
+
+/// Schema migrations, applied in order to bring a stats table from its
+/// recorded version (the number of migrations already applied) to the
+/// current one. `{table}` is replaced with the validated table name.
+///
+/// Never edit an existing migration. Add a new one, and add the previous
+/// schema to the historical schemas in the tests. Rename columns only with
+/// [`Migration::RenameColumn`], which also works with old SQLite versions.
+const MIGRATIONS: &[Migration] = &[
+    // 1: Initial schema. Databases created before schema versioning have
+    // exactly this table, so it is created only if missing.
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS {table} (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL,
+            total_batches INTEGER NOT NULL,
+            total_positions INTEGER NOT NULL,
+            total_nodes INTEGER NOT NULL,
+            nnue_nps INTEGER NOT NULL
+        )",
+    ),
+    // 2: Nodes of the batch, i.e. the difference of total_nodes to the
+    // previous row. NULL for the first row and rows from before this
+    // migration, until backfilled.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN batch_nodes INTEGER"),
+    // 3: Aggregates of old rows, compacted by StatsDb::rollup.
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS {table}_rollup (
+            bucket_start INTEGER PRIMARY KEY,
+            bucket_secs INTEGER NOT NULL,
+            row_count INTEGER NOT NULL,
+            total_batches INTEGER NOT NULL,
+            total_positions INTEGER NOT NULL,
+            total_nodes INTEGER NOT NULL,
+            batch_nodes INTEGER,
+            avg_nnue_nps INTEGER,
+            peak_nnue_nps INTEGER
+        )",
+    ),
+    // 4: Labels from --stats-tag, as a JSON object. NULL if there are none.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN tags TEXT"),
+    // 5: Cores of the recording client. NULL for rows from before this
+    // migration.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN cores INTEGER"),
+    // 6: Version of the recording client. NULL for rows from before this
+    // migration.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN version TEXT"),
+    // 7: Settings that apply to all rows, like the node unit.
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS {table}_meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+    ),
+    // 8: Whether the batch was completed right after startup, see
+    // StatsRecorder::record_batch. NULL for rows from before this migration
+    // and rows that were not recorded for a batch.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN cold_start INTEGER"),
+    // 9: Positions skipped instead of analysed, see
+    // Stats::total_positions_cached.
+    Migration::Sql(
+        "ALTER TABLE {table} ADD COLUMN total_positions_cached INTEGER NOT NULL DEFAULT 0",
+    ),
+    // 10: Human readable markers, see StatsDb::annotate.
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS {table}_annotations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL,
+            label TEXT NOT NULL
+        )",
+    ),
+    // 11: CPU temperature in degrees Celsius, with --stats-temperature.
+    // NULL if not recorded or not available.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN temp_c INTEGER"),
+    // 12: Compressed engine output of sampled batches, with
+    // --stats-capture-output. Not part of StatsRow.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN sample_output BLOB"),
+    // 13: Distinct positions, see Stats::total_unique_positions.
+    Migration::Sql(
+        "ALTER TABLE {table} ADD COLUMN total_unique_positions INTEGER NOT NULL DEFAULT 0",
+    ),
+    // 14: Milliseconds since the unix epoch, so that rows within the same
+    // second remain ordered. timestamp stays in whole seconds for queries.
+    // NULL for rows from before this migration.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN timestamp_ms INTEGER"),
+    // 15: Machine that recorded the row, for rows of another database
+    // merged with merge-db. NULL for rows recorded by this machine.
+    Migration::Sql("ALTER TABLE {table} ADD COLUMN source TEXT"),
+];
+
+/// A step of [`MIGRATIONS`].
+enum Migration {
+    /// SQL statements.
+    Sql(&'static str),
+    /// Renames a column of the stats table, see [`rename_column`].
+    #[allow(dead_code)] // No column has been renamed, yet.
+    RenameColumn {
+        from: &'static str,
+        to: &'static str,
+    },
+}
+
+/// First SQLite version that supports `ALTER TABLE ... RENAME COLUMN`.
+const SQLITE_RENAME_COLUMN: i32 = 3_025_000;
+
+/// Renames column `from` of `table` to `to`. Before SQLite 3.25, the table
+/// is recreated instead, copying its rows and restoring its indexes and
+/// triggers.
+fn rename_column(
+    conn: &Connection,
+    table: &str,
+    from: &str,
+    to: &str,
+    sqlite_version: i32,
+) -> Result<()> {
+    if sqlite_version >= SQLITE_RENAME_COLUMN {
+        return conn.execute_batch(&format!("ALTER TABLE {table} RENAME COLUMN {from} TO {to}"));
+    }
+
+    let create: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    let dependents = conn
+        .prepare(
+            "SELECT sql FROM sqlite_master
+             WHERE type IN ('index', 'trigger') AND tbl_name = ?1 AND sql IS NOT NULL",
+        )?
+        .query_map([table], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    let columns = conn
+        .prepare(&format!("PRAGMA table_info({table})"))?
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .collect::<Result<Vec<_>>>()?;
+    let renamed_columns: Vec<&str> = columns
+        .iter()
+        .map(|column| if column == from { to } else { column })
+        .collect();
+
+    // Dropping the old table also drops its indexes and triggers, without
+    // firing them.
+    conn.execute_batch(&format!(
+        "ALTER TABLE {table} RENAME TO {table}_renaming;
+         {create};
+         INSERT INTO {table} ({}) SELECT {} FROM {table}_renaming;
+         DROP TABLE {table}_renaming;",
+        renamed_columns.join(", "),
+        columns.join(", "),
+        create = rename_identifier(&create, from, to),
+    ))?;
+    for sql in dependents {
+        conn.execute_batch(&rename_identifier(&sql, from, to))?;
+    }
+    Ok(())
+}
+
+/// Replaces `from` with `to` where it is a whole identifier in `sql`.
+fn rename_identifier(sql: &str, from: &str, to: &str) -> String {
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut renamed = String::with_capacity(sql.len());
+    let mut last = 0;
+    for (i, _) in sql.match_indices(from) {
+        let end = i + from.len();
+        if !sql[..i].ends_with(is_identifier) && !sql[end..].starts_with(is_identifier) {
+            renamed.push_str(&sql[last..i]);
+            renamed.push_str(to);
+            last = end;
+        }
+    }
+    renamed.push_str(&sql[last..]);
+    renamed
+}
+
+/// Installs triggers that reject updates and deletes of rows in `table`.
+/// They stay in the database, also when it is later opened without
+/// append-only mode.
+fn make_append_only(conn: &Connection, table: &DbTable) -> Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TRIGGER IF NOT EXISTS {table}_append_only_update BEFORE UPDATE ON {table}
+         BEGIN SELECT RAISE(ABORT, 'table {table} is append-only'); END;
+         CREATE TRIGGER IF NOT EXISTS {table}_append_only_delete BEFORE DELETE ON {table}
+         BEGIN SELECT RAISE(ABORT, 'table {table} is append-only'); END;"
+    ))
+}
+
+/// Applies pending [`MIGRATIONS`] to `table` and verifies that the result
+/// has the expected columns. Nothing is changed if that fails. Schema
+/// versions are tracked per table (rather than in `PRAGMA user_version`),
+/// so that multiple stats tables can share a database.
+fn migrate(conn: &mut Connection, table: &DbTable) -> Result<(), StatsError> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS fishnet_schema (
+            name TEXT PRIMARY KEY,
+            version INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    let version: usize = tx
+        .query_row(
+            "SELECT version FROM fishnet_schema WHERE name = ?1",
+            [table.to_string()],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    if version < MIGRATIONS.len() {
+        for migration in &MIGRATIONS[version..] {
+            match *migration {
+                Migration::Sql(sql) => {
+                    tx.execute_batch(&sql.replace("{table}", &table.to_string()))?
+                }
+                Migration::RenameColumn { from, to } => rename_column(
+                    &tx,
+                    &table.to_string(),
+                    from,
+                    to,
+                    rusqlite::version_number(),
+                )?,
+            }
+        }
+        tx.execute(
+            "INSERT INTO fishnet_schema (name, version) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET version = excluded.version",
+            params![table.to_string(), MIGRATIONS.len()],
+        )?;
+    }
+
+    let columns = tx
+        .prepare(&format!("PRAGMA table_info({table})"))?
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .collect::<Result<Vec<_>>>()?;
+    let missing: Vec<String> = ROW_COLUMNS
+        .split(", ")
+        .filter(|expected| !columns.iter().any(|column| column == expected))
+        .map(str::to_owned)
+        .collect();
+    if !missing.is_empty() {
+        return Err(StatsError::SchemaMismatch {
+            table: table.to_string(),
+            missing,
+        });
+    }
+
+    Ok(tx.commit()?)
+}
+
+/// Records `requested` as the node unit of a new table. Rows of tables
+/// from before node units were configurable are in nodes. Fails if rows
+/// were recorded in another unit.
+fn check_node_unit(
+    conn: &Connection,
+    table: &DbTable,
+    requested: NodeUnit,
+) -> Result<(), StatsError> {
+    let empty: bool = conn.query_row(
+        &format!("SELECT NOT EXISTS (SELECT 1 FROM {table})"),
+        [],
+        |row| row.get(0),
+    )?;
+    let initial = if empty { requested } else { NodeUnit::Nodes };
+    conn.execute(
+        &format!("INSERT OR IGNORE INTO {table}_meta (key, value) VALUES ('node_unit', ?1)"),
+        [initial.to_string()],
+    )?;
+    let recorded: String = conn.query_row(
+        &format!("SELECT value FROM {table}_meta WHERE key = 'node_unit'"),
+        [],
+        |row| row.get(0),
+    )?;
+    if recorded != requested.to_string() {
+        return Err(StatsError::NodeUnit {
+            table: table.to_string(),
+            recorded,
+            requested,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum StatsError {
+    Sqlite(Error),
+    /// The stats table exists, but lacks expected columns even after
+    /// migrating, so it was not created by fishnet.
+    SchemaMismatch {
+        table: String,
+        missing: Vec<String>,
+    },
+    /// Snapshot mode was requested for a table that already contains a
+    /// time series.
+    TimeSeries {
+        table: String,
+    },
+    /// Rows of the table were recorded in another node unit.
+    NodeUnit {
+        table: String,
+        recorded: String,
+        requested: NodeUnit,
+    },
+    #[cfg(feature = "http")]
+    Http(reqwest::Error),
+    #[cfg(feature = "http")]
+    HttpStatus(reqwest::StatusCode),
+    #[cfg(feature = "http")]
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for StatsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatsError::Sqlite(err) => write!(f, "{err}"),
+            StatsError::SchemaMismatch { table, missing } => write!(
+                f,
+                "unexpected schema of table {table}, missing columns: {}",
+                missing.join(", ")
+            ),
+            StatsError::TimeSeries { table } => write!(
+                f,
+                "table {table} contains a time series, use another table for --db-snapshot"
+            ),
+            StatsError::NodeUnit {
+                table,
+                recorded,
+                requested,
+            } => write!(
+                f,
+                "table {table} records {recorded}, use another table for --stats-node-unit {requested}"
+            ),
+            #[cfg(feature = "http")]
+            StatsError::Http(err) => write!(f, "{err}"),
+            #[cfg(feature = "http")]
+            StatsError::HttpStatus(status) => write!(f, "unexpected status {status}"),
+            #[cfg(feature = "http")]
+            StatsError::Json(err) => write!(f, "malformed stats: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StatsError {}
+
+impl From<Error> for StatsError {
+    fn from(err: Error) -> StatsError {
+        StatsError::Sqlite(err)
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<reqwest::Error> for StatsError {
+    fn from(err: reqwest::Error) -> StatsError {
+        StatsError::Http(err)
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<serde_json::Error> for StatsError {
+    fn from(err: serde_json::Error) -> StatsError {
+        StatsError::Json(err)
+    }
+}
+
+/// Rows to collect in a single transaction with [`Durability::Fast`].
+const FAST_COMMIT_ROWS: usize = 16;
+
+const ROW_COLUMNS: &str = "id, timestamp, total_batches, total_positions, total_nodes, nnue_nps, batch_nodes, tags, cores, version, cold_start, total_positions_cached, temp_c, total_unique_positions, timestamp_ms, source";
+
+/// Version of fishnet recorded with stats.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+impl StatsDb {
+    pub fn open<P: AsRef<Path>>(path: P, config: StatsDbConfig) -> Result<StatsDb, StatsError> {
+        StatsDb::init(Connection::open(path)?, config)
+    }
+
+    /// Unresolved path and configuration of the database selected by
+    /// `opt`.
+    fn path_and_config(opt: &StatsOpt) -> (PathBuf, StatsDbConfig) {
+        let db_path = opt
+            .stats_db
+            .clone()
+            .or_else(|| {
+                xdg_state_dir()
+                    .filter(|_| opt.stats_xdg)
+                    .map(|dir| dir.join("stats.db"))
+            })
+            .unwrap_or_else(|| PathBuf::from("stats.db"));
+        let config = StatsDbConfig {
+            table: opt.stats_db_table.clone().unwrap_or_default(),
+            durability: opt.stats_durability.unwrap_or_default(),
+            tags: tag_map(&opt.stats_tag),
+            snapshot: opt.db_snapshot,
+            node_unit: opt.stats_node_unit.unwrap_or_default(),
+            wal_autocheckpoint: opt.stats_wal_autocheckpoint,
+            truncate_wal: opt.stats_wal_truncate,
+            append_only: opt.stats_append_only,
+        };
+        (db_path, config)
+    }
+
+    /// Opens the database configured in `opt`, logging errors.
+    pub fn open_opt(opt: &StatsOpt) -> Option<StatsDb> {
+        let (db_path, config) = StatsDb::path_and_config(opt);
+        match resolve_path(&db_path, opt.stats_mkdir) {
+            // A missing directory is a mistake, rather than a read-only
+            // filesystem.
+            Ok(db_path) => match open_with_fallback(
+                &db_path,
+                "stats.db",
+                !opt.no_stats_fallback && missing_dir_hint(&db_path).is_empty(),
+                is_db_unwritable,
+                |path| StatsDb::open(path, config.clone()),
+            ) {
+                Ok((_, db)) => Some(db),
+                Err(err) => {
+                    eprintln!(
+                        "E: Failed to initialize SQLite database {db_path:?}: {err}{}",
+                        missing_dir_hint(&db_path)
+                    );
+                    None
+                }
+            },
+            Err(err) => {
+                eprintln!("E: Failed to resolve stats database {db_path:?}: {err}");
+                None
+            }
+        }
+    }
+
+    /// Opens a migrated copy of the database at `path`, created in `dir`,
+    /// leaving the original untouched.
+    fn open_copy(path: &Path, dir: &Path, config: StatsDbConfig) -> Result<StatsDb, StatsError> {
+        let copy = dir.join("copy.db");
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?.execute(
+            "VACUUM INTO ?1",
+            params![copy.to_string_lossy().into_owned()],
+        )?;
+        StatsDb::open(&copy, config)
+    }
+
+    /// Opens the database at `path` for queries only. Unlike
+    /// [`StatsDb::open()`], nothing is created or migrated, so queries fail
+    /// on databases of older versions.
+    fn open_read_only(path: &Path, config: StatsDbConfig) -> Result<StatsDb, StatsError> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let recorded: Option<String> = conn
+            .query_row(
+                &format!(
+                    "SELECT value FROM {}_meta WHERE key = 'node_unit'",
+                    config.table
+                ),
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match recorded {
+            Some(recorded) if recorded != config.node_unit.to_string() => {
+                Err(StatsError::NodeUnit {
+                    table: config.table.to_string(),
+                    recorded,
+                    requested: config.node_unit,
+                })
+            }
+            _ => Ok(StatsDb {
+                conn,
+                table: config.table,
+                durability: config.durability,
+                node_unit: config.node_unit,
+                tags: None,
+                snapshot: config.snapshot,
+                truncate_wal: false,
+                cores: None,
+                uncommitted: 0,
+            }),
+        }
+    }
+
+    pub fn open_in_memory(config: StatsDbConfig) -> Result<StatsDb, StatsError> {
+        StatsDb::init(Connection::open_in_memory()?, config)
+    }
+
+    fn init(mut conn: Connection, config: StatsDbConfig) -> Result<StatsDb, StatsError> {
+        let StatsDbConfig {
+            table,
+            durability,
+            tags,
+            snapshot,
+            node_unit,
+            wal_autocheckpoint,
+            truncate_wal,
+            append_only,
+        } = config;
+        let (journal_mode, synchronous) = match durability {
+            Durability::Fast => ("WAL", "OFF"),
+            Durability::Balanced => ("WAL", "NORMAL"),
+            Durability::Safe => ("DELETE", "FULL"),
+        };
+        conn.pragma_update_and_check(None, "journal_mode", journal_mode, |row| {
+            row.get::<_, String>(0)
+        })?;
+        conn.pragma_update(None, "synchronous", synchronous)?;
+        if let Some(pages) = wal_autocheckpoint {
+            conn.pragma_update(None, "wal_autocheckpoint", pages)?;
+        }
+        migrate(&mut conn, &table)?;
+        if snapshot
+            && conn.query_row(
+                &format!("SELECT EXISTS (SELECT 1 FROM {table} WHERE id > 1)"),
+                [],
+                |row| row.get(0),
+            )?
+        {
+            return Err(StatsError::TimeSeries {
+                table: table.to_string(),
+            });
+        }
+        check_node_unit(&conn, &table, node_unit)?;
+        if append_only {
+            make_append_only(&conn, &table)?;
+        }
+        Ok(StatsDb {
+            conn,
+            table,
+            durability,
+            node_unit,
+            tags: if tags.is_empty() {
+                None
+            } else {
+                Some(serde_json::to_string(&tags).expect("serialize tags"))
+            },
+            snapshot,
+            truncate_wal: truncate_wal && durability != Durability::Safe,
+            cores: None,
+            uncommitted: 0,
+        })
+    }
+
+    /// Directory of the database file. `None` for in-memory databases.
+    fn dir(&self) -> Option<PathBuf> {
+        self.conn
+            .path()
+            .filter(|path| !path.is_empty())
+            .and_then(|path| Path::new(path).parent())
+            .map(Path::to_owned)
+    }
+
+    /// `stats` with nodes in the unit of this database.
+    fn scaled(&self, stats: &Stats) -> Stats {
+        Stats {
+            total_nodes: stats.total_nodes / self.node_unit.nodes(),
+            ..stats.clone()
+        }
+    }
+
+    /// Inverse of [`StatsDb::scaled()`], up to the precision of the unit.
+    fn unscaled(&self, stats: &Stats) -> Stats {
+        Stats {
+            total_nodes: self.unscaled_nodes(stats.total_nodes),
+            ..stats.clone()
+        }
+    }
+
+    /// Stored node count in nodes. Reports return nodes regardless of the
+    /// unit of the database.
+    fn unscaled_nodes(&self, nodes: u64) -> u64 {
+        nodes.saturating_mul(self.node_unit.nodes())
+    }
+
+    /// Cores recorded with subsequent rows.
+    pub fn set_cores(&mut self, cores: NonZeroUsize) {
+        self.cores = Some(cores);
+    }
+
+    /// Selects full rows. The table name is validated, so it is safe to
+    /// interpolate.
+    fn select_rows(&self, clauses: &str) -> String {
+        format!("SELECT {ROW_COLUMNS} FROM {} {clauses}", self.table)
+    }
+
+    pub fn insert(&mut self, stats: &Stats, nnue_nps: Option<u32>) -> Result<()> {
+        self.insert_at(Timestamp::now(), stats, nnue_nps)
+    }
+
+    fn insert_at(
+        &mut self,
+        timestamp: Timestamp,
+        stats: &Stats,
+        nnue_nps: Option<u32>,
+    ) -> Result<()> {
+        self.insert_row(timestamp, stats, nnue_nps, None, None, None)
+    }
+
+    fn insert_row(
+        &mut self,
+        timestamp: Timestamp,
+        stats: &Stats,
+        nnue_nps: Option<u32>,
+        cold_start: Option<bool>,
+        temp_c: Option<i32>,
+        sample_output: Option<&[u8]>,
+    ) -> Result<()> {
+        if self.durability == Durability::Fast && self.conn.is_autocommit() {
+            self.conn.execute_batch("BEGIN")?;
+        }
+        let stats = self.scaled(stats);
+        self.conn.execute(
+            &format!(
+                "INSERT INTO {table} (id, timestamp, total_batches, total_positions, total_nodes, nnue_nps, batch_nodes, tags, cores, version, cold_start, total_positions_cached, temp_c, sample_output, total_unique_positions, timestamp_ms)
+                 VALUES (?7, ?1, ?2, ?3, ?4, ?5, MAX(?4 - (SELECT total_nodes FROM {table} WHERE source IS NULL ORDER BY id DESC LIMIT 1), 0), ?6, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+                 ON CONFLICT (id) DO UPDATE SET
+                     timestamp = excluded.timestamp,
+                     total_batches = excluded.total_batches,
+                     total_positions = excluded.total_positions,
+                     total_nodes = excluded.total_nodes,
+                     nnue_nps = excluded.nnue_nps,
+                     batch_nodes = excluded.batch_nodes,
+                     tags = excluded.tags,
+                     cores = excluded.cores,
+                     version = excluded.version,
+                     cold_start = excluded.cold_start,
+                     total_positions_cached = excluded.total_positions_cached,
+                     temp_c = excluded.temp_c,
+                     sample_output = excluded.sample_output,
+                     total_unique_positions = excluded.total_unique_positions,
+                     timestamp_ms = excluded.timestamp_ms",
+                table = self.table
+            ),
+            params![
+                timestamp,
+                stats.total_batches as i64,
+                stats.total_positions as i64,
+                stats.total_nodes as i64,
+                nnue_nps.unwrap_or_default() as i64, // 0 if not reported
+                self.tags,
+                self.snapshot.then_some(1), // Otherwise the next id
+                self.cores.map(|cores| cores.get() as i64),
+                VERSION,
+                cold_start,
+                stats.total_positions_cached as i64,
+                temp_c,
+                sample_output,
+                stats.total_unique_positions as i64,
+                timestamp.to_unix_millis(),
+            ],
+        )?;
+        if self.durability == Durability::Fast {
+            self.uncommitted += 1;
+            if self.uncommitted >= FAST_COMMIT_ROWS {
+                self.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the write-ahead log into the database file and truncates it.
+    /// Returns `false` if other connections prevented a full checkpoint.
+    pub fn checkpoint(&mut self) -> Result<bool> {
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|busy| busy == 0)
+    }
+
+    /// Commits rows that are pending with [`Durability::Fast`].
+    pub fn commit(&mut self) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("COMMIT")?;
+        }
+        self.uncommitted = 0;
+        Ok(())
+    }
+
+    /// Recomputes derived columns of all rows in a single transaction,
+    /// reporting progress as `(done, total)` rows. Idempotent. Returns the
+    /// number of rows.
+    pub fn backfill<F>(&mut self, mut progress: F) -> Result<u64>
+    where
+        F: FnMut(u64, u64),
+    {
+        self.commit()?;
+        let tx = self.conn.transaction()?;
+        let (min_id, max_id, total): (Option<i64>, Option<i64>, u64) = tx.query_row(
+            &format!("SELECT MIN(id), MAX(id), COUNT(*) FROM {}", self.table),
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let (Some(min_id), Some(max_id)) = (min_id, max_id) else {
+            return Ok(0);
+        };
+
+        const CHUNK: i64 = 10_000;
+        let mut done = 0;
+        for chunk_start in (min_id..=max_id).step_by(CHUNK as usize) {
+            done += tx.execute(
+                &format!(
+                    "UPDATE {table} SET batch_nodes = MAX(total_nodes - (
+                        SELECT prev.total_nodes FROM {table} AS prev
+                        WHERE prev.id < {table}.id AND prev.source IS {table}.source
+                        ORDER BY prev.id DESC LIMIT 1
+                    ), 0)
+                    WHERE id BETWEEN ?1 AND ?2",
+                    table = self.table
+                ),
+                params![chunk_start, chunk_start + CHUNK - 1],
+            )? as u64;
+            progress(done, total);
+        }
+        tx.commit()?;
+        Ok(total)
+    }
+
+    /// Copies rows that `other` recorded itself into this database as the
+    /// time series of `source`, in a single transaction. Rows that `other`
+    /// merged keep their source. Rows are identified by source, time and
+    /// batch count, and skipped if already present. They keep their tags
+    /// and cores, but get new ids.
+    pub fn merge(&mut self, other: &StatsDb, source: &str) -> Result<MergeCounts> {
+        self.commit()?;
+        let rows = other
+            .conn
+            .prepare(&other.select_rows("ORDER BY id"))?
+            .query_map([], StatsRow::from_row)?
+            .collect::<Result<Vec<_>>>()?;
+
+        let tx = self.conn.transaction()?;
+        let mut counts = MergeCounts::default();
+        for row in rows {
+            let source = row.source.as_deref().unwrap_or(source);
+            let duplicate: bool = tx.query_row(
+                &format!(
+                    "SELECT EXISTS (
+                         SELECT 1 FROM {} WHERE source = ?1 AND timestamp = ?2
+                            AND timestamp_ms IS ?3 AND total_batches = ?4
+                     )",
+                    self.table
+                ),
+                params![
+                    source,
+                    row.timestamp,
+                    row.timestamp_ms,
+                    row.stats.total_batches as i64,
+                ],
+                |row| row.get(0),
+            )?;
+            if duplicate {
+                counts.skipped += 1;
+                continue;
+            }
+            tx.execute(
+                &format!(
+                    "INSERT INTO {} (timestamp, total_batches, total_positions, total_nodes, nnue_nps, batch_nodes, tags, cores, version, cold_start, total_positions_cached, temp_c, total_unique_positions, timestamp_ms, source)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                    self.table
+                ),
+                params![
+                    row.timestamp,
+                    row.stats.total_batches as i64,
+                    row.stats.total_positions as i64,
+                    row.stats.total_nodes as i64,
+                    row.nnue_nps.unwrap_or_default() as i64,
+                    row.batch_nodes.map(|nodes| nodes as i64),
+                    if row.tags.is_empty() {
+                        None
+                    } else {
+                        Some(serde_json::to_string(&row.tags).expect("serialize tags"))
+                    },
+                    row.cores.map(|cores| cores as i64),
+                    &row.version,
+                    row.cold_start,
+                    row.stats.total_positions_cached as i64,
+                    row.temp_c,
+                    row.stats.total_unique_positions as i64,
+                    row.timestamp_ms,
+                    source,
+                ],
+            )?;
+            counts.merged += 1;
+        }
+        tx.commit()?;
+        Ok(counts)
+    }
+
+    /// Work recorded by each version, in order of first appearance. Work is
+    /// the difference of totals to the previous row of the same source, so
+    /// the first row of each source does not count.
+    pub fn version_reports(&self) -> Result<Vec<VersionReport>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT version, COUNT(*), SUM(positions), SUM(nodes), AVG(NULLIF(nnue_nps, 0))
+             FROM (
+                 SELECT id, version, nnue_nps,
+                        total_positions - LAG(total_positions) OVER (PARTITION BY source ORDER BY id) AS positions,
+                        total_nodes - LAG(total_nodes) OVER (PARTITION BY source ORDER BY id) AS nodes
+                 FROM {}
+             )
+             GROUP BY version ORDER BY MIN(id)",
+            self.table
+        ))?;
+        let rows = stmt.query_map([], |row| {
+            Ok(VersionReport {
+                version: row.get(0)?,
+                batches: row.get(1)?,
+                positions: row.get::<_, Option<u64>>(2)?.unwrap_or(0),
+                nodes: self.unscaled_nodes(row.get::<_, Option<u64>>(3)?.unwrap_or(0)),
+                avg_nps: row.get::<_, Option<f64>>(4)?.map(|avg| avg as u32),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Most recently inserted row recorded by this machine, if any.
+    pub fn latest(&self) -> Result<Option<StatsRow>> {
+        self.conn
+            .query_row(
+                &self.select_rows("WHERE source IS NULL ORDER BY id DESC LIMIT 1"),
+                [],
+                StatsRow::from_row,
+            )
+            .optional()
+    }
+
+    /// Decompressed engine output captured with the row `id`, if any.
+    pub fn sample_output(&self, id: i64) -> Result<Option<String>> {
+        let compressed: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                &format!("SELECT sample_output FROM {} WHERE id = ?1", self.table),
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        compressed
+            .map(|compressed| {
+                zstd::decode_all(&compressed[..])
+                    .map(|output| String::from_utf8_lossy(&output).into_owned())
+                    .map_err(|err| Error::FromSqlConversionFailure(0, Type::Blob, Box::new(err)))
+            })
+            .transpose()
+    }
+
+    /// Rows inserted after the row with id `last_id`, oldest first, and
+    /// the id to pass next time, for incremental polling. Start with 0.
+    /// Updates of the single row of a snapshot table are not seen.
+    pub fn rows_since(&self, last_id: i64) -> Result<(Vec<StatsRow>, i64)> {
+        let mut stmt = self
+            .conn
+            .prepare(&self.select_rows("WHERE id > ?1 ORDER BY id"))?;
+        let rows = stmt
+            .query_map([last_id], StatsRow::from_row)?
+            .collect::<Result<Vec<_>>>()?;
+        let next_id = rows.last().map_or(last_id, |row| row.id);
+        Ok((rows, next_id))
+    }
+
+    /// Rows matching `filter`, oldest first.
+    pub fn filtered_rows(&self, filter: &RowFilter) -> Result<Vec<StatsRow>> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(since) = filter.since {
+            values.push(Box::new(since));
+            conditions.push(format!("timestamp >= ?{}", values.len()));
+        }
+        if let Some(until) = filter.until {
+            values.push(Box::new(until));
+            conditions.push(format!("timestamp <= ?{}", values.len()));
+        }
+        for (key, value) in &filter.tags {
+            values.push(Box::new(format!("$.{key}")));
+            values.push(Box::new(value.clone()));
+            conditions.push(format!(
+                "json_extract(tags, ?{}) = ?{}",
+                values.len() - 1,
+                values.len()
+            ));
+        }
+        let clauses = if conditions.is_empty() {
+            "ORDER BY id".to_owned()
+        } else {
+            format!("WHERE {} ORDER BY id", conditions.join(" AND "))
+        };
+        let mut stmt = self.conn.prepare(&self.select_rows(&clauses))?;
+        let rows = stmt.query_map(params_from_iter(&values), StatsRow::from_row)?;
+        rows.collect()
+    }
+
+    /// Marks an event like "changed cores" in the time series, to explain
+    /// changes when analysing history.
+    pub fn annotate(&mut self, timestamp: Timestamp, label: &str) -> Result<()> {
+        self.conn.execute(
+            &format!(
+                "INSERT INTO {}_annotations (timestamp, label) VALUES (?1, ?2)",
+                self.table
+            ),
+            params![timestamp, label],
+        )?;
+        Ok(())
+    }
+
+    /// Annotations from `since` (inclusive), oldest first.
+    pub fn annotations(&self, since: Timestamp) -> Result<Vec<Annotation>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT timestamp, label FROM {}_annotations WHERE timestamp >= ?1 ORDER BY timestamp, id",
+            self.table
+        ))?;
+        let annotations = stmt
+            .query_map(params![since], |row| {
+                Ok(Annotation {
+                    timestamp: row.get(0)?,
+                    label: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        Ok(annotations)
+    }
+
+    /// The `n` most recently inserted rows, oldest first.
+    pub fn recent(&self, n: u32) -> Result<Vec<StatsRow>> {
+        let mut stmt = self
+            .conn
+            .prepare(&self.select_rows("ORDER BY id DESC LIMIT ?1"))?;
+        let mut rows = stmt
+            .query_map(params![n], StatsRow::from_row)?
+            .collect::<Result<Vec<_>>>()?;
+        rows.reverse();
+        Ok(rows)
+    }
+
+    /// Cumulative totals of each source as of its last row before
+    /// `timestamp`. Totals of this machine fall back to rolled up rows.
+    fn totals_before(&self, timestamp: Timestamp) -> Result<BTreeMap<Option<String>, Stats>> {
+        let mut totals = self
+            .conn
+            .prepare(&self.select_rows(&format!(
+                "WHERE id IN (SELECT MAX(id) FROM {} WHERE timestamp < ?1 GROUP BY source)",
+                self.table
+            )))?
+            .query_map(params![timestamp], StatsRow::from_row)?
+            .map(|row| row.map(|row| (row.source, row.stats)))
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        if !totals.contains_key(&None) {
+            let rolled_up = self.conn.query_row(
+                &format!(
+                    "SELECT MAX(total_batches), MAX(total_positions), MAX(total_nodes)
+                         FROM {}_rollup WHERE bucket_start + bucket_secs <= ?1",
+                    self.table
+                ),
+                params![timestamp],
+                |row| {
+                    Ok(Stats {
+                        total_batches: row.get::<_, Option<u64>>(0)?.unwrap_or(0),
+                        total_positions: row.get::<_, Option<u64>>(1)?.unwrap_or(0),
+                        total_nodes: row.get::<_, Option<u64>>(2)?.unwrap_or(0),
+                        // Not rolled up.
+                        total_positions_cached: 0,
+                        total_unique_positions: 0,
+                    })
+                },
+            )?;
+            totals.insert(None, rolled_up);
+        }
+        Ok(totals)
+    }
+
+    /// Compacts rows older than `older_than` into aggregates per `bucket`
+    /// in the rollup table, deleting the original rows. Only complete
+    /// buckets of rows recorded by this machine are rolled up. Returns the
+    /// number of deleted rows.
+    pub fn rollup(&mut self, older_than: Duration, bucket: Duration) -> Result<usize> {
+        self.rollup_at(Timestamp::now(), older_than, bucket)
+    }
+
+    fn rollup_at(
+        &mut self,
+        now: Timestamp,
+        older_than: Duration,
+        bucket: Duration,
+    ) -> Result<usize> {
+        let bucket_secs = max(bucket.as_secs(), 1) as i64;
+        let cutoff = Timestamp::from_unix_secs(
+            (now - older_than).to_unix_secs().div_euclid(bucket_secs) * bucket_secs,
+        );
+
+        self.commit()?;
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            &format!(
+                "INSERT INTO {table}_rollup (bucket_start, bucket_secs, row_count, total_batches, total_positions, total_nodes, batch_nodes, avg_nnue_nps, peak_nnue_nps)
+                 SELECT timestamp / ?2 * ?2 AS bucket, ?2, COUNT(*), MAX(total_batches), MAX(total_positions), MAX(total_nodes), SUM(batch_nodes), AVG(NULLIF(nnue_nps, 0)), NULLIF(MAX(nnue_nps), 0)
+                 FROM {table} WHERE timestamp < ?1 AND source IS NULL GROUP BY bucket
+                 ON CONFLICT (bucket_start) DO UPDATE SET
+                    row_count = row_count + excluded.row_count,
+                    total_batches = MAX(total_batches, excluded.total_batches),
+                    total_positions = MAX(total_positions, excluded.total_positions),
+                    total_nodes = MAX(total_nodes, excluded.total_nodes),
+                    batch_nodes = batch_nodes + excluded.batch_nodes,
+                    avg_nnue_nps = COALESCE((avg_nnue_nps * row_count + excluded.avg_nnue_nps * excluded.row_count) / (row_count + excluded.row_count), avg_nnue_nps, excluded.avg_nnue_nps),
+                    peak_nnue_nps = MAX(COALESCE(peak_nnue_nps, 0), COALESCE(excluded.peak_nnue_nps, 0))",
+                table = self.table
+            ),
+            params![cutoff, bucket_secs],
+        )?;
+        let deleted = tx.execute(
+            &format!(
+                "DELETE FROM {} WHERE timestamp < ?1 AND source IS NULL",
+                self.table
+            ),
+            params![cutoff],
+        )?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Rolled up aggregates, oldest first.
+    pub fn rollups(&self) -> Result<Vec<RollupRow>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT bucket_start, bucket_secs, row_count, total_batches, total_positions, total_nodes, batch_nodes, avg_nnue_nps, peak_nnue_nps
+             FROM {}_rollup ORDER BY bucket_start",
+            self.table
+        ))?;
+        let rows = stmt.query_map([], RollupRow::from_row)?;
+        rows.collect()
+    }
+
+    /// Start and work done of the period with the most nodes, for
+    /// example to plan maintenance windows. Periods are aligned to UTC.
+    /// Work of all sources is added up. `None` if there are no rows.
+    pub fn busiest_period(&self, granularity: Granularity) -> Result<Option<(Timestamp, Stats)>> {
+        let secs = granularity.secs();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT timestamp / ?1 AS bucket, source,
+                    MAX(total_batches), MAX(total_positions), MAX(total_nodes), MAX(total_positions_cached),
+                    MAX(total_unique_positions)
+             FROM {} GROUP BY bucket, source ORDER BY bucket",
+            self.table
+        ))?;
+        let rows = stmt.query_map(params![secs], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                Stats {
+                    total_batches: row.get(2)?,
+                    total_positions: row.get(3)?,
+                    total_nodes: row.get(4)?,
+                    total_positions_cached: row.get(5)?,
+                    total_unique_positions: row.get(6)?,
+                },
+            ))
+        })?;
+
+        // Totals are cumulative per source, so the work of a period is the
+        // difference to the end of the previous period of the same source.
+        let mut prev: BTreeMap<Option<String>, Stats> = BTreeMap::new();
+        let mut periods: Vec<(i64, Stats)> = Vec::new();
+        for row in rows {
+            let (bucket, source, totals) = row?;
+            let base = match prev.remove(&source) {
+                Some(base) => base,
+                None => self
+                    .totals_before(Timestamp::from_unix_secs(bucket * secs))?
+                    .remove(&source)
+                    .unwrap_or_default(),
+            };
+            let work = totals.diff(&base);
+            match periods.last_mut() {
+                Some((last, sum)) if *last == bucket => *sum += &work,
+                _ => periods.push((bucket, work)),
+            }
+            prev.insert(source, totals);
+        }
+
+        let mut busiest: Option<(i64, Stats)> = None;
+        for (bucket, work) in periods {
+            if busiest
+                .as_ref()
+                .map_or(true, |(_, most)| work.total_nodes > most.total_nodes)
+            {
+                busiest = Some((bucket, work));
+            }
+        }
+        Ok(busiest.map(|(bucket, work)| {
+            (
+                Timestamp::from_unix_secs(bucket * secs),
+                self.unscaled(&work),
+            )
+        }))
+    }
+
+    /// Work done in each hour since `since`, oldest first, added up over all
+    /// sources. Hours without rows are omitted.
+    pub fn hourly_reports(&self, since: Timestamp) -> Result<Vec<WindowReport>> {
+        let mut prev = self.totals_before(since)?;
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT timestamp / 3600 AS hour, source,
+                    MAX(total_batches), MAX(total_positions), MAX(total_nodes),
+                    SUM(NULLIF(nnue_nps, 0)), COUNT(NULLIF(nnue_nps, 0)), MAX(nnue_nps),
+                    MAX(total_positions_cached), MAX(temp_c), MAX(total_unique_positions)
+             FROM {} WHERE timestamp >= ?1 GROUP BY hour, source ORDER BY hour",
+            self.table
+        ))?;
+        let rows = stmt.query_map(params![since], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                Stats {
+                    total_batches: row.get(2)?,
+                    total_positions: row.get(3)?,
+                    total_nodes: row.get(4)?,
+                    total_positions_cached: row.get(8)?,
+                    total_unique_positions: row.get(10)?,
+                },
+                row.get::<_, Option<f64>>(5)?.unwrap_or(0.0),
+                row.get::<_, u64>(6)?,
+                row.get::<_, Option<u32>>(7)?.filter(|peak| *peak > 0),
+                row.get::<_, Option<i32>>(9)?,
+            ))
+        })?;
+
+        // Work and the sum and count of nps samples of each hour.
+        let mut hours: Vec<(i64, Stats, f64, u64, Option<u32>, Option<i32>)> = Vec::new();
+        for row in rows {
+            let (hour, source, totals, nps_sum, nps_count, peak_nps, max_temp_c) = row?;
+            let work = totals.diff(&prev.remove(&source).unwrap_or_default());
+            prev.insert(source, totals);
+            match hours.last_mut() {
+                Some(last) if last.0 == hour => {
+                    last.1 += &work;
+                    last.2 += nps_sum;
+                    last.3 += nps_count;
+                    last.4 = max(last.4, peak_nps);
+                    last.5 = max(last.5, max_temp_c);
+                }
+                _ => hours.push((hour, work, nps_sum, nps_count, peak_nps, max_temp_c)),
+            }
+        }
+
+        Ok(hours
+            .into_iter()
+            .map(
+                |(hour, work, nps_sum, nps_count, peak_nps, max_temp_c)| WindowReport {
+                    start: Timestamp::from_unix_secs(hour * 3600),
+                    end: Timestamp::from_unix_secs(hour * 3600 + 3599),
+                    batches: work.total_batches,
+                    positions: work.total_positions,
+                    nodes: self.unscaled_nodes(work.total_nodes),
+                    avg_nps: (nps_count > 0).then(|| (nps_sum / nps_count as f64) as u32),
+                    peak_nps,
+                    max_temp_c,
+                },
+            )
+            .collect())
+    }
+
+    /// Work done in the time window `start..=end`, computed from the
+    /// cumulative totals of each source at the end of the window and its
+    /// last row before the window, added up over all sources. `None` if
+    /// there are no rows in the window.
+    pub fn window_report(&self, start: Timestamp, end: Timestamp) -> Result<Option<WindowReport>> {
+        let last = self
+            .conn
+            .prepare(&self.select_rows(&format!(
+                "WHERE id IN (SELECT MAX(id) FROM {} WHERE timestamp BETWEEN ?1 AND ?2 GROUP BY source)",
+                self.table
+            )))?
+            .query_map(params![start, end], StatsRow::from_row)?
+            .collect::<Result<Vec<_>>>()?;
+        if last.is_empty() {
+            return Ok(None);
+        }
+
+        let mut base = self.totals_before(start)?;
+
+        let (avg_nps, peak_nps, max_temp_c) = self.conn.query_row(
+            &format!(
+                "SELECT AVG(NULLIF(nnue_nps, 0)), MAX(nnue_nps), MAX(temp_c)
+                 FROM {} WHERE timestamp BETWEEN ?1 AND ?2",
+                self.table
+            ),
+            params![start, end],
+            |row| {
+                Ok((
+                    row.get::<_, Option<f64>>(0)?.map(|avg| avg as u32),
+                    row.get::<_, Option<u32>>(1)?.filter(|peak| *peak > 0),
+                    row.get::<_, Option<i32>>(2)?,
+                ))
+            },
+        )?;
+
+        let mut work = Stats::default();
+        for row in &last {
+            work += &row
+                .stats
+                .diff(&base.remove(&row.source).unwrap_or_default());
+        }
+        Ok(Some(WindowReport {
+            start,
+            end,
+            batches: work.total_batches,
+            positions: work.total_positions,
+            nodes: self.unscaled_nodes(work.total_nodes),
+            avg_nps,
+            peak_nps,
+            max_temp_c,
+        }))
+    }
+
+    /// Work per second averaged over the `window` ending at `now`, from the
+    /// row deltas like [`StatsDb::window_report()`]. Zero if no work was
+    /// recorded in the window, for example when idle, or if there are no
+    /// rows from before the window, yet.
+    pub fn rate(&self, now: Timestamp, window: Duration) -> Result<RateReport> {
+        let start = now - window;
+        let has_history: bool = self.conn.query_row(
+            &format!(
+                "SELECT EXISTS (SELECT 1 FROM {table} WHERE timestamp < ?1)
+                     OR EXISTS (SELECT 1 FROM {table}_rollup WHERE bucket_start + bucket_secs <= ?1)",
+                table = self.table
+            ),
+            params![start],
+            |row| row.get(0),
+        )?;
+        let work = match self.window_report(start, now)? {
+            Some(report) if has_history => Stats {
+                total_batches: report.batches,
+                total_positions: report.positions,
+                total_nodes: report.nodes,
+                ..Stats::default()
+            },
+            _ => Stats::default(),
+        };
+        Ok(RateReport::new(window, &work))
+    }
+}
+
+/// Uses an externally provided connection with the default configuration,
+/// migrating an outdated stats table. Fails if the table has an unexpected
+/// schema.
+impl TryFrom<Connection> for StatsDb {
+    type Error = StatsError;
+
+    fn try_from(conn: Connection) -> Result<StatsDb, StatsError> {
+        StatsDb::init(conn, StatsDbConfig::default())
+    }
+}
+
+impl Drop for StatsDb {
+    fn drop(&mut self) {
+        if let Err(err) = self.commit() {
+            eprintln!("E: Failed to commit pending stats to SQLite database: {err}");
+        }
+    }
+}
+
+/// Result of [`StatsDb::merge`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct MergeCounts {
+    pub merged: u64,
+    pub skipped: u64,
+}
+
+/// Selects rows for [`StatsDb::filtered_rows`]. Bounds are inclusive.
+#[derive(Debug, Default, Clone)]
+pub struct RowFilter {
+    pub since: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+    /// Tags that rows must all be labelled with. Keys are restricted like
+    /// in [`StatsTag`].
+    pub tags: BTreeMap<String, String>,
+}
+
+/// A single row of the stats table.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsRow {
+    pub id: i64,
+    pub timestamp: Timestamp,
+    /// Milliseconds since the unix epoch, to order rows within the same
+    /// second. `None` for rows recorded by older versions.
+    pub timestamp_ms: Option<i64>,
+    #[serde(flatten)]
+    pub stats: Stats,
+    pub nnue_nps: Option<u32>,
+    pub batch_nodes: Option<u64>,
+    pub tags: BTreeMap<String, String>,
+    pub cores: Option<u64>,
+    pub version: Option<String>,
+    pub cold_start: Option<bool>,
+    /// CPU temperature in degrees Celsius, with --stats-temperature.
+    pub temp_c: Option<i32>,
+    /// Machine that recorded the row, if merged from its database with
+    /// merge-db. Each source is a separate time series.
+    pub source: Option<String>,
+}
+
+impl StatsRow {
+    fn from_row(row: &Row<'_>) -> Result<StatsRow> {
+        Ok(StatsRow {
+            id: row.get("id")?,
+            timestamp: row.get("timestamp")?,
+            timestamp_ms: row.get("timestamp_ms")?,
+            stats: Stats {
+                total_batches: row.get("total_batches")?,
+                total_positions: row.get("total_positions")?,
+                total_nodes: row.get("total_nodes")?,
+                total_positions_cached: row.get("total_positions_cached")?,
+                total_unique_positions: row.get("total_unique_positions")?,
+            },
+            nnue_nps: Some(row.get("nnue_nps")?).filter(|nps| *nps > 0),
+            batch_nodes: row.get("batch_nodes")?,
+            tags: match row.get::<_, Option<String>>("tags")? {
+                Some(tags) => serde_json::from_str(&tags)
+                    .map_err(|err| Error::FromSqlConversionFailure(7, Type::Text, Box::new(err)))?,
+                None => BTreeMap::new(),
+            },
+            cores: row.get("cores")?,
+            version: row.get("version")?,
+            cold_start: row.get("cold_start")?,
+            temp_c: row.get("temp_c")?,
+            source: row.get("source")?,
+        })
+    }
+}
+
+/// Human readable marker in the time series, like "hardware replaced",
+/// see [`StatsDb::annotate`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Annotation {
+    pub timestamp: Timestamp,
+    pub label: String,
+}
+
+/// Aggregate of the rows in a time bucket, see [`StatsDb::rollup`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RollupRow {
+    pub bucket_start: u64,
+    pub bucket_secs: u64,
+    pub row_count: u64,
+    /// Cumulative totals at the end of the bucket.
+    #[serde(flatten)]
+    pub stats: Stats,
+    pub batch_nodes: Option<u64>,
+    pub avg_nnue_nps: Option<u32>,
+    pub peak_nnue_nps: Option<u32>,
+}
+
+impl RollupRow {
+    fn from_row(row: &Row<'_>) -> Result<RollupRow> {
+        Ok(RollupRow {
+            bucket_start: row.get("bucket_start")?,
+            bucket_secs: row.get("bucket_secs")?,
+            row_count: row.get("row_count")?,
+            stats: Stats {
+                total_batches: row.get("total_batches")?,
+                total_positions: row.get("total_positions")?,
+                total_nodes: row.get("total_nodes")?,
+                // Not rolled up.
+                total_positions_cached: 0,
+                total_unique_positions: 0,
+            },
+            batch_nodes: row.get("batch_nodes")?,
+            avg_nnue_nps: row
+                .get::<_, Option<f64>>("avg_nnue_nps")?
+                .map(|avg| avg as u32),
+            peak_nnue_nps: row.get("peak_nnue_nps")?,
+        })
+    }
+}
+
+/// Work done by a version of fishnet, see [`StatsDb::version_reports`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VersionReport {
+    /// `None` for rows recorded before versions were.
+    pub version: Option<String>,
+    pub batches: u64,
+    pub positions: u64,
+    pub nodes: u64,
+    pub avg_nps: Option<u32>,
+}
+
+/// Work done in a time window.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WindowReport {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub batches: u64,
+    pub positions: u64,
+    pub nodes: u64,
+    pub avg_nps: Option<u32>,
+    pub peak_nps: Option<u32>,
+    /// Hottest CPU temperature recorded in the window, in degrees Celsius.
+    pub max_temp_c: Option<i32>,
+}
+
+/// Work per second averaged over a window, see [`StatsDb::rate()`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RateReport {
+    pub window_secs: u64,
+    pub nodes_per_sec: f64,
+    pub positions_per_sec: f64,
+}
+
+impl RateReport {
+    pub fn new(window: Duration, work: &Stats) -> RateReport {
+        let secs = window.as_secs().max(1);
+        RateReport {
+            window_secs: secs,
+            nodes_per_sec: work.total_nodes as f64 / secs as f64,
+            positions_per_sec: work.total_positions as f64 / secs as f64,
+        }
+    }
+}
+
+impl WindowReport {
+    /// Rate of `count` per hour of the window.
+    fn per_hour(&self, count: u64) -> f64 {
+        let secs = max(self.end.to_unix_secs() - self.start.to_unix_secs(), 0) + 1;
+        count as f64 * 3600.0 / secs as f64
+    }
+}
+
+#[derive(Clone)]
+pub struct NpsRecorder {
+    pub nps: u32,
+    pub uncertainty: f64,
+    known: bool,
+    last_sample: Option<u32>,
+    peak_window: VecDeque<(Instant, u32)>,
+    // Most recent raw samples, for convergence.
+    recent: VecDeque<u32>,
+    convergence: Convergence,
+    smoothing: Smoothing,
+    // Samples learned faster, see NpsRecorder::set_warmup_batches.
+    warmup_batches: u64,
+    // Uniform sample of all raw samples, for percentiles.
+    reservoir: Vec<u32>,
+    samples: u64,
+    // Average batch size of all samples, the size of a typical sample.
+    avg_nodes: f64,
+    rng: Rng,
+    clock: Arc<dyn Clock>,
+}
+
+/// Uncertainty never decays below this, so that it can still react to
+/// changes after very long runs.
+const MIN_NPS_UNCERTAINTY: f64 = 0.01;
+
+/// Relative deviation of a sample from the estimate that indicates that
+/// the machine changed, for example due to throttling or other load.
+const NPS_DEVIATION: f64 = 0.5;
+
+/// Uncertainty after a deviating sample.
+const NPS_DEVIATION_UNCERTAINTY: f64 = 0.5;
+
+const PEAK_WINDOW: Duration = Duration::from_secs(10 * 60);
+const PEAK_WINDOW_CAPACITY: usize = 1024;
+
+const RESERVOIR_CAPACITY: usize = 256;
+
+/// Weight of the first sample relative to later samples in the warmup
+/// phase, see [`NpsRecorder::set_warmup_batches`].
+const WARMUP_WEIGHT: f64 = 4.0;
+
+/// Phase of the estimate, see [`NpsRecorder::phase`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NpsPhase {
+    /// Learning faster, while the hash table of the engine fills.
+    Warmup,
+    #[default]
+    Steady,
+}
+
+impl fmt::Display for NpsPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NpsPhase::Warmup => "warmup",
+            NpsPhase::Steady => "steady",
+        })
+    }
+}
+
+/// Number of recent samples that must agree with a converged estimate.
+const CONVERGENCE_SAMPLES: usize = 8;
+
+/// When [`NpsRecorder::has_converged`] considers the estimate stable.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Convergence {
+    /// Uncertainty must be below this.
+    pub max_uncertainty: f64,
+    /// Recent samples must deviate from the estimate by at most this
+    /// fraction.
+    pub tolerance: f64,
+}
+
+impl Default for Convergence {
+    fn default() -> Convergence {
+        Convergence {
+            max_uncertainty: 0.1,
+            tolerance: 0.05,
+        }
+    }
+}
+
+impl NpsRecorder {
+    fn new() -> NpsRecorder {
+        NpsRecorder {
+            nps: 400_000, // start with an optimistic estimate
+            uncertainty: 1.0,
+            known: true,
+            last_sample: None,
+            peak_window: VecDeque::new(),
+            recent: VecDeque::new(),
+            convergence: Convergence::default(),
+            smoothing: Smoothing::default(),
+            warmup_batches: 0,
+            reservoir: Vec::new(),
+            samples: 0,
+            avg_nodes: 0.0,
+            rng: Rng::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Like [`NpsRecorder::new()`], but sampling for percentiles is
+    /// deterministic, for tests and benchmarks.
+    pub fn with_seed(seed: u64) -> NpsRecorder {
+        NpsRecorder {
+            rng: Rng::with_seed(seed),
+            ..NpsRecorder::new()
+        }
+    }
+
+    /// Starts without an estimate, until the first sample is recorded.
+    fn new_unknown() -> NpsRecorder {
+        NpsRecorder {
+            nps: 0,
+            known: false,
+            ..NpsRecorder::new()
+        }
+    }
+
+    /// Independent copy of the estimate and its history, for example to
+    /// feed a shadow recorder with the same samples.
+    pub fn clone_state(&self) -> NpsRecorder {
+        self.clone()
+    }
+
+    pub fn smoothing(&self) -> Smoothing {
+        self.smoothing
+    }
+
+    /// Changes how samples are combined into the estimate from now on.
+    pub fn set_smoothing(&mut self, smoothing: Smoothing) {
+        self.smoothing = smoothing;
+    }
+
+    /// Learns faster from the first `batches` samples, because the first
+    /// batches after a fresh start are slow until the hash table is warm.
+    /// The weight of samples decreases linearly to the usual weight of the
+    /// exponentially weighted moving average. 0 to disable (default).
+    pub fn set_warmup_batches(&mut self, batches: u64) {
+        self.warmup_batches = batches;
+    }
+
+    pub fn phase(&self) -> NpsPhase {
+        if self.samples < self.warmup_batches {
+            NpsPhase::Warmup
+        } else {
+            NpsPhase::Steady
+        }
+    }
+
+    /// Factor applied to the weight of the next sample.
+    fn warmup_factor(&self) -> f64 {
+        match self.phase() {
+            NpsPhase::Warmup => {
+                let remaining = 1.0 - self.samples as f64 / self.warmup_batches as f64;
+                1.0 + (WARMUP_WEIGHT - 1.0) * remaining
+            }
+            NpsPhase::Steady => 1.0,
+        }
+    }
+
+    /// Current estimate, or `None` if started without an optimistic
+    /// estimate and nothing recorded, yet.
+    pub fn estimate(&self) -> Option<u32> {
+        self.known.then_some(self.nps)
+    }
+
+    /// Records the nps of a batch of `nodes`. Larger batches give more
+    /// reliable samples, so they move the estimate more. A batch of the
+    /// average size so far has the weight of a single sample in an
+    /// unweighted moving average.
+    fn record(&mut self, nps: u32, nodes: u64) {
+        self.record_at(self.clock.now(), nps, nodes);
+    }
+
+    fn record_at(&mut self, now: Instant, nps: u32, nodes: u64) {
+        // Capped, so that a single huge batch cannot erase the history.
+        let typical = if self.samples == 0 {
+            nodes as f64
+        } else {
+            self.avg_nodes
+        };
+        let weight = if typical > 0.0 {
+            (nodes as f64 / typical).min(10.0)
+        } else {
+            0.0
+        };
+        self.avg_nodes += (nodes as f64 - self.avg_nodes) / (self.samples + 1) as f64;
+        let alpha = 0.9f64.powf(weight);
+        self.uncertainty = (self.uncertainty * alpha).max(MIN_NPS_UNCERTAINTY);
+        if self.known
+            && (f64::from(nps) - f64::from(self.nps)).abs() > f64::from(self.nps) * NPS_DEVIATION
+        {
+            self.uncertainty = self.uncertainty.max(NPS_DEVIATION_UNCERTAINTY);
+        }
+        if self.recent.len() >= CONVERGENCE_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(nps);
+
+        self.nps = match self.smoothing {
+            Smoothing::Ewma if self.known => {
+                let alpha = alpha.powf(self.warmup_factor());
+                (f64::from(self.nps) * alpha + f64::from(nps) * (1.0 - alpha)) as u32
+            }
+            Smoothing::Ewma => nps,
+            Smoothing::Sma => {
+                (self
+                    .recent
+                    .iter()
+                    .map(|&sample| u64::from(sample))
+                    .sum::<u64>()
+                    / self.recent.len() as u64) as u32
+            }
+        };
+        self.known = true;
+        self.last_sample = Some(nps);
+
+        // Reservoir sampling, so that every sample so far is retained with
+        // equal probability.
+        self.samples += 1;
+        if self.reservoir.len() < RESERVOIR_CAPACITY {
+            self.reservoir.push(nps);
+        } else {
+            let i = self.rng.u64(..self.samples);
+            if let Some(slot) = usize::try_from(i)
+                .ok()
+                .and_then(|i| self.reservoir.get_mut(i))
+            {
+                *slot = nps;
+            }
+        }
+
+        // Monotonic deque: Samples are ordered by time, and nps is strictly
+        // decreasing, so that the front is the maximum of the window.
+        while self
+            .peak_window
+            .back()
+            .is_some_and(|&(_, sample)| sample <= nps)
+        {
+            self.peak_window.pop_back();
+        }
+        self.peak_window.push_back((now, nps));
+        while self.peak_window.len() > PEAK_WINDOW_CAPACITY
+            || self
+                .peak_window
+                .front()
+                .is_some_and(|&(at, _)| now.duration_since(at) > PEAK_WINDOW)
+        {
+            self.peak_window.pop_front();
+        }
+    }
+
+    /// Raw sample at quantile `q` (between 0 and 1) of all samples so far,
+    /// estimated from a uniform sample of at most 256 of them.
+    pub fn percentile(&self, q: f64) -> Option<u32> {
+        let mut sorted = self.reservoir.clone();
+        sorted.sort_unstable();
+        let last = sorted.len().checked_sub(1)?;
+        sorted
+            .get((q.clamp(0.0, 1.0) * last as f64).round() as usize)
+            .copied()
+    }
+
+    /// Overrides the default thresholds of [`NpsRecorder::has_converged`].
+    pub fn set_convergence(&mut self, convergence: Convergence) {
+        self.convergence = convergence;
+    }
+
+    /// The estimate has stabilized: It is certain enough, and the last 8
+    /// samples are all close to it. Useful to start measuring only after
+    /// warmup.
+    pub fn has_converged(&self) -> bool {
+        let nps = f64::from(self.nps);
+        self.known
+            && self.uncertainty < self.convergence.max_uncertainty
+            && self.recent.len() >= CONVERGENCE_SAMPLES
+            && self
+                .recent
+                .iter()
+                .all(|&sample| (f64::from(sample) - nps).abs() <= nps * self.convergence.tolerance)
+    }
+
+    /// Most recent raw sample, without smoothing.
+    pub fn last(&self) -> Option<u32> {
+        self.last_sample
+    }
+
+    /// Fraction of `max` nps per core that the current estimate leaves
+    /// unused. 0 if the estimate reaches or exceeds `max`.
+    pub fn headroom(&self, max: u32) -> f64 {
+        if max == 0 {
+            return 0.0;
+        }
+        (1.0 - f64::from(self.nps) / f64::from(max)).max(0.0)
+    }
+
+    /// Estimate relative to `reference` nps per core, for example 1.25 if
+    /// 25% faster. `None` without estimate or if `reference` is 0.
+    pub fn perf_index(&self, reference: u32) -> Option<f64> {
+        let nps = self.estimate()?;
+        (reference > 0).then(|| f64::from(nps) / f64::from(reference))
+    }
+
+    /// Best raw nps sample of the last 10 minutes.
+    pub fn peak_nps(&self) -> Option<u32> {
+        self.peak_nps_at(self.clock.now())
+    }
+
+    fn peak_nps_at(&self, now: Instant) -> Option<u32> {
+        self.peak_window
+            .iter()
+            .find(|&&(at, _)| now.duration_since(at) <= PEAK_WINDOW)
+            .map(|&(_, nps)| nps)
+    }
+}
+
+/// Engine of [`NpsRecorders`] that analyses batches.
+pub const DEFAULT_ENGINE: &str = "nnue";
+
+/// Engine of [`NpsRecorders`] that analyses variant batches, without NNUE.
+pub const HCE_ENGINE: &str = "hce";
+
+/// Separate [`NpsRecorder`]s for nodes that run more than one engine,
+/// keyed by engine id. The default engine is always present.
+#[derive(Clone)]
+pub struct NpsRecorders {
+    // State of engines that have not recorded a sample, yet.
+    initial: NpsRecorder,
+    engines: BTreeMap<String, NpsRecorder>,
+}
+
+impl NpsRecorders {
+    fn new(initial: NpsRecorder) -> NpsRecorders {
+        NpsRecorders {
+            engines: BTreeMap::from([(DEFAULT_ENGINE.to_owned(), initial.clone())]),
+            initial,
+        }
+    }
+
+    fn set_clock(&mut self, clock: &Arc<dyn Clock>) {
+        for nps in iter::once(&mut self.initial).chain(self.engines.values_mut()) {
+            nps.clock = Arc::clone(clock);
+        }
+    }
+
+    pub fn record(&mut self, engine: &str, nps: u32, nodes: u64) {
+        self.engines
+            .entry(engine.to_owned())
+            .or_insert_with(|| self.initial.clone())
+            .record(nps, nodes);
+    }
+
+    pub fn default_engine(&self) -> &NpsRecorder {
+        &self.engines[DEFAULT_ENGINE]
+    }
+
+    /// All engines, ordered by id.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &NpsRecorder)> {
+        self.engines
+            .iter()
+            .map(|(engine, nps)| (engine.as_str(), nps))
+    }
+}
+
+/// Shows only the estimate if there is just the default engine, otherwise
+/// each engine with its id.
+impl fmt::Display for NpsRecorders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.engines.len() == 1 {
+            return self.default_engine().fmt(f);
+        }
+        for (i, (engine, nps)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{engine} {nps}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats nps per core, rounded to knps, or in nps if less than 1 knps, so
+/// that slow devices do not show up as 0 knps/core.
+pub fn format_nps_per_core(nps: u32) -> String {
+    if nps < 1000 {
+        format!("{nps} nps/core")
+    } else {
+        format!("{} knps/core", (u64::from(nps) + 500) / 1000)
+    }
+}
+
+impl fmt::Display for NpsRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.known {
+            return write!(f, "? knps/core");
+        }
+        f.write_str(&format_nps_per_core(self.nps))?;
+        if self.uncertainty > 0.1 {
+            write!(f, " ?")?;
+        }
+        if self.uncertainty > 0.4 {
+            write!(f, "?")?;
+        }
+        if self.uncertainty > 0.7 {
+            write!(f, "?")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    const BATCH_NODES: u64 = 10_000_000;
+
+    fn at(secs: i64) -> Timestamp {
+        Timestamp::from_unix_secs(secs)
+    }
+
+    fn stats(total_batches: u64, total_positions: u64, total_nodes: u64) -> Stats {
+        Stats {
+            total_batches,
+            total_positions,
+            total_nodes,
+            ..Stats::default()
+        }
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert!(Stats::default().is_empty());
+        assert!(!stats(1, 0, 0).is_empty());
+        assert!(!stats(0, 0, 1).is_empty());
+    }
+
+    #[test]
+    fn test_load_legacy_field_names() {
+        let dir = tempfile::tempdir().unwrap();
+        for legacy in [
+            r#"{"totalBatches": 1, "totalPositions": 10, "totalNodes": 1000}"#,
+            r#"{"batches": 1, "positions": 10, "nodes": 1000, "cores": 4}"#,
+        ] {
+            let path = dir.path().join("stats");
+            fs::write(&path, legacy).unwrap();
+            let loaded = StatsFile::load_from(&mut File::open(&path).unwrap(), None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(loaded.stats, stats(1, 10, 1000), "{legacy}");
+        }
+
+        // Saved with the current names.
+        let json = String::from_utf8(
+            StatsFile {
+                stats: stats(1, 10, 1000),
+                ..StatsFile::default()
+            }
+            .to_bytes(None)
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(json.contains("\"total_batches\""));
+        assert!(!json.contains("totalBatches"));
+    }
+
+    #[test]
+    fn test_load_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats");
+        assert_eq!(
+            Stats::load_path(&path, None).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        // Caught in the middle of a rewrite, completed before the second
+        // attempt.
+        fs::write(&path, br#"{"total_batches": 1, "#).unwrap();
+        let mut waits = 0;
+        let loaded = Stats::load_path_with(&path, None, || {
+            waits += 1;
+            fs::write(
+                &path,
+                br#"{"total_batches": 1, "total_positions": 10, "total_nodes": 1000}"#,
+            )
+            .unwrap();
+        });
+        assert_eq!(loaded.unwrap(), stats(1, 10, 1000));
+        assert_eq!(waits, 1);
+
+        fs::write(&path, br#"{"total_batches": 1, "#).unwrap();
+        let mut waits = 0;
+        assert_eq!(
+            Stats::load_path_with(&path, None, || waits += 1)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(waits, LOAD_ATTEMPTS - 1);
+
+        fs::write(&path, b"").unwrap();
+        assert_eq!(
+            Stats::load_path_with(&path, None, || ()).unwrap(),
+            Stats::default()
+        );
+
+        // Encrypted files need the key.
+        let mut contents = ENCRYPTED_HEADER.to_vec();
+        contents.extend_from_slice(&[42; 64]);
+        fs::write(&path, &contents).unwrap();
+        assert_eq!(
+            Stats::load_path_with(&path, None, || ())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_check_cores() {
+        let n = |n| NonZeroUsize::new(n).unwrap();
+        assert_eq!(check_cores(n(8), Some(n(8))), None);
+        assert_eq!(check_cores(n(4), Some(n(8))), None);
+        assert!(check_cores(n(9), Some(n(8)))
+            .unwrap()
+            .contains("only 8 logical cores"));
+        assert_eq!(check_cores(n(64), None), None);
+    }
+
+    #[test]
+    fn test_cache_hit_ratio() {
+        assert_eq!(Stats::default().cache_hit_ratio(), None);
+        assert_eq!(stats(1, 10, 1000).cache_hit_ratio(), Some(0.0));
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        recorder.record_cached(3);
+        assert_eq!(recorder.totals().cache_hit_ratio(), Some(1.0));
+        recorder.record_cached(1);
+        recorder.record_batch(12, 1000, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.totals().total_positions_cached, 4);
+        assert_eq!(recorder.totals().cache_hit_ratio(), Some(0.25));
+        assert_eq!(recorder.session().total_positions_cached, 4);
+
+        // Missing in files of older versions.
+        let file: StatsFile = serde_json::from_str(
+            r#"{"total_batches": 1, "total_positions": 10, "total_nodes": 1000}"#,
+        )
+        .unwrap();
+        assert_eq!(file.stats.total_positions_cached, 0);
+    }
+
+    #[test]
+    fn test_duplicate_ratio() {
+        assert_eq!(Stats::default().duplicate_ratio(), None);
+        // Not recorded by older versions.
+        assert_eq!(stats(1, 10, 1000).duplicate_ratio(), None);
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        recorder.record_unique(10);
+        recorder.record_batch(10, 1000, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.totals().duplicate_ratio(), Some(0.0));
+        recorder.record_unique(6);
+        recorder.record_batch(10, 1000, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.totals().total_unique_positions, 16);
+        assert_eq!(recorder.session().total_unique_positions, 16);
+        assert_eq!(recorder.totals().duplicate_ratio(), Some(0.2));
+
+        // Never more unique than analysed positions.
+        let skewed = Stats {
+            total_unique_positions: 20,
+            ..stats(1, 10, 1000)
+        };
+        assert_eq!(skewed.duplicate_ratio(), Some(0.0));
+
+        let diff = recorder.totals().diff(&Stats {
+            total_unique_positions: 10,
+            ..stats(1, 10, 1000)
+        });
+        assert_eq!(diff.total_unique_positions, 6);
+
+        // Totals of older versions have no unique positions, the session
+        // does.
+        let mut upgraded = StatsRecorder::with_sinks(
+            stats(100, 990, 99_000),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        upgraded.record_unique(10);
+        upgraded.record_batch(10, 1000, None, Duration::from_secs(1), false);
+        assert_eq!(upgraded.totals().duplicate_ratio(), Some(0.99));
+        assert_eq!(upgraded.session().duplicate_ratio(), Some(0.0));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        for secs in [0, 1, 1_700_000_000, -1, -86_400] {
+            assert_eq!(at(secs).to_unix_secs(), secs);
+        }
+
+        // Rounded down, also before the epoch.
+        let half = Duration::from_millis(500);
+        assert_eq!(Timestamp::from(UNIX_EPOCH + half).to_unix_secs(), 0);
+        assert_eq!(Timestamp::from(UNIX_EPOCH - half).to_unix_secs(), -1);
+        assert_eq!(Timestamp::from(UNIX_EPOCH - 3 * half).to_unix_secs(), -2);
+        assert_eq!(at(10) - Duration::from_secs(20), at(-10));
+
+        // Stored as seconds, even if the clock was set before the epoch.
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(-100), &stats(1, 10, 1_000), None).unwrap();
+        let stored: i64 = db
+            .conn
+            .query_row("SELECT timestamp FROM stats", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, -100);
+        assert_eq!(db.latest().unwrap().unwrap().timestamp, at(-100));
+        assert_eq!(serde_json::to_string(&at(-100)).unwrap(), "-100");
+    }
+
+    #[test]
+    fn test_thread_safety() {
+        fn assert_send<T: Send>() {}
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        // Moves with its owner, e.g. across awaits in spawned tasks.
+        assert_send::<StatsRecorder>();
+        assert_send::<StatsDb>();
+        // Received on other threads.
+        assert_send_sync::<StatsSnapshot>();
+        assert_send_sync::<StatsEvent>();
+        assert_send::<watch::Receiver<StatsSnapshot>>();
+        assert_send::<broadcast::Receiver<StatsEvent>>();
+    }
+
+    #[test]
+    fn test_millisecond_timestamps() {
+        for millis in [0, 1, 1_700_000_000_123] {
+            let time = UNIX_EPOCH + Duration::from_millis(millis);
+            assert_eq!(Timestamp::from(time).to_unix_millis(), millis as i64);
+        }
+        let before = Timestamp::from(UNIX_EPOCH - Duration::from_millis(1_500));
+        assert_eq!(before.to_unix_millis(), -1_500);
+        assert_eq!(before.to_unix_secs(), -2);
+        let before = Timestamp::from(UNIX_EPOCH - Duration::from_micros(1_500));
+        assert_eq!(before.to_unix_millis(), -2);
+
+        let db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new_unknown(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            Some(db),
+        );
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        recorder.set_clock(clock.clone());
+        for _ in 0..3 {
+            recorder.record_batch(10, 1_000, None, Duration::from_millis(300), false);
+            clock.advance(Duration::from_millis(300));
+        }
+
+        // Same second, but still distinct and in order.
+        let rows = recorder.db.as_ref().unwrap().recent(3).unwrap();
+        assert!(rows.iter().all(|row| row.timestamp == at(1_000)));
+        assert_eq!(
+            rows.iter().map(|row| row.timestamp_ms).collect::<Vec<_>>(),
+            [Some(1_000_000), Some(1_000_300), Some(1_000_600)]
+        );
+    }
+
+    #[test]
+    fn test_window_report() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), Some(500_000))
+            .unwrap();
+        db.insert_at(at(200), &stats(2, 30, 3_000), Some(700_000))
+            .unwrap();
+        db.insert_at(at(300), &stats(3, 60, 6_000), None).unwrap();
+        db.insert_at(at(400), &stats(4, 100, 10_000), Some(900_000))
+            .unwrap();
+
+        assert_eq!(
+            db.window_report(at(150), at(300)).unwrap(),
+            Some(WindowReport {
+                start: at(150),
+                end: at(300),
+                batches: 2,
+                positions: 50,
+                nodes: 5_000,
+                avg_nps: Some(700_000),
+                peak_nps: Some(700_000),
+                max_temp_c: None,
+            })
+        );
+        assert_eq!(
+            db.window_report(at(0), at(100)).unwrap().unwrap().nodes,
+            1_000
+        );
+        assert_eq!(db.window_report(at(500), at(600)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.insert_at(at(200), &stats(2, 30, 3_000), None).unwrap();
+        db.insert_at(at(400), &stats(4, 100, 9_000), None).unwrap();
+
+        assert_eq!(
+            db.rate(at(400), Duration::from_secs(200)).unwrap(),
+            RateReport {
+                window_secs: 200,
+                nodes_per_sec: 40.0,
+                positions_per_sec: 0.45,
+            }
+        );
+
+        // Idle.
+        let idle = db.rate(at(1_000), Duration::from_secs(300)).unwrap();
+        assert_eq!(idle.nodes_per_sec, 0.0);
+        assert_eq!(idle.positions_per_sec, 0.0);
+
+        // History too short.
+        let short = db.rate(at(400), Duration::from_secs(300)).unwrap();
+        assert_eq!(short.window_secs, 300);
+        assert_eq!(short.nodes_per_sec, 0.0);
+    }
+
+    #[test]
+    fn test_busiest_period() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        assert_eq!(db.busiest_period(Granularity::Hour).unwrap(), None);
+
+        // Hour 0: 1_000 nodes, hour 1: 5_000 nodes, hour 2: 2_000 nodes,
+        // day 1: 4_000 nodes.
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.insert_at(at(3_700), &stats(2, 20, 3_000), None).unwrap();
+        db.insert_at(at(7_000), &stats(3, 30, 6_000), None).unwrap();
+        db.insert_at(at(7_300), &stats(4, 40, 8_000), None).unwrap();
+        db.insert_at(at(86_400 + 60), &stats(5, 50, 12_000), None)
+            .unwrap();
+
+        assert_eq!(
+            db.busiest_period(Granularity::Hour).unwrap(),
+            Some((at(3_600), stats(2, 20, 5_000)))
+        );
+        assert_eq!(
+            db.busiest_period(Granularity::Day).unwrap(),
+            Some((at(0), stats(4, 40, 8_000)))
+        );
+    }
+
+    #[test]
+    fn test_comparison() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), Some(500_000))
+            .unwrap();
+        db.insert_at(at(3_000), &stats(2, 30, 3_000), Some(500_000))
+            .unwrap();
+        db.insert_at(at(3_700), &stats(4, 90, 9_000), Some(600_000))
+            .unwrap();
+        db.insert_at(at(5_000), &stats(5, 100, 10_000), None)
+            .unwrap();
+
+        let from: TimeRange = "0..3599".parse().unwrap();
+        let to: TimeRange = "3600..5399".parse().unwrap();
+        let before = db.window_report(from.start, from.end).unwrap().unwrap();
+        let after = db.window_report(to.start, to.end).unwrap().unwrap();
+        assert_eq!(
+            comparison(&before, &after),
+            &[
+                "batches/h                   2 ->                6   +200.0%",
+                "positions/h                30 ->              140   +366.7%",
+                "nodes/h                  3000 ->            14000   +366.7%",
+                "avg nps                500000 ->           600000    +20.0%",
+            ]
+        );
+
+        let idle = db.window_report(at(5_400), at(8_999)).unwrap();
+        assert_eq!(idle, None);
+
+        db.insert_row(
+            at(5_300),
+            &stats(6, 110, 11_000),
+            None,
+            Some(false),
+            Some(71),
+            None,
+        )
+        .unwrap();
+        db.insert_row(
+            at(5_301),
+            &stats(7, 120, 12_000),
+            None,
+            Some(false),
+            Some(84),
+            None,
+        )
+        .unwrap();
+        let after = db.window_report(to.start, to.end).unwrap().unwrap();
+        assert_eq!(after.max_temp_c, Some(84));
+        assert_eq!(
+            comparison(&before, &after).last().unwrap(),
+            "max temp C                  - ->               84       n/a"
+        );
+        assert_eq!(
+            db.hourly_reports(at(3_600)).unwrap()[0].max_temp_c,
+            Some(84)
+        );
+        assert_eq!(db.recent(1).unwrap()[0].temp_c, Some(84));
+        assert_eq!(percent_change(0.0, 100.0), None);
+        assert_eq!(percent_change(200.0, 100.0), Some(-50.0));
+        assert!("3600..0".parse::<TimeRange>().is_err());
+        assert!("3600".parse::<TimeRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let opt = |file: &Path| {
+            StatsOpt::parse_from([
+                "fishnet",
+                "--stats-file",
+                file.to_str().unwrap(),
+                "--stats-db",
+                dir.path().join("stats.db").to_str().unwrap(),
+                "--no-stats-fallback",
+            ])
+        };
+        let mut recorder = StatsRecorder::with_sinks(
+            stats(1, 10, 1_000),
+            NpsRecorder::new_unknown(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            Some((source.clone(), File::create(&source).unwrap())),
+            None,
+        );
+        recorder.persist(None, None);
+        drop(recorder);
+        let source = source.to_str().unwrap();
+        let client = reqwest::Client::new();
+
+        // No stats file to import into.
+        let missing = dir.path().join("missing").join("stats");
+        assert_eq!(
+            import_stats(opt(&missing), &client, source).await,
+            ExitCode::FAILURE
+        );
+
+        let target = dir.path().join("stats");
+        assert_eq!(
+            import_stats(opt(&target), &client, source).await,
+            ExitCode::SUCCESS
+        );
+        assert_eq!(
+            Stats::load_path(&target, None).unwrap(),
+            stats(1, 10, 1_000)
+        );
+        // Not counted twice.
+        assert_eq!(
+            import_stats(opt(&target), &client, source).await,
+            ExitCode::FAILURE
+        );
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn test_load_from_url() {
+        use std::{
+            io::BufRead as _,
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        fn respond(stream: TcpStream, status: &str, body: &str) {
+            let mut reader = io::BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stats.json", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut incoming = listener.incoming();
+            let mut next = || incoming.next().unwrap().unwrap();
+            respond(
+                next(),
+                "200 OK",
+                r#"{"total_batches": 1, "total_positions": 10, "total_nodes": 1000, "cores": 4}"#,
+            );
+            respond(next(), "404 Not Found", "");
+            respond(next(), "200 OK", "<html>");
+        });
+
+        let client = reqwest::Client::new();
+        assert_eq!(
+            Stats::load_from_url(&client, &url).await.unwrap(),
+            stats(1, 10, 1_000)
+        );
+        assert!(matches!(
+            Stats::load_from_url(&client, &url).await,
+            Err(StatsError::HttpStatus(reqwest::StatusCode::NOT_FOUND))
+        ));
+        assert!(matches!(
+            Stats::load_from_url(&client, &url).await,
+            Err(StatsError::Json(_))
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_default_stats_file() {
+        if let Some(path) = default_stats_file() {
+            assert!(path.ends_with(".fishnet-stats"));
+            assert_eq!(path.parent(), home::home_dir().as_deref());
+        }
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let lookup =
+            |name: &str| (name == "STATS_DIR").then(|| dir.path().to_str().unwrap().to_owned());
+        let path =
+            resolve_path_with(Path::new("${STATS_DIR}/node/stats.json"), false, lookup).unwrap();
+        assert_eq!(path, dir.path().join("node").join("stats.json"));
+        assert!(dir.path().join("node").is_dir());
+
+        let path = resolve_path_with(Path::new("$UNDEFINED/stats.json"), false, lookup);
+        assert_eq!(path.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // Plain paths only with --stats-mkdir.
+        let plain = dir.path().join("new").join("stats.db");
+        assert_eq!(resolve_path(&plain, false).unwrap(), plain);
+        assert!(!dir.path().join("new").exists());
+        assert_ne!(missing_dir_hint(&plain), "");
+        assert_eq!(resolve_path(&plain, true).unwrap(), plain);
+        assert!(dir.path().join("new").is_dir());
+        assert_eq!(missing_dir_hint(&plain), "");
+
+        let blocked = dir.path().join("file");
+        fs::write(&blocked, "").unwrap();
+        let err = resolve_path(&blocked.join("stats.db"), true).unwrap_err();
+        assert!(err.to_string().starts_with("failed to create directory"));
+    }
+
+    #[test]
+    fn test_path_is_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = resolve_path(dir.path(), false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("is a directory"), "{err}");
+
+        let err = open_stats_file(dir.path(), true, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        assert!(StatsDb::open_opt(&StatsOpt::parse_from([
+            "fishnet",
+            "--stats-db",
+            dir.path().to_str().unwrap(),
+        ]))
+        .is_none());
+    }
+
+    #[test]
+    fn test_replace_json_error() {
+        let mut file = tempfile::tempfile().unwrap();
+        replace_json(&mut file, &stats(1, 10, 1_000)).unwrap();
+
+        // Maps with non-string keys cannot be serialized as JSON.
+        let unserializable = BTreeMap::from([((1, 2), 3)]);
+        let err = replace_json(&mut file, &unserializable).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let loaded = StatsFile::load_from(&mut file, None).unwrap().unwrap();
+        assert_eq!(loaded.stats, stats(1, 10, 1_000));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_stats_file() {
+        let key: StatsKey = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff"
+            .parse()
+            .unwrap();
+        let mut file = tempfile::tempfile().unwrap();
+        let saved = StatsFile {
+            stats: stats(1, 10, 1_000),
+            ..StatsFile::default()
+        };
+        saved.save_to(&mut file, Some(&key)).unwrap();
+
+        let mut raw = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut raw).unwrap();
+        assert!(raw.starts_with(ENCRYPTED_HEADER));
+        assert!(serde_json::from_slice::<StatsFile>(&raw).is_err());
+
+        let loaded = StatsFile::load_from(&mut file, Some(&key))
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.stats, stats(1, 10, 1_000));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats");
+        fs::write(&path, &raw).unwrap();
+        assert_eq!(
+            Stats::load_path(&path, Some(&key)).unwrap(),
+            stats(1, 10, 1_000)
+        );
+
+        // Plaintext files are still readable with a key.
+        saved.save_to(&mut file, None).unwrap();
+        let loaded = StatsFile::load_from(&mut file, Some(&key))
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.stats, stats(1, 10, 1_000));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encrypted_stats_file_wrong_key() {
+        let key = StatsKey([1; 32]);
+        let mut file = tempfile::tempfile().unwrap();
+        StatsFile::default().save_to(&mut file, Some(&key)).unwrap();
+
+        for wrong in [None, Some(&StatsKey([2; 32]))] {
+            let err = StatsFile::load_from(&mut file, wrong).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut truncated = tempfile::tempfile().unwrap();
+        truncated.write_all(ENCRYPTED_HEADER).unwrap();
+        let err = StatsFile::load_from(&mut truncated, Some(&key)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_stats_key() {
+        let key: StatsKey = "ff".repeat(32).parse().unwrap();
+        assert_eq!(key, StatsKey([0xff; 32]));
+        assert_eq!(format!("{key:?}"), "StatsKey(..)");
+        assert!("ff".repeat(31).parse::<StatsKey>().is_err());
+        assert!("gg".repeat(32).parse::<StatsKey>().is_err());
+    }
+
+    #[test]
+    fn test_open_with_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats");
+        // Independent of permissions, which are not enforced for root.
+        let open = |p: &Path| {
+            if p == path {
+                Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            } else {
+                Ok(p.to_owned())
+            }
+        };
+
+        let fallback_path = fallback_dir().unwrap().join("fishnet-stats");
+        let (opened_path, opened) =
+            open_with_fallback(&path, "fishnet-stats", true, is_unwritable, open).unwrap();
+        assert_eq!(opened_path, fallback_path);
+        assert_eq!(opened, fallback_path);
+        assert_eq!(
+            open_with_fallback(&path, "fishnet-stats", false, is_unwritable, open)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+
+        let other = dir.path().join("other");
+        assert_eq!(
+            open_with_fallback(&other, "fishnet-stats", true, is_unwritable, open).unwrap(),
+            (other.clone(), other)
+        );
+    }
+
+    #[test]
+    fn test_fallback_dir() {
+        let dir = fallback_dir().unwrap();
+        assert!(dir.starts_with(env::temp_dir()));
+        assert!(dir.is_dir());
+        assert_eq!(fallback_dir().unwrap(), dir);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+    }
+
+    #[test]
+    fn test_lock_stats_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats");
+        fs::write(
+            &path,
+            r#"{"total_batches": 1, "total_positions": 10, "total_nodes": 1000}"#,
+        )
+        .unwrap();
+        let (_, held) = open_stats_file(&path, false, Duration::ZERO).unwrap();
+
+        // Not waiting by default.
+        let err = open_stats_file(&path, false, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // Totals are still shown.
+        let db_path = dir.path().join("stats.db");
+        let recorder = StatsRecorder::new(
+            StatsOpt::parse_from([
+                "fishnet",
+                "--stats-file",
+                path.to_str().unwrap(),
+                "--stats-db",
+                db_path.to_str().unwrap(),
+            ]),
+            Some(NonZeroUsize::MIN),
+        );
+        assert_eq!(recorder.totals(), &stats(1, 10, 1000));
+        assert!(recorder.store.is_none());
+
+        // Timeout.
+        let started = Instant::now();
+        let err = open_stats_file(&path, false, Duration::from_millis(300)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // Released while waiting, for example by a previous instance that
+        // is shutting down.
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(held);
+        });
+        assert!(open_stats_file(&path, false, Duration::from_secs(10)).is_ok());
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn test_migrate_stats_file() {
+        let home = tempfile::tempdir().unwrap();
+        let legacy = home.path().join(".fishnet-stats");
+        let dir = home.path().join(".local").join("state").join("fishnet");
+        fs::write(&legacy, "{}").unwrap();
+
+        let path = migrate_stats_file(&legacy, &dir);
+        assert_eq!(path, dir.join("stats"));
+        assert!(!legacy.exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+
+        // Already migrated. A new legacy file is left alone.
+        fs::write(&legacy, "").unwrap();
+        assert_eq!(migrate_stats_file(&legacy, &dir), path);
+        assert!(legacy.exists());
+    }
+
+    #[test]
+    fn test_moved_stats_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join(KNOWN_LOCATIONS_FILE);
+        let old = dir.path().join("old-home").join(".fishnet-stats");
+        let new = dir.path().join(".fishnet-stats");
+        fs::create_dir(old.parent().unwrap()).unwrap();
+
+        // First run.
+        fs::write(&old, "{}").unwrap();
+        adopt_moved_stats_file(&marker, &old, |_| unreachable!());
+        assert_eq!(read_known_locations(&marker), [old.clone()]);
+
+        // Moved home directory. Unattended.
+        adopt_moved_stats_file(&marker, &new, |_| None);
+        assert!(!new.exists());
+
+        // Moved home directory. Declined.
+        adopt_moved_stats_file(&marker, &new, |previous| {
+            assert_eq!(previous, old);
+            Some(false)
+        });
+        assert!(!new.exists());
+        assert_eq!(read_known_locations(&marker), [old.clone(), new.clone()]);
+
+        // Confirmed.
+        adopt_moved_stats_file(&marker, &new, |_| Some(true));
+        assert_eq!(fs::read_to_string(&new).unwrap(), "{}");
+        assert!(old.exists());
+
+        // Stats at the new location take precedence.
+        assert_eq!(moved_stats_file(&read_known_locations(&marker), &new), None);
+        assert_eq!(moved_stats_file(&[new.clone()], &old), None);
+        fs::write(&old, "").unwrap();
+        assert_eq!(
+            moved_stats_file(&[old.clone(), new.clone()], &old),
+            Some(new)
+        );
+    }
+
+    #[test]
+    fn test_remember_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join(KNOWN_LOCATIONS_FILE);
+        let path = |i: usize| dir.path().join(i.to_string());
+        for i in 0..10 {
+            remember_location(&marker, &path(i)).unwrap();
+        }
+        remember_location(&marker, &path(5)).unwrap();
+        let known = read_known_locations(&marker);
+        assert_eq!(known.len(), MAX_KNOWN_LOCATIONS);
+        assert_eq!(known.first(), Some(&path(2)));
+        assert_eq!(known.last(), Some(&path(5)));
+        assert_eq!(known.iter().filter(|known| **known == path(5)).count(), 1);
+    }
+
+    #[test]
+    fn test_custom_table() {
+        assert!("stats; DROP TABLE stats".parse::<DbTable>().is_err());
+        assert!("1stats".parse::<DbTable>().is_err());
+        assert!("sqlite_master".parse::<DbTable>().is_err());
+        assert!("order".parse::<DbTable>().is_err());
+        assert!("SELECT".parse::<DbTable>().is_err());
+        assert!("orders".parse::<DbTable>().is_ok());
+
+        let mut db = StatsDb::open_in_memory(StatsDbConfig {
+            table: "node_1".parse().unwrap(),
+            ..StatsDbConfig::default()
+        })
+        .unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        assert_eq!(db.latest().unwrap().unwrap().stats, stats(1, 10, 1_000));
+        let count: i64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM node_1", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(db
+            .conn
+            .query_row("SELECT COUNT(*) FROM stats", [], |row| row.get::<_, i64>(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_fast_durability_batches_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        let mut db = StatsDb::open(
+            &path,
+            StatsDbConfig {
+                durability: Durability::Fast,
+                ..StatsDbConfig::default()
+            },
+        )
+        .unwrap();
+        let other = StatsDb::open(&path, StatsDbConfig::default()).unwrap();
+
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        assert!(other.latest().unwrap().is_none());
+        for i in 2..=FAST_COMMIT_ROWS as u64 {
+            db.insert_at(at(100), &stats(i, 10 * i, 1_000 * i), None)
+                .unwrap();
+        }
+        assert_eq!(other.latest().unwrap().unwrap().stats.total_batches, 16);
+
+        db.insert_at(at(100), &stats(17, 170, 17_000), None)
+            .unwrap();
+        drop(db);
+        assert_eq!(other.latest().unwrap().unwrap().stats.total_batches, 17);
+    }
+
+    /// Schemas of the stats table before each migration, by version.
+    const HISTORICAL_SCHEMAS: &[(usize, &str)] = &[
+        (
+            0,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL
+            )",
+        ),
+        (
+            1,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL
+            )",
+        ),
+        (
+            2,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER
+            )",
+        ),
+        (
+            3,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            )",
+        ),
+        (
+            4,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            )",
+        ),
+        (
+            5,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            )",
+        ),
+        (
+            6,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            )",
+        ),
+        (
+            7,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+        ),
+        (
+            8,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT,
+                cold_start INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+        ),
+        (
+            9,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT,
+                cold_start INTEGER,
+                total_positions_cached INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+        ),
+        (
+            10,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT,
+                cold_start INTEGER,
+                total_positions_cached INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS stats_annotations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                label TEXT NOT NULL
+            )",
+        ),
+        (
+            11,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT,
+                cold_start INTEGER,
+                total_positions_cached INTEGER NOT NULL DEFAULT 0,
+                temp_c INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS stats_annotations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                label TEXT NOT NULL
+            )",
+        ),
+        (
+            12,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT,
+                cold_start INTEGER,
+                total_positions_cached INTEGER NOT NULL DEFAULT 0,
+                temp_c INTEGER,
+                sample_output BLOB
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS stats_annotations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                label TEXT NOT NULL
+            )",
+        ),
+        (
+            13,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT,
+                cold_start INTEGER,
+                total_positions_cached INTEGER NOT NULL DEFAULT 0,
+                temp_c INTEGER,
+                sample_output BLOB,
+                total_unique_positions INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS stats_annotations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                label TEXT NOT NULL
+            )",
+        ),
+        (
+            14,
+            "CREATE TABLE IF NOT EXISTS stats (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                nnue_nps INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                tags TEXT,
+                cores INTEGER,
+                version TEXT,
+                cold_start INTEGER,
+                total_positions_cached INTEGER NOT NULL DEFAULT 0,
+                temp_c INTEGER,
+                sample_output BLOB,
+                total_unique_positions INTEGER NOT NULL DEFAULT 0,
+                timestamp_ms INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_rollup (
+                bucket_start INTEGER PRIMARY KEY,
+                bucket_secs INTEGER NOT NULL,
+                row_count INTEGER NOT NULL,
+                total_batches INTEGER NOT NULL,
+                total_positions INTEGER NOT NULL,
+                total_nodes INTEGER NOT NULL,
+                batch_nodes INTEGER,
+                avg_nnue_nps INTEGER,
+                peak_nnue_nps INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS stats_meta (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS stats_annotations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                label TEXT NOT NULL
+            )",
+        ),
+    ];
+
+    fn table_info(conn: &Connection, table: &str) -> Vec<(String, String, bool, Option<String>)> {
+        let mut stmt = conn
+            .prepare(&format!("PRAGMA table_info({table})"))
+            .unwrap();
+        let columns = stmt
+            .query_map([], |row| {
+                Ok((row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        columns
+    }
+
+    #[test]
+    fn test_migrate_historical_schemas() {
+        let current = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        let current_columns = table_info(&current.conn, "stats");
+        let current_rollup_columns = table_info(&current.conn, "stats_rollup");
+
+        assert_eq!(HISTORICAL_SCHEMAS.len(), MIGRATIONS.len());
+        for &(version, schema) in HISTORICAL_SCHEMAS {
+            let conn = Connection::open_in_memory().unwrap();
+            conn.execute_batch(schema).unwrap();
+            if version > 0 {
+                conn.execute_batch(&format!(
+                    "CREATE TABLE fishnet_schema (name TEXT PRIMARY KEY, version INTEGER NOT NULL);
+                     INSERT INTO fishnet_schema VALUES ('stats', {version});"
+                ))
+                .unwrap();
+            }
+            conn.execute(
+                "INSERT INTO stats (timestamp, total_batches, total_positions, total_nodes, nnue_nps)
+                 VALUES (100, 1, 10, 1000, 500000)",
+                [],
+            )
+            .unwrap();
+
+            let db = StatsDb::init(conn, StatsDbConfig::default()).unwrap();
+            assert_eq!(
+                table_info(&db.conn, "stats"),
+                current_columns,
+                "schema after migrating from version {version}"
+            );
+            assert_eq!(table_info(&db.conn, "stats_rollup"), current_rollup_columns);
+            let row = db.latest().unwrap().unwrap();
+            assert_eq!(row.timestamp, at(100));
+            assert_eq!(row.stats, stats(1, 10, 1_000));
+            assert_eq!(row.nnue_nps, Some(500_000));
+        }
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig {
+            tags: tag_map(&[
+                "region=eu".parse().unwrap(),
+                "spot=true".parse().unwrap(),
+                "region=us".parse().unwrap(),
+            ]),
+            ..StatsDbConfig::default()
+        })
+        .unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        let row = db.latest().unwrap().unwrap();
+        assert_eq!(
+            row.tags,
+            BTreeMap::from([
+                ("region".to_owned(), "us".to_owned()),
+                ("spot".to_owned(), "true".to_owned()),
+            ])
+        );
+
+        let mut untagged = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        untagged
+            .insert_at(at(100), &stats(1, 10, 1_000), None)
+            .unwrap();
+        assert!(untagged.latest().unwrap().unwrap().tags.is_empty());
+
+        assert!("region".parse::<StatsTag>().is_err());
+        assert!("1region=eu".parse::<StatsTag>().is_err());
+        assert!("re-gion=eu".parse::<StatsTag>().is_err());
+        assert!(format!("region={}", "x".repeat(65))
+            .parse::<StatsTag>()
+            .is_err());
+        assert!("empty=".parse::<StatsTag>().is_ok());
+    }
+
+    #[test]
+    fn test_note_progress() {
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        let snapshot = recorder.watch_snapshot();
+        recorder.note_progress(100, 0);
+        assert_eq!(snapshot.borrow().batch_progress, None);
+        recorder.note_progress(250, 1_000);
+        assert_eq!(snapshot.borrow().batch_progress, Some(0.25));
+        recorder.note_progress(2_000, 1_000);
+        assert_eq!(snapshot.borrow().batch_progress, Some(1.0));
+
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert_eq!(snapshot.borrow().batch_progress, None);
+        assert_eq!(recorder.totals(), &stats(1, 10, 1_000));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig {
+            snapshot: true,
+            ..StatsDbConfig::default()
+        })
+        .unwrap();
+        for i in 1..=3 * FAST_COMMIT_ROWS as u64 {
+            db.insert_at(
+                at(100 * i as i64),
+                &stats(i, 10 * i, 1_000 * i),
+                Some(500_000),
+            )
+            .unwrap();
+        }
+        let count: u64 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM stats", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        let row = db.latest().unwrap().unwrap();
+        assert_eq!(row.id, 1);
+        assert_eq!(row.timestamp, at(4_800));
+        assert_eq!(row.stats, stats(48, 480, 48_000));
+        assert_eq!(row.batch_nodes, Some(1_000));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        let mut series = StatsDb::open(&path, StatsDbConfig::default()).unwrap();
+        series
+            .insert_at(at(100), &stats(1, 10, 1_000), None)
+            .unwrap();
+        series
+            .insert_at(at(200), &stats(2, 20, 2_000), None)
+            .unwrap();
+        drop(series);
+        assert!(matches!(
+            StatsDb::open(
+                &path,
+                StatsDbConfig {
+                    snapshot: true,
+                    ..StatsDbConfig::default()
+                }
+            ),
+            Err(StatsError::TimeSeries { .. })
+        ));
+    }
+
+    #[test]
+    fn test_try_from_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        drop(StatsDb::open(&path, StatsDbConfig::default()).unwrap());
+        assert!(StatsDb::try_from(Connection::open(&path).unwrap()).is_ok());
+
+        let outdated = Connection::open_in_memory().unwrap();
+        outdated.execute_batch(HISTORICAL_SCHEMAS[0].1).unwrap();
+        let db = StatsDb::try_from(outdated).unwrap();
+        let current = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        assert_eq!(
+            table_info(&db.conn, "stats"),
+            table_info(&current.conn, "stats")
+        );
+
+        let wrong = Connection::open_in_memory().unwrap();
+        wrong
+            .execute_batch("CREATE TABLE stats (id INTEGER PRIMARY KEY, name TEXT)")
+            .unwrap();
+        match StatsDb::try_from(wrong) {
+            Err(StatsError::SchemaMismatch { table, missing }) => {
+                assert_eq!(table, "stats");
+                assert_eq!(
+                    missing,
+                    &[
+                        "timestamp",
+                        "total_batches",
+                        "total_positions",
+                        "total_nodes",
+                        "nnue_nps"
+                    ]
+                );
+            }
+            other => panic!("expected schema mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_rename_column() {
+        for sqlite_version in [SQLITE_RENAME_COLUMN - 1, SQLITE_RENAME_COLUMN] {
+            let mut db = StatsDb::open_in_memory(StatsDbConfig {
+                append_only: true,
+                ..StatsDbConfig::default()
+            })
+            .unwrap();
+            db.conn
+                .execute_batch("CREATE INDEX stats_nps ON stats (nnue_nps)")
+                .unwrap();
+            db.insert_at(at(100), &stats(1, 10, 1_000), Some(500_000))
+                .unwrap();
+            db.insert_at(at(200), &stats(2, 20, 2_000), None).unwrap();
+
+            rename_column(&db.conn, "stats", "nnue_nps", "engine_nps", sqlite_version).unwrap();
+
+            let columns: Vec<String> = table_info(&db.conn, "stats")
+                .into_iter()
+                .map(|(name, ..)| name)
+                .collect();
+            assert!(columns.contains(&"engine_nps".to_owned()));
+            assert!(!columns.contains(&"nnue_nps".to_owned()));
+            let nps: Vec<i64> = db
+                .conn
+                .prepare("SELECT engine_nps FROM stats ORDER BY id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(nps, [500_000, 0], "sqlite {sqlite_version}");
+
+            // Indexes and triggers survive.
+            let index: String = db
+                .conn
+                .query_row(
+                    "SELECT sql FROM sqlite_master WHERE name = 'stats_nps'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert!(index.contains("engine_nps"), "{index}");
+            assert!(db.conn.execute("DELETE FROM stats", []).is_err());
+            db.conn
+                .execute(
+                    "INSERT INTO stats (timestamp, total_batches, total_positions, total_nodes, engine_nps)
+                     VALUES (300, 3, 30, 3000, 0)",
+                    [],
+                )
+                .unwrap();
+            assert_eq!(db.conn.last_insert_rowid(), 3);
+        }
+
+        assert_eq!(
+            rename_identifier("nnue_nps, peak_nnue_nps, \"nnue_nps\"", "nnue_nps", "nps"),
+            "nps, peak_nnue_nps, \"nps\""
+        );
+    }
+
+    #[test]
+    fn test_append_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        let mut db = StatsDb::open(
+            &path,
+            StatsDbConfig {
+                append_only: true,
+                ..StatsDbConfig::default()
+            },
+        )
+        .unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.insert_at(at(200), &stats(2, 20, 2_000), None).unwrap();
+
+        for sql in [
+            "DELETE FROM stats",
+            "UPDATE stats SET total_nodes = 0 WHERE id = 1",
+        ] {
+            let err = db.conn.execute(sql, []).unwrap_err();
+            assert!(err.to_string().contains("append-only"), "{sql}: {err}");
+        }
+        assert!(db
+            .rollup_at(
+                at(8_000),
+                Duration::from_secs(300),
+                Duration::from_secs(3600)
+            )
+            .is_err());
+        assert_eq!(db.recent(10).unwrap().len(), 2);
+
+        // Reopening without append-only mode keeps the triggers.
+        drop(db);
+        let db = StatsDb::open(&path, StatsDbConfig::default()).unwrap();
+        assert!(db.conn.execute("DELETE FROM stats", []).is_err());
+    }
+
+    #[test]
+    fn test_wal_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        let wal = dir.path().join("stats.db-wal");
+        let mut db = StatsDb::open(
+            &path,
+            StatsDbConfig {
+                wal_autocheckpoint: Some(0),
+                truncate_wal: true,
+                ..StatsDbConfig::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            db.conn
+                .query_row("PRAGMA wal_autocheckpoint", [], |row| row.get::<_, i64>(0))
+                .unwrap(),
+            0
+        );
+        for i in 1..=10 {
+            db.insert_at(at(100 * i), &stats(i as u64, 10, 1_000), None)
+                .unwrap();
+        }
+        assert!(fs::metadata(&wal).unwrap().len() > 0);
+
+        assert!(db.truncate_wal);
+        assert!(db.checkpoint().unwrap());
+        assert_eq!(fs::metadata(&wal).unwrap().len(), 0);
+        assert_eq!(db.recent(100).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.insert_at(at(200), &stats(2, 20, 2_000), None).unwrap();
+
+        // Other machine, with an outdated schema.
+        let outdated = Connection::open_in_memory().unwrap();
+        outdated.execute_batch(HISTORICAL_SCHEMAS[0].1).unwrap();
+        outdated
+            .execute_batch(
+                "INSERT INTO stats (timestamp, total_batches, total_positions, total_nodes, nnue_nps)
+                 VALUES (200, 5, 50, 5000, 0), (300, 6, 60, 6000, 400000)",
+            )
+            .unwrap();
+        let mut other = StatsDb::init(
+            outdated,
+            StatsDbConfig {
+                tags: tag_map(&["host=other".parse().unwrap()]),
+                ..StatsDbConfig::default()
+            },
+        )
+        .unwrap();
+        other
+            .insert_at(at(400), &stats(7, 70, 7_000), None)
+            .unwrap();
+
+        assert_eq!(
+            db.merge(&other, "other").unwrap(),
+            MergeCounts {
+                merged: 3,
+                skipped: 0
+            }
+        );
+        let row = db.recent(1).unwrap().pop().unwrap();
+        assert_eq!(row.id, 5);
+        assert_eq!(row.timestamp, at(400));
+        assert_eq!(row.stats, stats(7, 70, 7_000));
+        assert_eq!(row.batch_nodes, Some(1_000));
+        assert_eq!(row.tags["host"], "other");
+        assert_eq!(row.version.as_deref(), Some(VERSION));
+        assert_eq!(row.source.as_deref(), Some("other"));
+
+        // Local totals are not affected.
+        let local = db.latest().unwrap().unwrap();
+        assert_eq!(local.stats, stats(2, 20, 2_000));
+        assert_eq!(local.source, None);
+
+        // Merging again changes nothing.
+        assert_eq!(
+            db.merge(&other, "other").unwrap(),
+            MergeCounts {
+                merged: 0,
+                skipped: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_merge_same_second() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        let mut other = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        other
+            .insert_at(at(100), &stats(1, 10, 1_000), None)
+            .unwrap();
+        other
+            .insert_at(at(100), &stats(2, 20, 2_000), None)
+            .unwrap();
+
+        // Rows of the same second are told apart by their batch count, and
+        // local rows by their source.
+        assert_eq!(
+            db.merge(&other, "other").unwrap(),
+            MergeCounts {
+                merged: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(
+            db.merge(&other, "other").unwrap(),
+            MergeCounts {
+                merged: 0,
+                skipped: 2
+            }
+        );
+        assert_eq!(db.recent(10).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_merge_independent() {
+        // Two machines, each with totals starting from 0.
+        let mut a = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        a.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        a.insert_at(at(200), &stats(3, 30, 3_000), None).unwrap();
+        let mut b = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        b.insert_at(at(150), &stats(1, 10, 500), None).unwrap();
+        b.insert_at(at(250), &stats(2, 20, 1_500), None).unwrap();
+
+        let mut central = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        assert_eq!(central.merge(&a, "a").unwrap().merged, 2);
+        assert_eq!(central.merge(&b, "b").unwrap().merged, 2);
+        assert!(central.latest().unwrap().is_none());
+
+        // Work is computed per source and added up.
+        let report = central.window_report(at(0), at(300)).unwrap().unwrap();
+        assert_eq!(
+            (report.batches, report.positions, report.nodes),
+            (5, 50, 4_500)
+        );
+        let report = central.window_report(at(150), at(300)).unwrap().unwrap();
+        assert_eq!(
+            (report.batches, report.positions, report.nodes),
+            (4, 40, 3_500)
+        );
+        let hourly = central.hourly_reports(at(0)).unwrap();
+        assert_eq!(hourly.len(), 1);
+        assert_eq!(hourly[0].nodes, 4_500);
+        assert_eq!(
+            central.busiest_period(Granularity::Hour).unwrap(),
+            Some((at(0), stats(5, 50, 4_500)))
+        );
+        let versions = central.version_reports().unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!((versions[0].positions, versions[0].nodes), (30, 3_000));
+
+        // A row recorded locally starts its own series.
+        central
+            .insert_at(at(300), &stats(1, 10, 100), None)
+            .unwrap();
+        assert_eq!(central.latest().unwrap().unwrap().batch_nodes, None);
+    }
+
+    #[test]
+    fn test_open_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("other.db");
+        let outdated = Connection::open(&path).unwrap();
+        outdated.execute_batch(HISTORICAL_SCHEMAS[0].1).unwrap();
+        drop(outdated);
+        let before = fs::read(&path).unwrap();
+
+        let copy_dir = tempfile::tempdir().unwrap();
+        let copy = StatsDb::open_copy(&path, copy_dir.path(), StatsDbConfig::default()).unwrap();
+        assert!(copy.latest().unwrap().is_none());
+        assert_eq!(fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn test_version_reports() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.conn
+            .execute_batch(
+                "INSERT INTO stats (timestamp, total_batches, total_positions, total_nodes, nnue_nps)
+                 VALUES (100, 1, 10, 1000, 0), (200, 2, 30, 3000, 500000)",
+            )
+            .unwrap();
+        db.insert_at(at(300), &stats(3, 60, 6_000), Some(700_000))
+            .unwrap();
+        db.insert_at(at(400), &stats(4, 100, 10_000), Some(900_000))
+            .unwrap();
+
+        assert_eq!(
+            db.version_reports().unwrap(),
+            &[
+                VersionReport {
+                    version: None,
+                    batches: 2,
+                    positions: 20,
+                    nodes: 2_000,
+                    avg_nps: Some(500_000),
+                },
+                VersionReport {
+                    version: Some(VERSION.to_owned()),
+                    batches: 2,
+                    positions: 70,
+                    nodes: 7_000,
+                    avg_nps: Some(800_000),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_node_unit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        let kilonodes = || StatsDbConfig {
+            node_unit: NodeUnit::Kilonodes,
+            ..StatsDbConfig::default()
+        };
+        let mut db = StatsDb::open(&path, kilonodes()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_500), None).unwrap();
+        db.insert_at(at(200), &stats(2, 20, 4_000_000), None)
+            .unwrap();
+        let row = db.latest().unwrap().unwrap();
+        assert_eq!(row.stats, stats(2, 20, 4_000));
+        assert_eq!(row.batch_nodes, Some(3_999));
+        drop(db);
+
+        assert!(StatsDb::open(&path, kilonodes()).is_ok());
+        assert!(matches!(
+            StatsDb::open(&path, StatsDbConfig::default()),
+            Err(StatsError::NodeUnit { recorded, requested: NodeUnit::Nodes, .. }) if recorded == "kilonodes"
+        ));
+
+        // Existing rows are in nodes.
+        let outdated = Connection::open_in_memory().unwrap();
+        outdated.execute_batch(HISTORICAL_SCHEMAS[0].1).unwrap();
+        outdated
+            .execute_batch(
+                "INSERT INTO stats (timestamp, total_batches, total_positions, total_nodes, nnue_nps)
+                 VALUES (100, 1, 10, 1000, 0)",
+            )
+            .unwrap();
+        assert!(matches!(
+            StatsDb::init(outdated, kilonodes()),
+            Err(StatsError::NodeUnit { .. })
+        ));
+    }
+
+    #[test]
+    fn test_node_unit_reports() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig {
+            node_unit: NodeUnit::Kilonodes,
+            ..StatsDbConfig::default()
+        })
+        .unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000_000), None)
+            .unwrap();
+        db.insert_at(at(200), &stats(3, 30, 4_000_000), None)
+            .unwrap();
+        db.insert_at(at(3_700), &stats(4, 40, 5_000_000), None)
+            .unwrap();
+
+        // Reports are in nodes, not in the stored unit.
+        assert_eq!(
+            db.window_report(at(150), at(300)).unwrap().unwrap().nodes,
+            3_000_000
+        );
+        let hourly = db.hourly_reports(at(0)).unwrap();
+        assert_eq!(
+            hourly.iter().map(|report| report.nodes).collect::<Vec<_>>(),
+            [4_000_000, 1_000_000]
+        );
+        assert_eq!(db.version_reports().unwrap()[0].nodes, 4_000_000);
+        assert_eq!(
+            db.busiest_period(Granularity::Hour).unwrap(),
+            Some((at(0), stats(3, 30, 4_000_000)))
+        );
+        assert_eq!(
+            db.rate(at(300), Duration::from_secs(150))
+                .unwrap()
+                .nodes_per_sec,
+            20_000.0
+        );
+    }
+
+    #[test]
+    fn test_backfill() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.insert_at(at(200), &stats(2, 30, 3_000), None).unwrap();
+        assert_eq!(db.latest().unwrap().unwrap().batch_nodes, Some(2_000));
+        db.conn
+            .execute("UPDATE stats SET batch_nodes = NULL", [])
+            .unwrap();
+        db.insert_at(at(300), &stats(3, 60, 6_000), None).unwrap();
+
+        for _ in 0..2 {
+            let mut reported = Vec::new();
+            assert_eq!(
+                db.backfill(|done, total| reported.push((done, total)))
+                    .unwrap(),
+                3
+            );
+            assert_eq!(reported, &[(3, 3)]);
+            let batch_nodes = db
+                .conn
+                .prepare("SELECT batch_nodes FROM stats ORDER BY id")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<Vec<Option<u64>>>>()
+                .unwrap();
+            assert_eq!(batch_nodes, &[None, Some(2_000), Some(3_000)]);
+        }
+    }
+
+    #[test]
+    fn test_hourly_reports() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(3_000), &stats(1, 10, 1_000), Some(500_000))
+            .unwrap();
+        db.insert_at(at(3_700), &stats(2, 30, 3_000), Some(600_000))
+            .unwrap();
+        db.insert_at(at(4_000), &stats(3, 60, 6_000), Some(800_000))
+            .unwrap();
+        db.insert_at(at(11_000), &stats(4, 100, 10_000), None)
+            .unwrap();
+
+        let reports = db.hourly_reports(at(3_600)).unwrap();
+        assert_eq!(
+            reports,
+            &[
+                WindowReport {
+                    start: at(3_600),
+                    end: at(7_199),
+                    batches: 2,
+                    positions: 50,
+                    nodes: 5_000,
+                    avg_nps: Some(700_000),
+                    peak_nps: Some(800_000),
+                    max_temp_c: None,
+                },
+                WindowReport {
+                    start: at(10_800),
+                    end: at(14_399),
+                    batches: 1,
+                    positions: 40,
+                    nodes: 4_000,
+                    avg_nps: None,
+                    peak_nps: None,
+                    max_temp_c: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rollup() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), Some(500_000))
+            .unwrap();
+        db.insert_at(at(200), &stats(2, 30, 3_000), Some(700_000))
+            .unwrap();
+        db.insert_at(at(3_700), &stats(3, 60, 6_000), None).unwrap();
+        db.insert_at(at(7_300), &stats(4, 100, 10_000), Some(900_000))
+            .unwrap();
+        db.insert_at(at(9_000), &stats(5, 150, 15_000), Some(800_000))
+            .unwrap();
+        let before = db.window_report(at(0), at(7_199)).unwrap().unwrap();
+
+        // Cutoff at 7_200, the start of the incomplete bucket.
+        let hour = Duration::from_secs(3600);
+        assert_eq!(
+            db.rollup_at(at(8_000), Duration::from_secs(300), hour)
+                .unwrap(),
+            3
+        );
+        assert_eq!(
+            db.rollups().unwrap(),
+            &[
+                RollupRow {
+                    bucket_start: 0,
+                    bucket_secs: 3600,
+                    row_count: 2,
+                    stats: stats(2, 30, 3_000),
+                    batch_nodes: Some(2_000),
+                    avg_nnue_nps: Some(600_000),
+                    peak_nnue_nps: Some(700_000),
+                },
+                RollupRow {
+                    bucket_start: 3_600,
+                    bucket_secs: 3600,
+                    row_count: 1,
+                    stats: stats(3, 60, 6_000),
+                    batch_nodes: Some(3_000),
+                    avg_nnue_nps: None,
+                    peak_nnue_nps: None,
+                },
+            ]
+        );
+        let rolled_up_nodes: u64 = db
+            .rollups()
+            .unwrap()
+            .iter()
+            .filter_map(|r| r.batch_nodes)
+            .sum();
+        assert_eq!(rolled_up_nodes + 1_000, before.nodes); // first row has no delta
+
+        // Newer rows are kept, and reports still find the previous totals.
+        assert_eq!(
+            db.window_report(at(7_200), at(9_000))
+                .unwrap()
+                .unwrap()
+                .nodes,
+            9_000
+        );
+
+        // Nothing left to roll up.
+        assert_eq!(
+            db.rollup_at(at(8_000), Duration::from_secs(300), hour)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_latest() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        assert!(db.latest().unwrap().is_none());
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        let row = db.latest().unwrap().unwrap();
+        assert_eq!(row.timestamp, at(100));
+        assert_eq!(row.stats, stats(1, 10, 1_000));
+        assert_eq!(row.nnue_nps, None);
+    }
+
+    #[test]
+    fn test_capture_output() {
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+        );
+        // Disabled by default.
+        assert!(!recorder.sample_output());
+        recorder.capture_output = 1.0;
+        assert!(recorder.sample_output());
+
+        let output = "info depth 1 nodes 20 nps 400000 score cp 30 pv e2e4\nbestmove e2e4\n";
+        recorder.record_output(output);
+        recorder.record_batch(1, 20, None, Duration::from_secs(1), false);
+        recorder.record_batch(1, 20, None, Duration::from_secs(1), false);
+
+        let db = recorder.db.as_ref().unwrap();
+        let (rows, _) = db.rows_since(0).unwrap();
+        assert_eq!(
+            db.sample_output(rows[0].id).unwrap().as_deref(),
+            Some(output)
+        );
+        assert_eq!(db.sample_output(rows[1].id).unwrap(), None);
+
+        // Truncated before compression.
+        recorder.record_output(&"é".repeat(MAX_SAMPLE_OUTPUT));
+        recorder.record_batch(1, 20, None, Duration::from_secs(1), false);
+        let db = recorder.db.as_ref().unwrap();
+        let latest = db.latest().unwrap().unwrap();
+        let stored = db.sample_output(latest.id).unwrap().unwrap();
+        assert_eq!(stored.len(), MAX_SAMPLE_OUTPUT);
+    }
+
+    #[test]
+    fn test_rows_since() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        assert_eq!(db.rows_since(0).unwrap().1, 0);
+
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.insert_at(at(200), &stats(2, 20, 2_000), None).unwrap();
+        let (rows, last_id) = db.rows_since(0).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(last_id, rows[1].id);
+
+        // Nothing new.
+        let (rows, same_id) = db.rows_since(last_id).unwrap();
+        assert!(rows.is_empty());
+        assert_eq!(same_id, last_id);
+
+        db.insert_at(at(300), &stats(3, 30, 3_000), None).unwrap();
+        let (rows, next_id) = db.rows_since(last_id).unwrap();
+        assert_eq!(
+            rows.iter().map(|row| row.timestamp).collect::<Vec<_>>(),
+            [at(300)]
+        );
+        assert!(next_id > last_id);
+    }
+
+    #[test]
+    fn test_filtered_rows() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.tags = Some(r#"{"node":"a","region":"eu"}"#.to_owned());
+        db.insert_at(at(200), &stats(2, 20, 2_000), None).unwrap();
+        db.insert_at(at(300), &stats(3, 30, 3_000), Some(500_000))
+            .unwrap();
+        db.tags = Some(r#"{"node":"b","region":"eu"}"#.to_owned());
+        db.insert_at(at(400), &stats(4, 40, 4_000), None).unwrap();
+
+        let tags = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+                .collect()
+        };
+        let timestamps = |filter: RowFilter| -> Vec<Timestamp> {
+            db.filtered_rows(&filter)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.timestamp)
+                .collect()
+        };
+        assert_eq!(timestamps(RowFilter::default()).len(), 4);
+        assert_eq!(
+            timestamps(RowFilter {
+                tags: tags(&[("node", "a")]),
+                ..RowFilter::default()
+            }),
+            [at(200), at(300)]
+        );
+        assert_eq!(
+            timestamps(RowFilter {
+                since: Some(at(300)),
+                tags: tags(&[("region", "eu")]),
+                ..RowFilter::default()
+            }),
+            [at(300), at(400)]
+        );
+        assert_eq!(
+            timestamps(RowFilter {
+                until: Some(at(200)),
+                ..RowFilter::default()
+            }),
+            [at(100), at(200)]
+        );
+        assert!(timestamps(RowFilter {
+            tags: tags(&[("node", "a"), ("region", "us")]),
+            ..RowFilter::default()
+        })
+        .is_empty());
+
+        let rows = db
+            .filtered_rows(&RowFilter {
+                since: Some(at(300)),
+                until: Some(at(300)),
+                ..RowFilter::default()
+            })
+            .unwrap();
+        let mut csv = Vec::new();
+        write_rows(&mut csv, ExportFormat::Csv, ',', &rows).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            format!(
+                r#"3,300,300000,3,30,3000,0,0,500000,1000,,{VERSION},,,"{{""node"":""a"",""region"":""eu""}}""#
+            )
+        );
+
+        // Localized spreadsheet apps.
+        let mut csv = Vec::new();
+        write_rows(&mut csv, ExportFormat::Csv, ';', &rows).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("id;timestamp;timestamp_ms;total_batches;"));
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            format!(
+                r#"3;300;300000;3;30;3000;0;0;500000;1000;;{VERSION};;;{{"node":"a","region":"eu"}}"#
+            )
+        );
+        assert_eq!(csv_field("a;b", ';'), r#""a;b""#);
+
+        let mut json = Vec::new();
+        write_rows(&mut json, ExportFormat::Json, ',', &rows).unwrap();
+        let row: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(row["timestamp"], 300);
+        assert_eq!(row["tags"]["node"], "a");
+    }
+
+    #[test]
+    fn test_recent() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        assert!(db.recent(10).unwrap().is_empty());
+        for i in 1..=5 {
+            db.insert_at(at(100 * i), &stats(i as u64, 10, 1_000), None)
+                .unwrap();
+        }
+        let timestamps: Vec<_> = db
+            .recent(3)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.timestamp)
+            .collect();
+        assert_eq!(timestamps, [at(300), at(400), at(500)]);
+        assert_eq!(db.recent(10).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.annotate(at(1_000), "started benchmark").unwrap();
+        db.insert_at(at(1_030), &stats(1, 10, 1_000), None).unwrap();
+        db.annotate(at(1_060), "hardware replaced").unwrap();
+
+        let annotations = db.annotations(at(1_000)).unwrap();
+        assert_eq!(
+            annotations,
+            [
+                Annotation {
+                    timestamp: at(1_000),
+                    label: "started benchmark".to_owned(),
+                },
+                Annotation {
+                    timestamp: at(1_060),
+                    label: "hardware replaced".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(db.annotations(at(1_001)).unwrap().len(), 1);
+        // Not mixed up with the time series.
+        assert_eq!(db.recent(10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_cold_start() {
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new_unknown(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+        );
+        recorder.record_batch(10, 1_000, Some(100_000), Duration::from_secs(40), true);
+        assert_eq!(recorder.totals(), &stats(1, 10, 1_000));
+        assert_eq!(recorder.nnue_nps.default_engine().estimate(), None);
+        assert_eq!(recorder.batch_durations().total(), 0);
+
+        recorder.record_batch(10, 1_000, Some(500_000), Duration::from_secs(1), false);
+        assert_eq!(recorder.nnue_nps.default_engine().estimate(), Some(500_000));
+        assert_eq!(recorder.batch_durations().batches_under_5s, 1);
+
+        let db = recorder.db.as_ref().unwrap();
+        let cold_start: Vec<_> = db
+            .recent(10)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.cold_start)
+            .collect();
+        assert_eq!(cold_start, [Some(true), Some(false)]);
+    }
+
+    #[test]
+    fn test_peak_nps() {
+        let start = Instant::now();
+        let mut nps = NpsRecorder::new();
+        assert_eq!(nps.peak_nps_at(start), None);
+
+        nps.record_at(start, 900_000, BATCH_NODES);
+        nps.record_at(start + Duration::from_secs(60), 500_000, BATCH_NODES);
+        nps.record_at(start + Duration::from_secs(120), 700_000, BATCH_NODES);
+        assert_eq!(
+            nps.peak_nps_at(start + Duration::from_secs(120)),
+            Some(900_000)
+        );
+        assert_eq!(nps.peak_window.len(), 2);
+
+        // The spike expires, the best of the remaining samples takes over.
+        assert_eq!(
+            nps.peak_nps_at(start + Duration::from_secs(11 * 60)),
+            Some(700_000)
+        );
+        assert_eq!(nps.peak_nps_at(start + Duration::from_secs(30 * 60)), None);
+
+        for i in 0..2 * PEAK_WINDOW_CAPACITY as u32 {
+            nps.record_at(start + Duration::from_secs(180), 1_000_000 - i, BATCH_NODES);
+        }
+        assert_eq!(nps.peak_window.len(), PEAK_WINDOW_CAPACITY);
+    }
+
+    #[test]
+    fn test_per_core() {
+        assert_eq!(cores_u64(NonZeroUsize::MIN), 1);
+        assert_eq!(
+            cores_u64(NonZeroUsize::new(usize::MAX).unwrap()),
+            usize::MAX as u64
+        );
+        assert_eq!(per_core(1_000_000.0, NonZeroUsize::MIN), 1_000_000.0);
+        assert_eq!(
+            per_core(1_000_000.0, NonZeroUsize::new(4).unwrap()),
+            250_000.0
+        );
+        assert!(per_core(1_000_000.0, NonZeroUsize::new(usize::MAX).unwrap()) > 0.0);
+    }
+
+    #[test]
+    fn test_round_nodes() {
+        assert_eq!(
+            round_nodes(&stats(1, 10, 1_234_567), 1),
+            stats(1, 10, 1_234_567)
+        );
+        assert_eq!(
+            round_nodes(&stats(1, 10, 1_234_567), 1_000_000),
+            stats(1, 10, 1_000_000)
+        );
+        assert_eq!(
+            round_nodes(&stats(1, 10, 1_500_000), 1_000_000),
+            stats(1, 10, 2_000_000)
+        );
+        assert_eq!(round_nodes(&stats(1, 10, 400), 1_000), stats(1, 10, 0));
+        assert_eq!(
+            round_nodes(&stats(1, 10, u64::MAX), 1_000).total_nodes % 1_000,
+            0
+        );
+
+        // Exact in memory and in the database.
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+        );
+        recorder.node_precision = 1_000_000;
+        recorder.record_batch(10, 1_234_567, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.totals().total_nodes, 1_234_567);
+        let row = recorder.db.as_ref().unwrap().latest().unwrap().unwrap();
+        assert_eq!(row.stats.total_nodes, 1_234_567);
+        assert_eq!(row.batch_nodes, Some(1_234_567));
+    }
+
+    #[test]
+    fn test_prefer_db() {
+        assert!(prefer_db(&Stats::default(), true, &stats(3, 30, 3_000)));
+        assert!(!prefer_db(&Stats::default(), false, &stats(3, 30, 3_000)));
+        assert!(!prefer_db(&Stats::default(), true, &Stats::default()));
+        assert!(!prefer_db(&stats(3, 30, 3_000), true, &stats(3, 30, 3_000)));
+    }
+
+    #[test]
+    fn test_keep_encrypted_stats_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("fishnet-stats");
+        let db_path = dir.path().join("stats.db");
+        let mut contents = ENCRYPTED_HEADER.to_vec();
+        contents.extend_from_slice(&[42; 64]);
+        fs::write(&file_path, &contents).unwrap();
+
+        // No key given.
+        let mut recorder = StatsRecorder::new(
+            StatsOpt::parse_from([
+                "fishnet",
+                "--stats-file",
+                file_path.to_str().unwrap(),
+                "--stats-db",
+                db_path.to_str().unwrap(),
+            ]),
+            Some(NonZeroUsize::MIN),
+        );
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        drop(recorder);
+        assert_eq!(fs::read(&file_path).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_seed_from_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("fishnet-stats");
+        let db_path = dir.path().join("stats.db");
+        StatsDb::open(&db_path, StatsDbConfig::default())
+            .unwrap()
+            .insert(&stats(3, 30, 3_000), None)
+            .unwrap();
+        File::create(&file_path).unwrap(); // truncated by accident
+
+        let recorder = StatsRecorder::new(
+            StatsOpt::parse_from([
+                "fishnet",
+                "--stats-file",
+                file_path.to_str().unwrap(),
+                "--stats-db",
+                db_path.to_str().unwrap(),
+            ]),
+            Some(NonZeroUsize::MIN),
+        );
+        assert_eq!(recorder.totals(), &stats(3, 30, 3_000));
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("sub").join("fishnet-stats");
+        let db_path = dir.path().join("stats.db");
+        let opt = StatsOpt::parse_from([
+            "fishnet",
+            "--stats-file",
+            file_path.to_str().unwrap(),
+            "--stats-db",
+            db_path.to_str().unwrap(),
+        ]);
+
+        // Nothing is created.
+        let recorder = StatsRecorder::open_read_only(&opt, NonZeroUsize::MIN);
+        assert!(recorder.totals().is_empty());
+        assert!(recorder.store.is_none() && recorder.db().is_none());
+        drop(recorder);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        fs::create_dir(file_path.parent().unwrap()).unwrap();
+        let mut recorder = StatsRecorder::new(opt.clone(), Some(NonZeroUsize::MIN));
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        drop(recorder);
+        let file = fs::read(&file_path).unwrap();
+        let db = fs::read(&db_path).unwrap();
+
+        // Nothing is written.
+        let recorder = StatsRecorder::open_read_only(&opt, NonZeroUsize::MIN);
+        assert_eq!(recorder.totals(), &stats(1, 10, 1_000));
+        assert!(recorder.store.is_some() && recorder.db().is_some());
+        drop(recorder);
+        assert_eq!(fs::read(&file_path).unwrap(), file);
+        assert_eq!(fs::read(&db_path).unwrap(), db);
+    }
+
+    #[test]
+    fn test_bench_stats() {
+        // Smoke test with the default settings.
+        assert_eq!(
+            bench_stats(StatsOpt::default(), 20, None),
+            ExitCode::SUCCESS
+        );
+    }
+
+    #[test]
+    fn test_contribution_share() {
+        let mut recorder = StatsRecorder::new(
+            StatsOpt::parse_from(["fishnet", "--no-stats-file"]),
+            Some(NonZeroUsize::MIN),
+        );
+        recorder.stats = stats(100, 1_000, 1_000_000); // resumed lifetime totals
+        assert_eq!(recorder.contribution_share(1_000), 0.0);
+
+        recorder.record_batch(10, 250, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.contribution_share(1_000), 0.25);
+        assert_eq!(recorder.contribution_share(100), 1.0);
+        assert_eq!(recorder.contribution_share(0), 0.0);
+        assert_eq!(recorder.totals().total_nodes, 1_000_250);
+    }
+
+    #[test]
+    fn test_batch_durations() {
+        let mut durations = BatchDurations::default();
+        assert_eq!(durations.to_string(), "no batches");
+        for millis in [0, 999, 1_000, 4_999, 5_000, 29_999, 30_000, 3_600_000] {
+            durations.record(Duration::from_millis(millis));
+        }
+        assert_eq!(
+            durations,
+            BatchDurations {
+                batches_under_1s: 2,
+                batches_under_5s: 2,
+                batches_under_30s: 2,
+                batches_from_30s: 2,
+            }
+        );
+        assert_eq!(
+            durations.to_string(),
+            "<1s 25%, 1-5s 25%, 5-30s 25%, 30s+ 25%"
+        );
+
+        let legacy: StatsFile = serde_json::from_str(
+            r#"{"total_batches": 1, "total_positions": 10, "total_nodes": 1000}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.batch_durations, BatchDurations::default());
+    }
+
+    #[test]
+    fn test_changed_cores() {
+        let four = NonZeroUsize::new(4).unwrap();
+        let eight = NonZeroUsize::new(8).unwrap();
+        assert_eq!(changed_cores(Some(4), eight), Some(4));
+        assert_eq!(changed_cores(Some(8), eight), None);
+        assert_eq!(changed_cores(None, eight), None);
+
+        let mut file = tempfile::tempfile().unwrap();
+        StatsFile {
+            stats: stats(1, 10, 1_000),
+            cores: Some(4),
+            batch_durations: BatchDurations::default(),
+        }
+        .save_to(&mut file, None)
+        .unwrap();
+        assert_eq!(
+            StatsFile::load_from(&mut file, None)
+                .unwrap()
+                .unwrap()
+                .cores,
+            Some(4)
+        );
+        file.set_len(0).unwrap();
+        file.rewind().unwrap();
+        file.write_all(br#"{"total_batches": 1, "total_positions": 10, "total_nodes": 1000}"#)
+            .unwrap();
+        let legacy = StatsFile::load_from(&mut file, None).unwrap().unwrap();
+        assert_eq!(legacy.stats, stats(1, 10, 1_000));
+        assert_eq!(legacy.cores, None);
+
+        let mut db = StatsDb::open_in_memory(StatsDbConfig::default()).unwrap();
+        db.insert_at(at(100), &stats(1, 10, 1_000), None).unwrap();
+        db.set_cores(four);
+        db.insert_at(at(200), &stats(2, 20, 2_000), None).unwrap();
+        let row = db.latest().unwrap().unwrap();
+        assert_eq!(changed_cores(row.cores, eight), Some(4));
+    }
+
+    #[test]
+    fn test_drop_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("fishnet-stats");
+        let db_path = dir.path().join("stats.db");
+        let config = StatsDbConfig {
+            durability: Durability::Fast,
+            ..StatsDbConfig::default()
+        };
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            Some((file_path.clone(), File::create(&file_path).unwrap())),
+            Some(StatsDb::open(&db_path, config).unwrap()),
+        );
+        for _ in 0..3 {
+            recorder.record_batch(10, 1_000, Some(500_000), Duration::from_secs(1), false);
+        }
+        drop(recorder);
+
+        let mut file = File::open(&file_path).unwrap();
+        assert_eq!(
+            StatsFile::load_from(&mut file, None)
+                .unwrap()
+                .unwrap()
+                .stats,
+            stats(3, 30, 3_000)
+        );
+        let db = StatsDb::open(&db_path, StatsDbConfig::default()).unwrap();
+        assert_eq!(db.latest().unwrap().unwrap().stats, stats(3, 30, 3_000));
+    }
+
+    #[test]
+    fn test_file_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("fishnet-stats");
+        let db_path = dir.path().join("stats.db");
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            Some((file_path.clone(), File::create(&file_path).unwrap())),
+            Some(StatsDb::open(&db_path, StatsDbConfig::default()).unwrap()),
+        );
+        recorder.defer_file = true;
+        for _ in 0..3 {
+            recorder.record_batch(10, 1_000, Some(500_000), Duration::from_secs(1), false);
+        }
+
+        // Stale mid-run, but the database is up to date.
+        assert_eq!(fs::metadata(&file_path).unwrap().len(), 0);
+        let db = StatsDb::open(&db_path, StatsDbConfig::default()).unwrap();
+        let latest = db.latest().unwrap().unwrap().stats;
+        assert_eq!(latest, stats(3, 30, 3_000));
+
+        drop(recorder);
+        let mut file = File::open(&file_path).unwrap();
+        assert_eq!(
+            StatsFile::load_from(&mut file, None)
+                .unwrap()
+                .unwrap()
+                .stats,
+            latest
+        );
+    }
+
+    #[test]
+    fn test_reopen_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_dir = dir.path().join("briefly-missing");
+        let db_path = db_dir.join("stats.db");
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            None,
+        );
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        recorder.set_clock(clock.clone());
+        recorder.retry_db(db_path.clone(), StatsDbConfig::default());
+
+        // Not retried before the backoff elapsed.
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert!(recorder.db.is_none());
+
+        // Retried, but still failing, so the backoff doubles.
+        clock.advance(DB_REOPEN_BACKOFF_MIN);
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert!(recorder.db.is_none());
+        assert_eq!(
+            recorder.db_reopen.as_ref().unwrap().backoff,
+            2 * DB_REOPEN_BACKOFF_MIN
+        );
+
+        // The transient issue clears.
+        fs::create_dir(&db_dir).unwrap();
+        clock.advance(DB_REOPEN_BACKOFF_MIN);
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert!(recorder.db.is_none());
+        clock.advance(DB_REOPEN_BACKOFF_MIN);
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert!(recorder.db.is_some());
+        assert!(recorder.db_reopen.is_none());
+        assert!(!recorder.db_failing);
+
+        let db = StatsDb::open(&db_path, StatsDbConfig::default()).unwrap();
+        assert_eq!(db.latest().unwrap().unwrap().stats, stats(4, 40, 4_000));
+    }
+
+    #[cfg(feature = "influx")]
+    #[test]
+    fn test_influx_sink() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = url::Url::parse(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::from([("region".to_owned(), "eu".to_owned())]),
+            false,
+            None,
+            None,
+        );
+        recorder.connect_influx(&url, &reqwest::Client::new());
+        recorder.record_batch(10, 1_000, Some(500_000), Duration::from_secs(1), false);
+        let mut buf = [0; 256];
+        let n = server.recv(&mut buf).unwrap();
+        let line = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(line.starts_with(
+            "fishnet,region=eu batches=1i,positions=10i,nodes=1000i,nnue_nps=500000i "
+        ));
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new_unknown(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        recorder.set_clock(clock.clone());
+        let mut events = recorder.subscribe();
+        let threshold = Duration::from_secs(600);
+
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        clock.advance(Duration::from_secs(300));
+        assert!(!recorder.is_idle(threshold));
+        assert_eq!(recorder.check_idle(threshold), None);
+
+        clock.advance(Duration::from_secs(360));
+        assert!(recorder.is_idle(threshold));
+        assert_eq!(
+            recorder.check_idle(threshold),
+            Some(Duration::from_secs(660))
+        );
+        assert_eq!(recorder.snapshot().idle_since, Some(1_000));
+        let idle: Vec<_> = iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, StatsEvent::Idle { .. }))
+            .collect();
+        assert_eq!(
+            idle,
+            [StatsEvent::Idle {
+                since: Duration::from_secs(660)
+            }]
+        );
+
+        // Reported once per idle period.
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(recorder.check_idle(threshold), None);
+
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert!(!recorder.is_idle(threshold));
+        assert_eq!(recorder.snapshot().idle_since, None);
+    }
+
+    #[test]
+    fn test_measured_nps() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::new(2).unwrap(),
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        recorder.set_clock(clock.clone());
+
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.measured_nps.estimate(), None);
+
+        // 4M nodes in 2 seconds on 2 cores.
+        clock.advance(Duration::from_secs(2));
+        recorder.record_batch(10, 4_000_000, None, Duration::from_secs(2), false);
+        assert_eq!(recorder.measured_nps.estimate(), Some(1_000_000));
+        assert_eq!(recorder.snapshot().measured_nps, Some(1_000_000));
+        assert_eq!(recorder.snapshot().nnue_nps, Some(400_000));
+
+        // No time elapsed.
+        recorder.record_batch(10, 4_000_000, None, Duration::from_secs(2), false);
+        assert_eq!(recorder.measured_nps.last(), Some(1_000_000));
+
+        // Cold start batches are not sampled.
+        clock.advance(Duration::from_secs(1));
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), true);
+        assert_eq!(recorder.measured_nps.last(), Some(1_000_000));
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+        );
+        recorder.set_clock(clock.clone());
+
+        recorder.record_batch(10, 1_000, Some(900_000), Duration::from_secs(1), false);
+        clock.advance(Duration::from_secs(60));
+        recorder.record_batch(10, 1_000, Some(500_000), Duration::from_secs(1), false);
+        assert_eq!(recorder.snapshot().peak_nnue_nps, Some(900_000));
+        assert_eq!(recorder.snapshot().timestamp, 1_060);
+        let row = recorder.db().unwrap().latest().unwrap().unwrap();
+        assert_eq!(row.timestamp, at(1_060));
+
+        // Peak expires after 10 minutes.
+        clock.advance(PEAK_WINDOW);
+        assert_eq!(recorder.snapshot().peak_nnue_nps, Some(500_000));
+    }
+
+    #[test]
+    fn test_perf_index() {
+        let mut nps = NpsRecorder::new_unknown();
+        assert_eq!(nps.perf_index(400_000), None);
+        nps.record(500_000, BATCH_NODES);
+        assert_eq!(nps.perf_index(400_000), Some(1.25));
+        assert_eq!(nps.perf_index(0), None);
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new_unknown(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        recorder.record_batch(10, 1_000, Some(300_000), Duration::from_secs(1), false);
+        assert_eq!(recorder.perf_index(), None);
+        recorder.nps_reference = Some(600_000);
+        assert_eq!(recorder.perf_index(), Some(0.5));
+        assert_eq!(recorder.snapshot().perf_index, Some(0.5));
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut recorder = StatsRecorder::with_sinks(
+            stats(5, 50, 5_000),
+            NpsRecorder::new_unknown(),
+            NonZeroUsize::new(4).unwrap(),
+            BTreeMap::new(),
+            true,
+            None,
+            Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+        );
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        recorder.set_clock(clock.clone());
+        let dump = |recorder: &StatsRecorder| {
+            let mut buf = Vec::new();
+            recorder.dump(&mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+
+        let before = dump(&recorder);
+        assert!(before.lines().any(|line| line == "total_batches=5"));
+        assert!(before.lines().any(|line| line == "nnue_nps="));
+
+        recorder.record_batch(10, 1_000, Some(600_000), Duration::from_secs(1), false);
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(
+            format!("{:.3}", recorder.snapshot().nnue_nps_uncertainty),
+            "0.900"
+        );
+        let after = dump(&recorder);
+        assert_eq!(
+            after,
+            "total_batches=6\ntotal_positions=60\ntotal_nodes=6000\n\
+             total_positions_cached=0\ntotal_unique_positions=0\n\
+             nnue_nps=600000\nnnue_nps_uncertainty=0.900\ncores=4\nuptime_secs=90\n\
+             stats_file=false\ndatabase=true\n"
+        );
+        assert!(after
+            .lines()
+            .all(|line| line.split_once('=').is_some_and(|(key, _)| !key.is_empty())));
+    }
+
+    #[test]
+    fn test_stats_tee() {
+        let dir = tempfile::tempdir().unwrap();
+        let tees = [
+            dir.path().join("a"),
+            dir.path().join("missing").join("b"),
+            dir.path().join("c"),
+        ];
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            None,
+        );
+        recorder.tees = tees
+            .iter()
+            .map(|path| (path.clone(), ErrorThrottle::default()))
+            .collect();
+        let mut events = recorder.subscribe();
+
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        for path in [&tees[0], &tees[2]] {
+            let loaded: StatsFile = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+            assert_eq!(loaded.stats, stats(2, 20, 2_000));
+        }
+        assert!(!tees[1].exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(StatsEvent::PersistenceError {
+                sink: StatsSink::File,
+                ..
+            })
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(StatsEvent::BatchRecorded { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sinks_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("fishnet-stats");
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            Some((file_path.clone(), File::create(&file_path).unwrap())),
+            Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+        );
+        for (positions, nodes) in [(10, 1_000), (1, 7), (0, 0), (50, 4_000_000)] {
+            recorder.record_batch(
+                positions,
+                nodes,
+                Some(500_000),
+                Duration::from_secs(1),
+                false,
+            );
+
+            let mut file = File::open(&file_path).unwrap();
+            let file_stats = StatsFile::load_from(&mut file, None)
+                .unwrap()
+                .unwrap()
+                .stats;
+            let db_stats = recorder.db().unwrap().latest().unwrap().unwrap().stats;
+            assert_eq!(&file_stats, recorder.totals());
+            assert_eq!(&db_stats, recorder.totals());
+            assert_eq!(stats_mismatch(&file_stats, &db_stats), None);
+        }
+        assert_eq!(recorder.totals(), &stats(4, 61, 4_001_007));
+        assert_eq!(recorder.session(), recorder.totals());
+    }
+
+    #[test]
+    fn test_stats_mismatch_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("fishnet-stats");
+        let db_path = dir.path().join("stats.db");
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            Some((file_path.clone(), File::create(&file_path).unwrap())),
+            Some(StatsDb::open(&db_path, StatsDbConfig::default()).unwrap()),
+        );
+        for _ in 0..3 {
+            recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        }
+        drop(recorder);
+
+        // Crash after writing the stats file, but before inserting the row.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file_path)
+            .unwrap();
+        let mut loaded = StatsFile::load_from(&mut file, None).unwrap().unwrap();
+        loaded.stats.total_batches += 1;
+        loaded.stats.total_positions += 10;
+        loaded.stats.total_nodes += 1_000;
+        loaded.save_to(&mut file, None).unwrap();
+
+        let loaded = StatsFile::load_from(&mut file, None).unwrap().unwrap();
+        let db = StatsDb::open(&db_path, StatsDbConfig::default()).unwrap();
+        let latest = db.latest().unwrap().unwrap();
+        assert_eq!(
+            stats_mismatch(&loaded.stats, &latest.stats).as_deref(),
+            Some("Stats file (4 batches, 4000 nodes) and database (3 batches, 3000 nodes) disagree. One of them missed writes, for example due to a crash.")
+        );
+    }
+
+    #[test]
+    fn test_disk_space_guard() {
+        let mut guard = DiskSpaceGuard::new(PathBuf::from("stats"), 100 * MB);
+        let start = Instant::now();
+        assert!(guard.is_low(start, |_| Ok(50 * MB)));
+
+        // Not checked again until the interval elapsed.
+        assert!(guard.is_low(start + Duration::from_secs(30), |_| Ok(200 * MB)));
+        let later = start + DISK_SPACE_CHECK_INTERVAL;
+        assert!(!guard.is_low(later, |_| Ok(200 * MB)));
+
+        // Errors keep the previous state.
+        let much_later = later + DISK_SPACE_CHECK_INTERVAL;
+        assert!(!guard.is_low(much_later, |_| Err(io::Error::other("statvfs"))));
+    }
+
+    #[test]
+    fn test_error_throttle() {
+        let mut throttle = ErrorThrottle::default();
+        let start = Instant::now();
+        let lines: Vec<String> = (0..100)
+            .flat_map(|i| {
+                throttle.error(
+                    start + Duration::from_millis(i * 100),
+                    "disk full".to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(lines, &["disk full"]);
+        assert_eq!(
+            throttle.error(start + ERROR_SUMMARY_INTERVAL, "disk full".to_owned()),
+            &["disk full (100 occurrences in the last minute)"]
+        );
+
+        // A different error is logged immediately.
+        let later = start + 2 * ERROR_SUMMARY_INTERVAL;
+        assert_eq!(
+            throttle.error(later, "read-only".to_owned()),
+            &["read-only"]
+        );
+        assert!(throttle.error(later, "read-only".to_owned()).is_empty());
+        assert!(throttle.error(later, "read-only".to_owned()).is_empty());
+        assert_eq!(
+            throttle.error(later, "locked".to_owned()),
+            &["read-only (2 more occurrences)", "locked"]
+        );
+        assert!(throttle.error(later, "locked".to_owned()).is_empty());
+        assert_eq!(
+            throttle.recovered().as_deref(),
+            Some("locked (1 more occurrences)")
+        );
+        assert_eq!(throttle.recovered(), None);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let mut recorder = StatsRecorder::new(
+            StatsOpt::parse_from(["fishnet", "--no-stats-file"]),
+            Some(NonZeroUsize::MIN),
+        );
+        let mut first = recorder.subscribe();
+        recorder.record_batch(10, 1_000, Some(500_000), Duration::from_secs(1), false);
+        let mut second = recorder.subscribe();
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+
+        let batch = |n: u64, nnue_nps| StatsEvent::BatchRecorded {
+            stats: stats(n, 10 * n, 1_000 * n),
+            nnue_nps,
+        };
+        assert_eq!(first.try_recv().unwrap(), batch(1, Some(500_000)));
+        assert_eq!(first.try_recv().unwrap(), batch(2, None));
+        assert_eq!(second.try_recv().unwrap(), batch(2, None));
+        assert!(second.try_recv().is_err());
+
+        // Slow receivers skip the oldest events.
+        for _ in 0..EVENT_CAPACITY + 2 {
+            recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        }
+        assert_eq!(
+            first.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(2))
+        );
+        assert_eq!(first.try_recv().unwrap(), batch(5, None));
+
+        let mut last = recorder.subscribe();
+        drop(recorder);
+        assert_eq!(last.try_recv(), Ok(StatsEvent::Flushed));
+        assert_eq!(last.try_recv(), Err(broadcast::error::TryRecvError::Closed));
+    }
+
+    #[test]
+    fn test_db_disk_space() {
+        let recorder = |db| {
+            StatsRecorder::with_sinks(
+                Stats::default(),
+                NpsRecorder::new(),
+                NonZeroUsize::MIN,
+                BTreeMap::new(),
+                true,
+                None,
+                Some(db),
+            )
+        };
+        let in_memory = recorder(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap());
+        assert_eq!(in_memory.db_disk_space(), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let on_disk =
+            recorder(StatsDb::open(dir.path().join("stats.db"), StatsDbConfig::default()).unwrap());
+        let (available, total) = on_disk.db_disk_space().unwrap();
+        assert!(total > 0);
+        assert!(available <= total);
+    }
+
+    #[test]
+    fn test_persistence_status() {
+        let disabled = StatsRecorder::new(
+            StatsOpt::parse_from(["fishnet", "--no-stats-file"]),
+            Some(NonZeroUsize::MIN),
+        );
+        assert_eq!(disabled.persistence_status(), PersistenceStatus::Disabled);
+
+        let db = || Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap());
+        let file_missing = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            db(),
+        );
+        assert_eq!(
+            file_missing.persistence_status(),
+            PersistenceStatus::Degraded
+        );
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            Some((PathBuf::from("stats"), tempfile::tempfile().unwrap())),
+            db(),
+        );
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.persistence_status(), PersistenceStatus::Healthy);
+        recorder
+            .db
+            .as_ref()
+            .unwrap()
+            .conn
+            .execute_batch("DROP TABLE stats")
+            .unwrap();
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.persistence_status(), PersistenceStatus::Degraded);
+        assert_eq!(
+            recorder.watch_snapshot().borrow().persistence,
+            PersistenceStatus::Degraded
+        );
+    }
+
+    #[test]
+    fn test_unrecoverable_db() {
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            true,
+            None,
+            Some(StatsDb::open_in_memory(StatsDbConfig::default()).unwrap()),
+        );
+        let mut events = recorder.subscribe();
+
+        // Recoverable errors keep the database.
+        let db = recorder.db.as_ref().unwrap();
+        db.conn
+            .execute_batch("ALTER TABLE stats RENAME TO moved")
+            .unwrap();
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert!(recorder.db.is_some());
+        let db = recorder.db.as_ref().unwrap();
+        db.conn
+            .execute_batch("ALTER TABLE moved RENAME TO stats")
+            .unwrap();
+
+        // Read-only database is closed after the first failure.
+        db.conn.pragma_update(None, "query_only", true).unwrap();
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert!(recorder.db.is_none());
+        recorder.record_batch(10, 1_000, None, Duration::from_secs(1), false);
+        assert_eq!(recorder.persistence_status(), PersistenceStatus::Degraded);
+
+        let errors = std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, StatsEvent::PersistenceError { .. }))
+            .count();
+        assert_eq!(errors, 2);
+    }
+
+    #[test]
+    fn test_format_nps_per_core() {
+        assert_eq!(format_nps_per_core(0), "0 nps/core");
+        assert_eq!(format_nps_per_core(847), "847 nps/core");
+        assert_eq!(format_nps_per_core(999), "999 nps/core");
+        assert_eq!(format_nps_per_core(1_000), "1 knps/core");
+        assert_eq!(format_nps_per_core(1_499), "1 knps/core");
+        assert_eq!(format_nps_per_core(1_500), "2 knps/core");
+        assert_eq!(format_nps_per_core(599_600), "600 knps/core");
+        assert_eq!(format_nps_per_core(u32::MAX), "4294967 knps/core");
+    }
+
+    #[test]
+    fn test_unknown_nps() {
+        let mut nps = NpsRecorder::new_unknown();
+        assert_eq!(nps.estimate(), None);
+        assert_eq!(nps.to_string(), "? knps/core");
+        nps.record(600_000, BATCH_NODES);
+        assert_eq!(nps.estimate(), Some(600_000));
+        nps.record(500_000, BATCH_NODES);
+        assert_eq!(nps.estimate(), Some(590_000));
+
+        assert_eq!(NpsRecorder::new().estimate(), Some(400_000));
+    }
+
+    #[test]
+    fn test_nps_percentile() {
+        let mut nps = NpsRecorder::with_seed(1);
+        assert_eq!(nps.percentile(0.5), None);
+        for sample in (1..=101).rev() {
+            nps.record(sample * 1_000, BATCH_NODES);
+        }
+        assert_eq!(nps.percentile(0.0), Some(1_000));
+        assert_eq!(nps.percentile(0.5), Some(51_000));
+        assert_eq!(nps.percentile(0.9), Some(91_000));
+        assert_eq!(nps.percentile(2.0), Some(101_000));
+
+        // Beyond the capacity, sampling is reproducible with the same seed.
+        let mut a = NpsRecorder::with_seed(42);
+        let mut b = NpsRecorder::with_seed(42);
+        for sample in 0..10 * RESERVOIR_CAPACITY as u32 {
+            a.record(sample, BATCH_NODES);
+            b.record(sample, BATCH_NODES);
+        }
+        assert_eq!(a.reservoir.len(), RESERVOIR_CAPACITY);
+        assert_eq!(a.reservoir, b.reservoir);
+        let median = a.percentile(0.5).unwrap();
+        assert!((1_000..1_600).contains(&median), "{median}");
+    }
+
+    #[test]
+    fn test_nps_headroom() {
+        let mut nps = NpsRecorder::new_unknown();
+        nps.record(300_000, BATCH_NODES);
+        assert_eq!(nps.headroom(400_000), 0.25);
+        assert_eq!(nps.headroom(300_000), 0.0);
+        assert_eq!(nps.headroom(200_000), 0.0);
+        assert_eq!(nps.headroom(0), 0.0);
+    }
+
+    #[test]
+    fn test_nps_convergence() {
+        let mut steady = NpsRecorder::new_unknown();
+        let mut converged_after = None;
+        for i in 0..100 {
+            steady.record(if i % 2 == 0 { 600_000 } else { 610_000 }, BATCH_NODES);
+            if steady.has_converged() {
+                converged_after.get_or_insert(i + 1);
+            }
+        }
+        assert!(steady.has_converged());
+        assert_eq!(converged_after, Some(22));
+
+        let mut drifting = NpsRecorder::new_unknown();
+        for i in 0..100 {
+            drifting.record(400_000 + i * 10_000, BATCH_NODES);
+            assert!(!drifting.has_converged());
+        }
+
+        // Stricter threshold.
+        steady.set_convergence(Convergence {
+            max_uncertainty: 0.1,
+            tolerance: 0.001,
+        });
+        assert!(!steady.has_converged());
+    }
+
+    #[test]
+    fn test_nps_uncertainty_bounds() {
+        let mut nps = NpsRecorder::new();
+        for _ in 0..1_000 {
+            nps.record(400_000, BATCH_NODES);
+        }
+        assert_eq!(nps.uncertainty, MIN_NPS_UNCERTAINTY);
+        assert_eq!(nps.to_string(), "400 knps/core");
+
+        // Small fluctuations keep the confidence.
+        nps.record(500_000, BATCH_NODES);
+        assert_eq!(nps.uncertainty, MIN_NPS_UNCERTAINTY);
+
+        // Halved throughput is a regime change.
+        nps.record(200_000, BATCH_NODES);
+        assert_eq!(nps.uncertainty, NPS_DEVIATION_UNCERTAINTY);
+        assert_eq!(nps.to_string(), "389 knps/core ??");
+    }
+
+    #[test]
+    fn test_weighted_nps() {
+        let typical = || {
+            let mut nps = NpsRecorder::new();
+            for _ in 0..4 {
+                nps.record(400_000, BATCH_NODES);
+            }
+            nps
+        };
+        let mut small = typical();
+        small.record(2_000_000, BATCH_NODES / 100);
+        let mut large = typical();
+        large.record(500_000, 10 * BATCH_NODES);
+        assert!(small.estimate().unwrap() < 420_000);
+        assert!(large.estimate().unwrap() > 460_000);
+        assert!(small.uncertainty > large.uncertainty);
+
+        // Weights are relative to the typical batch size of the machine.
+        let mut slow = NpsRecorder::new();
+        slow.record(500_000, 1_000);
+        assert_eq!(slow.estimate(), Some(410_000));
+
+        let mut empty = NpsRecorder::new();
+        empty.record(2_000_000, 0);
+        assert_eq!(empty.estimate(), Some(400_000));
+        assert_eq!(empty.uncertainty, 1.0);
+    }
+
+    #[test]
+    fn test_last_nps() {
+        let mut nps = NpsRecorder::new();
+        assert_eq!(nps.last(), None);
+        nps.record(500_000, BATCH_NODES);
+        assert_eq!(nps.last(), Some(500_000));
+        nps.record(900_000, BATCH_NODES);
+        assert_eq!(nps.last(), Some(900_000));
+        assert_eq!(nps.estimate(), Some(459_000));
+    }
+
+    #[test]
+    fn test_nps_smoothing() {
+        let mut ewma = NpsRecorder::new();
+        ewma.record(500_000, BATCH_NODES);
+        let mut sma = ewma.clone_state();
+        sma.set_smoothing(Smoothing::Sma);
+        assert_eq!(sma.smoothing(), Smoothing::Sma);
+        for nps in [600_000, 700_000] {
+            ewma.record(nps, BATCH_NODES);
+            sma.record(nps, BATCH_NODES);
+        }
+        assert_eq!(sma.estimate(), Some(600_000));
+        assert_ne!(ewma.estimate(), sma.estimate());
+
+        // Window of the last 8 samples.
+        for _ in 0..8 {
+            sma.record(300_000, BATCH_NODES);
+        }
+        assert_eq!(sma.estimate(), Some(300_000));
+    }
+
+    #[test]
+    fn test_nps_warmup() {
+        let mut plain = NpsRecorder::new();
+        let mut warmup = NpsRecorder::new();
+        warmup.set_warmup_batches(10);
+        assert_eq!(plain.phase(), NpsPhase::Steady);
+        assert_eq!(warmup.phase(), NpsPhase::Warmup);
+
+        // Faster convergence from the optimistic estimate to the actual
+        // speed.
+        for _ in 0..5 {
+            plain.record(200_000, BATCH_NODES);
+            warmup.record(200_000, BATCH_NODES);
+        }
+        assert_eq!(warmup.phase(), NpsPhase::Warmup);
+        assert!(warmup.nps < plain.nps, "{} < {}", warmup.nps, plain.nps);
+        assert!(warmup.nps < 250_000, "{}", warmup.nps);
+
+        for _ in 0..5 {
+            warmup.record(200_000, BATCH_NODES);
+        }
+        assert_eq!(warmup.phase(), NpsPhase::Steady);
+
+        // Same as the usual moving average afterwards.
+        let mut steady = warmup.clone_state();
+        steady.set_warmup_batches(0);
+        for nps in [300_000, 150_000, 210_000] {
+            warmup.record(nps, BATCH_NODES);
+            steady.record(nps, BATCH_NODES);
+            assert_eq!(warmup.nps, steady.nps);
+        }
+    }
+
+    #[test]
+    fn test_shadow_nps() {
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        assert!(recorder.shadow_nps().is_none());
+        recorder.set_shadow_nps(Smoothing::Sma);
+        recorder.record_batch(10, 1_000, Some(500_000), Duration::from_secs(1), false);
+        recorder.record_batch(10, 1_000, Some(700_000), Duration::from_secs(1), false);
+        let shadow = recorder.shadow_nps().expect("shadow");
+        assert_eq!(shadow.estimate(), Some(600_000));
+        // Batch sizing still uses the primary estimate.
+        assert_ne!(
+            recorder.nnue_nps.default_engine().estimate(),
+            shadow.estimate()
+        );
+
+        // Cold starts are ignored, like for the primary estimate.
+        recorder.record_batch(10, 1_000, Some(100_000), Duration::from_secs(1), true);
+        assert_eq!(recorder.shadow_nps().unwrap().estimate(), Some(600_000));
+    }
+
+    #[test]
+    fn test_nps_recorders() {
+        let mut engines = NpsRecorders::new(NpsRecorder::new_unknown());
+        engines.record(DEFAULT_ENGINE, 600_000, BATCH_NODES);
+        assert_eq!(engines.to_string(), "600 knps/core ???");
+        engines.record("lite", 2_000_000, BATCH_NODES);
+        assert_eq!(engines.default_engine().estimate(), Some(600_000));
+        assert_eq!(
+            engines.to_string(),
+            "lite 2000 knps/core ???, nnue 600 knps/core ???"
+        );
+
+        let mut recorder = StatsRecorder::with_sinks(
+            Stats::default(),
+            NpsRecorder::new(),
+            NonZeroUsize::MIN,
+            BTreeMap::new(),
+            false,
+            None,
+            None,
+        );
+        recorder.record_engine_nps(HCE_ENGINE, 1_000_000, BATCH_NODES);
+        recorder.record_batch(
+            10,
+            BATCH_NODES,
+            Some(500_000),
+            Duration::from_secs(1),
+            false,
+        );
+        let snapshot = recorder.snapshot();
+        assert_eq!(snapshot.nnue_nps, Some(410_000));
+        assert_eq!(
+            snapshot.engine_nps,
+            BTreeMap::from([
+                ("hce".to_owned(), Some(460_000)),
+                ("nnue".to_owned(), Some(410_000)),
+            ])
+        );
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_stats_json_schema() {
+        let schema: serde_json::Value = serde_json::from_str(&stats_json_schema()).unwrap();
+        let counter = serde_json::json!({ "type": "integer", "format": "uint64", "minimum": 0.0 });
+        let defaulted_counter = serde_json::json!({ "default": 0, "type": "integer", "format": "uint64", "minimum": 0.0 });
+        assert_eq!(
+            schema,
+            serde_json::json!({
+                "$schema": "http://json-schema.org/draft-07/schema#",
+                "title": "Stats",
+                "type": "object",
+                "required": ["total_batches", "total_nodes", "total_positions"],
+                "properties": {
+                    "total_batches": counter,
+                    "total_positions": counter,
+                    "total_nodes": counter,
+                    "total_positions_cached": defaulted_counter,
+                    "total_unique_positions": defaulted_counter,
+                    "cores": { "type": ["integer", "null"], "format": "uint64", "minimum": 0.0 },
+                    "version": { "type": ["string", "null"] },
+                    "batches_under_1s": defaulted_counter,
+                    "batches_under_5s": defaulted_counter,
+                    "batches_under_30s": defaulted_counter,
+                    "batches_from_30s": defaulted_counter,
+                },
+            })
+        );
     }
 }
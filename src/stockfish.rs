@@ -280,8 +280,14 @@ impl StockfishActor {
         let mut responses = Vec::with_capacity(chunk.positions.len());
         for position in chunk.positions {
             responses.push(
-                self.go(stdout, stdin, chunk.flavor.eval_flavor(), position)
-                    .await?,
+                self.go(
+                    stdout,
+                    stdin,
+                    chunk.flavor.eval_flavor(),
+                    position,
+                    chunk.capture_output,
+                )
+                .await?,
             );
         }
         Ok(responses)
@@ -293,6 +299,7 @@ impl StockfishActor {
         stdin: &mut BufWriter<ChildStdin>,
         eval_flavor: EvalFlavor,
         position: Position,
+        capture_output: bool,
     ) -> io::Result<PositionResponse> {
         // Setup position.
         let moves = position
@@ -357,9 +364,17 @@ impl StockfishActor {
         let mut time = Duration::default();
         let mut nodes = 0;
         let mut nps = None;
+        let mut output = capture_output.then(String::new);
 
         loop {
             let line = stdout.read_line().await?;
+            if let Some(output) = output
+                .as_mut()
+                .filter(|output| output.len() + line.len() < Chunk::MAX_CAPTURED_OUTPUT)
+            {
+                output.push_str(&line);
+                output.push('\n');
+            }
             let mut parts = line.split(' ');
             match parts.next() {
                 Some("bestmove") => {
@@ -378,6 +393,7 @@ impl StockfishActor {
                         time,
                         nodes,
                         nps,
+                        output,
                     });
                 }
                 Some("info") => {
@@ -0,0 +1,133 @@
+use std::{fmt::Write as _, io, process};
+
+use crate::stats::Stats;
+
+/// Structured data ID of the batch parameters. 32473 is the private
+/// enterprise number reserved for documentation (RFC 5612).
+const SD_ID: &str = "fishnet@32473";
+
+/// Facility user (1 * 8), severity informational (6).
+const PRI: u8 = 14;
+
+/// Sends stats to the local system logger, as RFC 5424 messages.
+#[cfg(unix)]
+pub struct Syslog {
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+#[cfg(unix)]
+impl Syslog {
+    /// Connects to /dev/log, or /var/run/syslog on macOS.
+    pub fn connect() -> io::Result<Syslog> {
+        Syslog::connect_to("/dev/log")
+            .or_else(|err| Syslog::connect_to("/var/run/syslog").map_err(|_| err))
+    }
+
+    pub fn connect_to<P: AsRef<std::path::Path>>(path: P) -> io::Result<Syslog> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        // Sent while recording a batch, which must not wait for a slow
+        // system logger.
+        socket.set_nonblocking(true)?;
+        Ok(Syslog { socket })
+    }
+
+    /// Drops the message if the system logger is not keeping up.
+    pub fn send(&self, message: &str) -> io::Result<()> {
+        match self.socket.send(message.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "system logger is busy, message dropped",
+            )),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// No system logger on this platform. Messages are discarded.
+#[cfg(not(unix))]
+pub struct Syslog;
+
+#[cfg(not(unix))]
+impl Syslog {
+    pub fn connect() -> io::Result<Syslog> {
+        eprintln!("W: --stats-syslog is not supported on this platform");
+        Ok(Syslog)
+    }
+
+    pub fn send(&self, _message: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats the totals after a batch. Timestamp and hostname are left to
+/// the system logger.
+pub fn batch_message(stats: &Stats, nnue_nps: Option<u32>) -> String {
+    let mut message = format!(
+        "<{PRI}>1 - - fishnet {} batch [{SD_ID} total_batches=\"{}\" total_positions=\"{}\" total_nodes=\"{}\"",
+        process::id(),
+        stats.total_batches,
+        stats.total_positions,
+        stats.total_nodes
+    );
+    if let Some(nnue_nps) = nnue_nps {
+        let _ = write!(message, " nnue_nps=\"{nnue_nps}\"");
+    }
+    let _ = write!(
+        message,
+        "] Recorded batch: {} batches, {} positions, {} nodes in total",
+        stats.total_batches, stats.total_positions, stats.total_nodes
+    );
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_message() {
+        let stats = Stats {
+            total_batches: 2,
+            total_positions: 120,
+            total_nodes: 3_000_000,
+            ..Stats::default()
+        };
+        assert_eq!(
+            batch_message(&stats, Some(400_000)),
+            format!(
+                "<14>1 - - fishnet {} batch [fishnet@32473 total_batches=\"2\" total_positions=\"120\" total_nodes=\"3000000\" nnue_nps=\"400000\"] Recorded batch: 2 batches, 120 positions, 3000000 nodes in total",
+                process::id()
+            )
+        );
+        assert!(!batch_message(&stats, None).contains("nnue_nps"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let server = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::connect_to(&path).unwrap();
+        syslog.send("<14>1 - - fishnet - - - hello").unwrap();
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<14>1 - - fishnet - - - hello");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_send_busy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let _server = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::connect_to(&path).unwrap();
+        // Nobody receives, so the socket buffer fills up eventually.
+        let err = (0..100_000)
+            .find_map(|_| syslog.send(&"x".repeat(1024)).err())
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Interval between samples of the background thread.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+const UNKNOWN: i32 = i32::MIN;
+
+/// Latest CPU temperature, sampled in a background thread, so that reading
+/// it never blocks. The thread stops when the last clone is dropped.
+#[derive(Clone)]
+pub struct CpuTemperature {
+    celsius: Arc<AtomicI32>,
+}
+
+impl CpuTemperature {
+    /// Starts sampling. `None` if there is no supported sensor.
+    pub fn spawn() -> Option<CpuTemperature> {
+        let sensor = sensor()?;
+        let temperature = CpuTemperature {
+            celsius: Arc::new(AtomicI32::new(UNKNOWN)),
+        };
+        let celsius = Arc::downgrade(&temperature.celsius);
+        thread::Builder::new()
+            .name("cpu-temperature".to_owned())
+            .spawn(move || {
+                while let Some(celsius) = celsius.upgrade() {
+                    celsius.store(read(&sensor).unwrap_or(UNKNOWN), Ordering::Relaxed);
+                    drop(celsius);
+                    thread::sleep(SAMPLE_INTERVAL);
+                }
+            })
+            .ok()?;
+        Some(temperature)
+    }
+
+    /// Most recent sample in degrees Celsius, rounded.
+    pub fn celsius(&self) -> Option<i32> {
+        Some(self.celsius.load(Ordering::Relaxed)).filter(|&celsius| celsius != UNKNOWN)
+    }
+}
+
+/// Drivers of hwmon devices that measure the CPU package or die.
+#[cfg(target_os = "linux")]
+const CPU_SENSORS: &[&str] = &[
+    "coretemp",
+    "k10temp",
+    "zenpower",
+    "cpu_thermal",
+    "cpu-thermal",
+    "soc_thermal",
+];
+
+/// Labels of temperature inputs that measure the whole package, rather
+/// than a single core.
+#[cfg(target_os = "linux")]
+const PACKAGE_LABELS: &[&str] = &["Package id 0", "Tctl", "Tdie", "Physical id 0"];
+
+#[cfg(target_os = "linux")]
+fn sensor() -> Option<PathBuf> {
+    sensor_in(Path::new("/sys/class/hwmon"))
+}
+
+/// Package temperature input of the first CPU sensor, or its first input
+/// if none is labelled as such.
+#[cfg(target_os = "linux")]
+fn sensor_in(hwmon: &Path) -> Option<PathBuf> {
+    let mut devices: Vec<PathBuf> = std::fs::read_dir(hwmon)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    devices.sort();
+    let device = devices.into_iter().find(|device| {
+        std::fs::read_to_string(device.join("name"))
+            .is_ok_and(|name| CPU_SENSORS.contains(&name.trim()))
+    })?;
+    let mut labels: Vec<(u32, String)> = std::fs::read_dir(&device)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let index = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("temp")?
+                .strip_suffix("_label")?
+                .parse()
+                .ok()?;
+            Some((index, std::fs::read_to_string(entry.path()).ok()?))
+        })
+        .collect();
+    labels.sort();
+    let index = package_input(&labels).unwrap_or(1);
+    Some(device.join(format!("temp{index}_input")))
+}
+
+#[cfg(target_os = "linux")]
+fn package_input(labels: &[(u32, String)]) -> Option<u32> {
+    labels
+        .iter()
+        .find(|(_, label)| PACKAGE_LABELS.contains(&label.trim()))
+        .map(|&(index, _)| index)
+}
+
+#[cfg(target_os = "linux")]
+fn read(sensor: &Path) -> Option<i32> {
+    parse_millidegrees(&std::fs::read_to_string(sensor).ok()?)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_millidegrees(s: &str) -> Option<i32> {
+    let millidegrees: i32 = s.trim().parse().ok()?;
+    Some((millidegrees + 500).div_euclid(1000))
+}
+
+// Not supported, yet. On macOS the SMC would have to be queried through
+// IOKit.
+#[cfg(not(target_os = "linux"))]
+fn sensor() -> Option<PathBuf> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read(_sensor: &Path) -> Option<i32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_millidegrees() {
+        use super::parse_millidegrees;
+        assert_eq!(parse_millidegrees("54000\n"), Some(54));
+        assert_eq!(parse_millidegrees("54500"), Some(55));
+        assert_eq!(parse_millidegrees("-1200"), Some(-1));
+        assert_eq!(parse_millidegrees(""), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_package_input() {
+        use super::package_input;
+        let label = |index, label: &str| (index, format!("{label}\n"));
+        assert_eq!(
+            package_input(&[label(1, "Tctl"), label(3, "Tccd1")]),
+            Some(1)
+        );
+        assert_eq!(
+            package_input(&[label(2, "Core 0"), label(5, "Package id 0")]),
+            Some(5)
+        );
+        assert_eq!(package_input(&[label(2, "Core 0")]), None);
+        assert_eq!(package_input(&[]), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sensor_in() {
+        use std::fs;
+
+        use super::sensor_in;
+        let hwmon = tempfile::tempdir().unwrap();
+        let other = hwmon.path().join("hwmon0");
+        fs::create_dir(&other).unwrap();
+        fs::write(other.join("name"), "nvme\n").unwrap();
+        let cpu = hwmon.path().join("hwmon1");
+        fs::create_dir(&cpu).unwrap();
+        fs::write(cpu.join("name"), "coretemp\n").unwrap();
+        assert_eq!(sensor_in(hwmon.path()), Some(cpu.join("temp1_input")));
+
+        fs::write(cpu.join("temp1_label"), "Core 0\n").unwrap();
+        fs::write(cpu.join("temp2_label"), "Package id 0\n").unwrap();
+        assert_eq!(sensor_in(hwmon.path()), Some(cpu.join("temp2_input")));
+
+        assert_eq!(sensor_in(&hwmon.path().join("missing")), None);
+    }
+}
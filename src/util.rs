@@ -1,6 +1,7 @@
 use std::{
     cmp::{max, min},
-    str,
+    error::Error,
+    fmt, str,
     time::Duration,
 };
 
@@ -65,6 +66,120 @@ pub fn dot_thousands(n: u64) -> String {
         .join(".")
 }
 
+const SI_PREFIXES: [char; 6] = ['k', 'M', 'G', 'T', 'P', 'E'];
+
+/// Formats a node count with an SI prefix and at most one decimal, for
+/// example `1.2G`.
+pub fn format_nodes(n: u64) -> String {
+    if n < 1000 {
+        return n.to_string();
+    }
+    let mut scaled = n as f64;
+    for prefix in SI_PREFIXES {
+        scaled /= 1000.0;
+        let rounded = (scaled * 10.0).round() / 10.0;
+        if rounded < 1000.0 || prefix == 'E' {
+            let formatted = format!("{rounded:.1}");
+            return format!(
+                "{}{prefix}",
+                formatted.strip_suffix(".0").unwrap_or(&formatted)
+            );
+        }
+    }
+    unreachable!()
+}
+
+/// Parses a node count as formatted by [`format_nodes()`], for example
+/// `1.2G`, `1.2 g` or `1200000000`. Returns `None` for malformed input,
+/// on overflow, and if the result would not be a whole number of nodes.
+pub fn parse_nodes(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => {
+            let exp = SI_PREFIXES
+                .iter()
+                .position(|prefix| prefix.eq_ignore_ascii_case(&c))?;
+            (s[..i].trim_end(), 1000u64.pow(exp as u32 + 1))
+        }
+        _ => (s, 1),
+    };
+    let (int, frac) = number.split_once('.').unwrap_or((number, ""));
+    if int.is_empty() || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac_scale = 10u64.checked_pow(u32::try_from(frac.len()).ok()?)?;
+    let frac_nodes = if frac.is_empty() {
+        0
+    } else {
+        let nodes = frac.parse::<u64>().ok()?.checked_mul(multiplier)?;
+        if nodes % frac_scale != 0 {
+            return None;
+        }
+        nodes / frac_scale
+    };
+    int.parse::<u64>()
+        .ok()?
+        .checked_mul(multiplier)?
+        .checked_add(frac_nodes)
+}
+
+#[derive(Debug)]
+pub enum ExpandError {
+    Undefined(String),
+    Unterminated,
+    Empty,
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpandError::Undefined(name) => write!(f, "environment variable ${name} is not set"),
+            ExpandError::Unterminated => f.write_str("unterminated ${...}"),
+            ExpandError::Empty => f.write_str("empty ${}"),
+        }
+    }
+}
+
+impl Error for ExpandError {}
+
+/// Expands shell-style `$VAR` and `${VAR}` references, looking up values
+/// with `lookup`. A `$` not followed by a variable name is kept as is,
+/// but an empty `${}` is an error.
+pub fn expand_vars<F>(s: &str, mut lookup: F) -> Result<String, ExpandError>
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        expanded.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let name = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced.find('}').ok_or(ExpandError::Unterminated)?;
+            if end == 0 {
+                return Err(ExpandError::Empty);
+            }
+            rest = &braced[end + 1..];
+            &braced[..end]
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            name
+        };
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            expanded
+                .push_str(&lookup(name).ok_or_else(|| ExpandError::Undefined(name.to_owned()))?);
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -87,4 +202,80 @@ mod tests {
         assert_eq!(dot_thousands(123456), "123.456");
         assert_eq!(dot_thousands(1234567), "1.234.567");
     }
+
+    #[test]
+    fn test_format_nodes() {
+        assert_eq!(format_nodes(0), "0");
+        assert_eq!(format_nodes(999), "999");
+        assert_eq!(format_nodes(1_000), "1k");
+        assert_eq!(format_nodes(1_250), "1.3k");
+        assert_eq!(format_nodes(999_949), "999.9k");
+        assert_eq!(format_nodes(999_999), "1M");
+        assert_eq!(format_nodes(1_200_000_000), "1.2G");
+        assert_eq!(format_nodes(u64::MAX), "18.4E");
+    }
+
+    #[test]
+    fn test_parse_nodes() {
+        assert_eq!(parse_nodes("0"), Some(0));
+        assert_eq!(parse_nodes("1500000"), Some(1_500_000));
+        assert_eq!(parse_nodes("1.2G"), Some(1_200_000_000));
+        assert_eq!(parse_nodes(" 1.2 g "), Some(1_200_000_000));
+        assert_eq!(parse_nodes("1T"), Some(1_000_000_000_000));
+        assert_eq!(parse_nodes("1.5"), None);
+        assert_eq!(parse_nodes("1.0005k"), None);
+        assert_eq!(parse_nodes("18.5E"), None);
+        assert_eq!(parse_nodes(""), None);
+        assert_eq!(parse_nodes("k"), None);
+        assert_eq!(parse_nodes(".5k"), None);
+        assert_eq!(parse_nodes("1..2k"), None);
+        assert_eq!(parse_nodes("1kk"), None);
+        assert_eq!(parse_nodes("1x"), None);
+        assert_eq!(parse_nodes("-1k"), None);
+        assert_eq!(parse_nodes("1 000"), None);
+    }
+
+    #[test]
+    fn test_parse_format_nodes_roundtrip() {
+        let mut rng = Rng::with_seed(0);
+        let samples = (0..1000).map(|_| rng.u64(..) >> rng.u32(0..64)).chain([
+            0,
+            999,
+            1_000,
+            999_999,
+            u64::MAX,
+        ]);
+        for n in samples {
+            let formatted = format_nodes(n);
+            let parsed = parse_nodes(&formatted)
+                .unwrap_or_else(|| panic!("parse {formatted:?} formatted from {n}"));
+            // Rounding to one decimal is off by at most 5%.
+            let tolerance = if n < 1000 { 0 } else { n / 19 };
+            assert!(
+                parsed.abs_diff(n) <= tolerance,
+                "{n} formatted as {formatted:?} parsed as {parsed}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let lookup = |name: &str| (name == "HOST").then(|| "node1".to_owned());
+        assert_eq!(expand_vars("stats", lookup).unwrap(), "stats");
+        assert_eq!(expand_vars("$HOST/stats", lookup).unwrap(), "node1/stats");
+        assert_eq!(expand_vars("${HOST}_stats", lookup).unwrap(), "node1_stats");
+        assert_eq!(expand_vars("a$/b", lookup).unwrap(), "a$/b");
+        assert!(matches!(
+            expand_vars("$MISSING/stats", lookup),
+            Err(ExpandError::Undefined(name)) if name == "MISSING"
+        ));
+        assert!(matches!(
+            expand_vars("${HOST", lookup),
+            Err(ExpandError::Unterminated)
+        ));
+        assert!(matches!(
+            expand_vars("${}/stats", lookup),
+            Err(ExpandError::Empty)
+        ));
+    }
 }